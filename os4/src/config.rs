@@ -12,15 +12,46 @@ pub const MEMORY_END: usize = 0x81000000;
 pub const PAGE_SIZE: usize = 0x1000;
 // 页面内偏移位宽 12
 pub const PAGE_SIZE_BITS: usize = 0xc;
+/// size of a Sv39 megapage, the length `sys_mmap_hugepage` maps
+pub const HUGE_PAGE_SIZE: usize = PAGE_SIZE * 512;
 pub const MAX_SYSCALL_NUM: usize = 500;
 
+/// lowest address `sys_mmap` picks when the caller passes `start == 0`
+pub const MMAP_BASE: usize = 0x1000_0000;
+/// end of the lower half of the Sv39 address space, kernel-chosen mappings stay below it
+pub const MMAP_TOP: usize = 0x40_0000_0000;
+/// whether pages mapped by `sys_mmap` start with the A and D bits set. Off,
+/// so a page replacement scan can watch the hardware set them on first access
+pub const MMAP_PRESET_AD: bool = false;
+
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
 pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
-/// Return (bottom, top) of a kernel stack in kernel space.
-pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
+/// number of kernel stack slots below the trampoline, together with their
+/// guard pages they take up about 336MiB at the top of kernel space
+pub const MAX_KERNEL_STACKS: usize = 4096;
+/// Return (bottom, top) of a kernel stack in kernel space, `None` if
+/// `app_id` has no slot.
+pub fn checked_kernel_stack_position(app_id: usize) -> Option<(usize, usize)> {
+    if app_id >= MAX_KERNEL_STACKS {
+        return None;
+    }
     let top = TRAMPOLINE - app_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
     let bottom = top - KERNEL_STACK_SIZE;
-    (bottom, top)
+    Some((bottom, top))
+}
+/// Return (bottom, top) of a kernel stack in kernel space.
+/// app_id 超出 MAX_KERNEL_STACKS 时直接 panic ，内核栈不能和其它区域重叠
+pub fn kernel_stack_position(app_id: usize) -> (usize, usize) {
+    match checked_kernel_stack_position(app_id) {
+        Some(position) => position,
+        None => panic!("no kernel stack slot for app {}", app_id),
+    }
+}
+/// Return (bottom, top) of the guard page right below a kernel stack.
+/// 内核栈之间相隔一个页面，这个页面从不映射，内核栈溢出时访问它会触发缺页异常
+pub fn kernel_stack_guard_position(app_id: usize) -> (usize, usize) {
+    let (bottom, _) = kernel_stack_position(app_id);
+    (bottom - PAGE_SIZE, bottom)
 }
 
 pub const CLOCK_FREQ: usize = 12500000;
@@ -1,6 +1,7 @@
 //! The panic handler
 
 use crate::sbi::shutdown;
+use crate::task::{backtrace, TaskContext};
 use core::panic::PanicInfo;
 
 #[panic_handler]
@@ -16,5 +17,6 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         println!("[kernel] Panicked: {}", info.message().unwrap());
     }
-    shutdown()
+    backtrace(&TaskContext::here());
+    shutdown(1)
 }
@@ -24,3 +24,29 @@ pub fn get_app_data(app_id: usize) -> &'static [u8] {
         )
     }
 }
+
+/// Get the name of application `app_id`, its file name without extension.
+pub fn get_app_name(app_id: usize) -> &'static str {
+    extern "C" {
+        fn _app_names();
+    }
+    assert!(app_id < get_num_app());
+    // 名字表是一串连续存放、以 0 结尾的字符串
+    let mut start = _app_names as usize as *const u8;
+    for _ in 0..app_id {
+        start = unsafe { start.add(c_str_len(start) + 1) };
+    }
+    unsafe {
+        let name = core::slice::from_raw_parts(start, c_str_len(start));
+        core::str::from_utf8(name).unwrap()
+    }
+}
+
+/// length of the 0-terminated string at `start`, without the 0
+fn c_str_len(start: *const u8) -> usize {
+    let mut len = 0;
+    while unsafe { start.add(len).read_volatile() } != 0 {
+        len += 1;
+    }
+    len
+}
@@ -35,6 +35,8 @@ mod loader;
 mod logging;
 mod mm;
 mod sbi;
+#[cfg(feature = "self_test")]
+mod selftest;
 mod sync;
 pub mod syscall;
 pub mod task;
@@ -57,15 +59,18 @@ fn clear_bss() {
 }
 
 #[no_mangle]
-/// the rust entry-point of os
-pub fn rust_main() -> ! {
+/// the rust entry-point of os, `dtb` is the device tree address the
+/// bootloader leaves in `a1`
+pub fn rust_main(_hart_id: usize, dtb: usize) -> ! {
     clear_bss();
     logging::init();
     println!("[kernel] Hello, world!");
-    mm::init();
+    mm::init(dtb);
     println!("[kernel] back to world!");
     mm::remap_test();
     trap::init();
+    #[cfg(feature = "self_test")]
+    selftest::run_self_tests();
     //trap::enable_interrupt();
     trap::enable_timer_interrupt();
     timer::set_next_trigger();
@@ -3,6 +3,7 @@
 use super::PageTableEntry;
 use crate::config::{PAGE_SIZE, PAGE_SIZE_BITS};
 use core::fmt::{self, Debug, Formatter};
+use core::ops::{Add, Sub};
 
 /// S/U 特权级的访存被视为一个 39 位的虚拟地址，MMU 会将其转换成 56 位的物理地址
 /// 0-11~12位为虚拟内存地址~4KB，业内偏移地址
@@ -107,24 +108,81 @@ impl From<VirtPageNum> for usize {
     }
 }
 
+/// 分页方案的参数：页面大小及页内偏移位宽
+/// 目前 MMU 实际使用的只有 [`Sv39`] 的 4KiB 页面，其他方案仅用于实验
+pub trait PagingScheme {
+    const PAGE_SIZE: usize;
+    const PAGE_SIZE_BITS: usize;
+}
+
+/// SV39 with 4KiB pages, the default scheme
+pub struct Sv39;
+
+impl PagingScheme for Sv39 {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const PAGE_SIZE_BITS: usize = PAGE_SIZE_BITS;
+}
+
 impl VirtAddr {
     pub fn floor(&self) -> VirtPageNum {
-        VirtPageNum(self.0 / PAGE_SIZE)
+        self.floor_in::<Sv39>()
     }
     pub fn ceil(&self) -> VirtPageNum {
-        VirtPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
+        self.ceil_in::<Sv39>()
     }
     pub fn page_offset(&self) -> usize {
-        self.0 & (PAGE_SIZE - 1)
+        self.page_offset_in::<Sv39>()
+    }
+    pub fn floor_in<S: PagingScheme>(&self) -> VirtPageNum {
+        VirtPageNum(self.0 / S::PAGE_SIZE)
+    }
+    pub fn ceil_in<S: PagingScheme>(&self) -> VirtPageNum {
+        VirtPageNum((self.0 - 1 + S::PAGE_SIZE) / S::PAGE_SIZE)
+    }
+    pub fn page_offset_in<S: PagingScheme>(&self) -> usize {
+        self.0 & (S::PAGE_SIZE - 1)
     }
     pub fn aligned(&self) -> bool {
         self.page_offset() == 0
     }
+    pub fn is_aligned(&self) -> bool {
+        self.aligned()
+    }
+    /// 到下一个页边界的字节数，已经页对齐的地址返回一整页 PAGE_SIZE
+    pub fn offset_to_next_page(&self) -> usize {
+        PAGE_SIZE - self.page_offset()
+    }
+    /// The page starting at this address, or an error if it is not page
+    /// aligned, for addresses that come from user space. `From` panics instead.
+    pub fn try_into_vpn(self) -> Result<VirtPageNum, UnalignedAddr> {
+        if self.aligned() {
+            Ok(self.floor())
+        } else {
+            Err(UnalignedAddr(self))
+        }
+    }
 }
+/// a virtual address expected to start a page but with a nonzero offset
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct UnalignedAddr(pub VirtAddr);
+
+impl fmt::Display for UnalignedAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "virtual address {:#x} is not page aligned (offset {:#x}), use floor() or ceil()",
+            (self.0).0,
+            self.0.page_offset()
+        )
+    }
+}
+
 impl From<VirtAddr> for VirtPageNum {
     fn from(v: VirtAddr) -> Self {
-        assert_eq!(v.page_offset(), 0);
-        v.floor()
+        match v.try_into_vpn() {
+            Ok(vpn) => vpn,
+            Err(err) => panic!("{}", err),
+        }
     }
 }
 impl From<VirtPageNum> for VirtAddr {
@@ -137,20 +195,56 @@ impl PhysAddr {
      * 向下取整
      */
     pub fn floor(&self) -> PhysPageNum {
-        PhysPageNum(self.0 / PAGE_SIZE)
+        self.floor_in::<Sv39>()
     }
     /**
      * 向上取整
      */
     pub fn ceil(&self) -> PhysPageNum {
-        PhysPageNum((self.0 - 1 + PAGE_SIZE) / PAGE_SIZE)
+        self.ceil_in::<Sv39>()
     }
     pub fn page_offset(&self) -> usize {
-        self.0 & (PAGE_SIZE - 1)
+        self.page_offset_in::<Sv39>()
+    }
+    pub fn floor_in<S: PagingScheme>(&self) -> PhysPageNum {
+        PhysPageNum(self.0 / S::PAGE_SIZE)
+    }
+    pub fn ceil_in<S: PagingScheme>(&self) -> PhysPageNum {
+        PhysPageNum((self.0 - 1 + S::PAGE_SIZE) / S::PAGE_SIZE)
+    }
+    pub fn page_offset_in<S: PagingScheme>(&self) -> usize {
+        self.0 & (S::PAGE_SIZE - 1)
     }
     pub fn aligned(&self) -> bool {
         self.page_offset() == 0
     }
+    pub fn is_aligned(&self) -> bool {
+        self.aligned()
+    }
+    /// Reference to a `T` stored at this address, which may be anywhere in
+    /// the page. `T` must be aligned and must not cross into the next page,
+    /// this is only checked in debug builds.
+    pub fn get_ref<T>(&self) -> &'static T {
+        self.debug_check_fits::<T>();
+        unsafe { (self.0 as *const T).as_ref().unwrap() }
+    }
+    /// Mutable reference to a `T` stored at this address, see `get_ref`.
+    pub fn get_mut<T>(&self) -> &'static mut T {
+        self.debug_check_fits::<T>();
+        unsafe { (self.0 as *mut T).as_mut().unwrap() }
+    }
+    fn debug_check_fits<T>(&self) {
+        debug_assert!(
+            self.0 % core::mem::align_of::<T>() == 0,
+            "PhysAddr::get_mut: {:?} is not aligned for the type",
+            self
+        );
+        debug_assert!(
+            self.page_offset() + core::mem::size_of::<T>() <= PAGE_SIZE,
+            "PhysAddr::get_mut: type at {:?} crosses a page boundary",
+            self
+        );
+    }
 }
 impl From<PhysAddr> for PhysPageNum {
     fn from(v: PhysAddr) -> Self {
@@ -164,6 +258,34 @@ impl From<PhysPageNum> for PhysAddr {
     }
 }
 
+/// T: {PhysAddr, VirtAddr}
+/// T + usize -> T, T - usize -> T
+
+impl Add<usize> for VirtAddr {
+    type Output = Self;
+    fn add(self, rhs: usize) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+impl Sub<usize> for VirtAddr {
+    type Output = Self;
+    fn sub(self, rhs: usize) -> Self {
+        Self(self.0 - rhs)
+    }
+}
+impl Add<usize> for PhysAddr {
+    type Output = Self;
+    fn add(self, rhs: usize) -> Self {
+        Self(self.0 + rhs)
+    }
+}
+impl Sub<usize> for PhysAddr {
+    type Output = Self;
+    fn sub(self, rhs: usize) -> Self {
+        Self(self.0 - rhs)
+    }
+}
+
 // 建立和拆除虚实地址映射关系
 impl VirtPageNum {
     /**
@@ -222,7 +344,13 @@ impl PhysPageNum {
         }
     }
     // 可以获取一个恰好放在一个物理页帧开头的类型为 T 的数据的可变引用
+    /// `T` must fit in one page and be at most page-aligned, this is only
+    /// checked in debug builds.
     pub fn get_mut<T>(&self) -> &'static mut T {
+        debug_assert!(
+            core::mem::size_of::<T>() <= PAGE_SIZE && core::mem::align_of::<T>() <= PAGE_SIZE,
+            "get_mut: type does not fit at the start of a page"
+        );
         // 先把物理页号转为物理地址 PhysAddr ，然后再转成 usize 形式的物理地址
         let pa: PhysAddr = (*self).into();
         unsafe {
@@ -239,11 +367,15 @@ impl PhysPageNum {
 
 pub trait StepByOne {
     fn step(&mut self);
+    fn step_back(&mut self);
 }
 impl StepByOne for VirtPageNum {
     fn step(&mut self) {
         self.0 += 1;
     }
+    fn step_back(&mut self) {
+        self.0 -= 1;
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -269,6 +401,10 @@ where
     pub fn get_end(&self) -> T {
         self.r
     }
+    /// whether the two half-open ranges share at least one element
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.l < other.r && other.l < self.r
+    }
 }
 impl<T> IntoIterator for SimpleRange<T>
 where
@@ -311,6 +447,185 @@ where
         }
     }
 }
+impl<T> DoubleEndedIterator for SimpleRangeIterator<T>
+where
+    T: StepByOne + Copy + PartialEq + PartialOrd + Debug,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.current == self.end {
+            None
+        } else {
+            self.end.step_back();
+            Some(self.end)
+        }
+    }
+}
 
 /// a simple range structure for virtual page number
 pub type VPNRange = SimpleRange<VirtPageNum>;
+
+impl VPNRange {
+    /// number of pages in the range
+    pub fn len(&self) -> usize {
+        self.r.0 - self.l.0
+    }
+    pub fn is_empty(&self) -> bool {
+        self.l == self.r
+    }
+    /// zero-based position of `vpn` in the range, `None` if it is outside
+    pub fn index_of(&self, vpn: VirtPageNum) -> Option<usize> {
+        if self.l <= vpn && vpn < self.r {
+            Some(vpn.0 - self.l.0)
+        } else {
+            None
+        }
+    }
+}
+
+#[allow(unused)]
+/// a simple test for address arithmetic
+pub fn address_arith_test() {
+    let va = VirtAddr::from(0x1000_0ff0);
+    assert!(!va.is_aligned());
+    assert_eq!(va.offset_to_next_page(), 0x10);
+    let next = va + va.offset_to_next_page();
+    assert!(next.is_aligned());
+    assert_eq!(next.floor(), VirtPageNum(0x1000_1));
+    // crossing a page boundary moves floor but keeps the in-page offset
+    let crossed = va + 0x20;
+    assert_eq!(crossed.floor(), VirtPageNum(0x1000_1));
+    assert_eq!(crossed.page_offset(), 0x10);
+    assert_eq!(crossed - 0x20, va);
+    assert_eq!(VirtAddr::from(0x2000).offset_to_next_page(), PAGE_SIZE);
+    assert!((PhysAddr::from(0x8020_0000) + PAGE_SIZE).is_aligned());
+    info!("address_arith_test passed!");
+}
+
+#[allow(unused)]
+/// check `VPNRange::len` and `VPNRange::index_of` at both ends of a range
+pub fn vpn_range_index_test() {
+    let range = VPNRange::new(VirtPageNum(0x1000_0), VirtPageNum(0x1000_4));
+    assert_eq!(range.len(), 4);
+    assert!(!range.is_empty());
+    assert_eq!(range.index_of(VirtPageNum(0x1000_0)), Some(0));
+    assert_eq!(range.index_of(VirtPageNum(0x1000_2)), Some(2));
+    assert_eq!(range.index_of(VirtPageNum(0x1000_3)), Some(3));
+    // 区间是左闭右开的，end 本身不在里面
+    assert_eq!(range.index_of(VirtPageNum(0x1000_4)), None);
+    assert_eq!(range.index_of(VirtPageNum(0xffff)), None);
+    let empty = VPNRange::new(VirtPageNum(0x1000_0), VirtPageNum(0x1000_0));
+    assert_eq!(empty.len(), 0);
+    assert!(empty.is_empty());
+    assert_eq!(empty.index_of(VirtPageNum(0x1000_0)), None);
+    info!("vpn_range_index_test passed!");
+}
+
+#[allow(unused)]
+/// check floor/ceil under an alternate 16KiB paging scheme
+pub fn paging_scheme_test() {
+    struct Sv39Large;
+    impl PagingScheme for Sv39Large {
+        const PAGE_SIZE: usize = 0x4000;
+        const PAGE_SIZE_BITS: usize = 14;
+    }
+    let va = VirtAddr::from(0x5000);
+    assert_eq!(va.floor(), va.floor_in::<Sv39>());
+    assert_eq!(va.floor(), VirtPageNum(5));
+    assert_eq!(va.floor_in::<Sv39Large>(), VirtPageNum(1));
+    assert_eq!(va.ceil_in::<Sv39Large>(), VirtPageNum(2));
+    assert_eq!(va.page_offset_in::<Sv39Large>(), 0x1000);
+    assert_eq!(VirtAddr::from(0x8000).ceil_in::<Sv39Large>(), VirtPageNum(2));
+    assert_eq!(PhysAddr::from(0x8001).floor_in::<Sv39Large>(), PhysPageNum(2));
+    info!("paging_scheme_test passed!");
+}
+
+#[allow(unused)]
+/// a simple test for `PhysPageNum::get_mut`
+///
+/// A type larger than a page, e.g. `[u8; PAGE_SIZE + 1]`, trips the debug
+/// assertion in `get_mut` and panics the kernel, so it is not exercised here.
+pub fn get_mut_test() {
+    #[repr(C)]
+    struct Header {
+        magic: u32,
+        len: u32,
+        next: usize,
+    }
+    let frame = super::frame_alloc().unwrap();
+    let header: &mut Header = frame.ppn.get_mut();
+    header.magic = 0xdead_beef;
+    header.len = 3;
+    header.next = 0x1000;
+    let bytes = frame.ppn.get_bytes_array();
+    assert_eq!(&bytes[..4], &0xdead_beefu32.to_ne_bytes());
+    assert_eq!(&bytes[4..8], &3u32.to_ne_bytes());
+    assert_eq!(&bytes[8..16], &0x1000usize.to_ne_bytes());
+    info!("get_mut_test passed!");
+}
+
+#[allow(unused)]
+/// check conversions between addresses, page numbers and `usize`
+pub fn address_conversion_test() {
+    let va = VirtAddr::from(0x1234_5678);
+    assert_eq!(usize::from(va), 0x1234_5678);
+    assert_eq!(va.floor(), VirtPageNum(0x1234_5));
+    assert_eq!(va.ceil(), VirtPageNum(0x1234_6));
+    assert_eq!(VirtAddr::from(va.floor()), VirtAddr::from(0x1234_5000));
+    // 页对齐的地址与页号可以互相转换而不丢失信息
+    let vpn = VirtPageNum::from(VirtAddr::from(0x1234_5000));
+    assert_eq!(vpn, VirtPageNum(0x1234_5));
+    assert_eq!(VirtAddr::from(vpn).floor(), vpn);
+    assert_eq!(VirtAddr::from(0x1234_5000).ceil(), vpn);
+    assert_eq!(VirtAddr::from(0x1234_5000).try_into_vpn(), Ok(vpn));
+    let pa = PhysAddr::from(0x8020_1000);
+    assert_eq!(PhysPageNum::from(pa), PhysPageNum(0x8020_1));
+    assert_eq!(PhysAddr::from(PhysPageNum(0x8020_1)), pa);
+    assert_eq!(usize::from(PhysPageNum::from(0x8020_1)), 0x8020_1);
+    // 三级页索引从高到低排列
+    assert_eq!(VirtPageNum((1 << 18) | (2 << 9) | 3).indexes(), [1, 2, 3]);
+    info!("address_conversion_test passed!");
+}
+
+#[allow(unused)]
+/// a simple test for `PhysAddr::get_ref` and `PhysAddr::get_mut` at a
+/// nonzero offset in the page
+pub fn phys_addr_get_mut_test() {
+    #[repr(C)]
+    struct Pair {
+        sec: usize,
+        usec: usize,
+    }
+    let frame = super::frame_alloc().unwrap();
+    let pa = PhysAddr::from(frame.ppn) + 0xff0;
+    assert_eq!(pa.page_offset(), 0xff0);
+    let pair = pa.get_mut::<Pair>();
+    pair.sec = 1;
+    pair.usec = 0x1234;
+    assert_eq!(pa.get_ref::<Pair>().sec, 1);
+    assert_eq!(pa.get_ref::<Pair>().usec, 0x1234);
+    // 数据落在页内偏移 0xff0 处，页开头没有被写入
+    let bytes = frame.ppn.get_bytes_array();
+    assert_eq!(&bytes[0xff0..0xff8], &1usize.to_ne_bytes());
+    assert_eq!(&bytes[0xff8..], &0x1234usize.to_ne_bytes());
+    assert!(bytes[..0xff0].iter().all(|byte| *byte == 0));
+    info!("phys_addr_get_mut_test passed!");
+}
+
+#[allow(unused)]
+/// check `try_into_vpn` accepts only page aligned addresses, and the message
+/// `From<VirtAddr>` panics with names the address and its offset
+pub fn unaligned_vpn_test() {
+    use alloc::format;
+    assert_eq!(VirtAddr::from(0).try_into_vpn(), Ok(VirtPageNum(0)));
+    assert_eq!(VirtAddr::from(0x1000_0000).try_into_vpn(), Ok(VirtPageNum(0x1000_0)));
+    for va in [0x1000_0001, 0x1000_0fff, 0x1000_0800] {
+        assert_eq!(
+            VirtAddr::from(va).try_into_vpn(),
+            Err(UnalignedAddr(VirtAddr::from(va)))
+        );
+    }
+    let message = format!("{}", VirtAddr::from(0x1000_0123).try_into_vpn().unwrap_err());
+    assert!(message.contains("0x10000123"), "{}", message);
+    assert!(message.contains("offset 0x123"), "{}", message);
+    info!("unaligned_vpn_test passed!");
+}
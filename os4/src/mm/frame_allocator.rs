@@ -1,11 +1,12 @@
 //! Implementation of [`FrameAllocator`] which 
 //! controls all the frames in the operating system.
 
-use super::{PhysAddr, PhysPageNum};
-use crate::config::MEMORY_END;
+use super::{memory_end, PhysAddr, PhysPageNum};
 use crate::sync::UPSafeCell;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
+use core::ops::{Deref, DerefMut};
 use lazy_static::*;
 
 /// manage a frame which has the same lifecycle as the tracker
@@ -34,6 +35,18 @@ impl FrameTracker {
         }
         Self { ppn }
     }
+    /// Wrap `ppn` without clearing it, the caller must overwrite every byte
+    /// before the frame becomes visible to user space.
+    pub fn new_uninit(ppn: PhysPageNum) -> Self {
+        Self { ppn }
+    }
+    /// Another owner of the same frame, which is freed only when every
+    /// tracker of it has been dropped.
+    #[allow(unused)]
+    pub fn share(&self) -> Self {
+        FRAME_ALLOCATOR.exclusive_access().inc_ref(self.ppn);
+        Self { ppn: self.ppn }
+    }
 }
 
 impl Debug for FrameTracker {
@@ -47,7 +60,7 @@ impl Debug for FrameTracker {
  */
 impl Drop for FrameTracker {
     fn drop(&mut self) {
-        frame_dealloc(self.ppn);
+        frame_dec_ref(self.ppn);
     }
 }
 
@@ -66,11 +79,21 @@ trait FrameAllocator {
  * current: 可分配的物理地址的起始位置
  * end: 可分配物理地址的最终位置，current-end 表示这里的地址从来没有使用过
  * recycled: 已经分配过回收的内存地址，可重复使用的地址
+ * allocated: 当前已经分配出去的页帧数
+ * peak: allocated 曾经达到过的最大值
+ * limit: 测试用，allocated 不能超过的上限
+ * refs: 被多个 FrameTracker 共享的页帧的引用计数，只有一个所有者的页帧不在表里，
+ *       所以最常见的独占页帧不需要任何额外的记录
  */
 pub struct StackFrameAllocator {
     current: usize,
     end: usize,
     recycled: Vec<usize>,
+    allocated: usize,
+    peak: usize,
+    #[cfg(feature = "self_test")]
+    limit: Option<usize>,
+    refs: BTreeMap<usize, usize>,
 }
 
 impl StackFrameAllocator {
@@ -81,6 +104,77 @@ impl StackFrameAllocator {
         self.current = l.0;
         self.end = r.0;
     }
+    /**
+     * 分配 n 个物理页号连续的页帧，只能从从未分配过的 current..end 中切出来，
+     * 回收栈 recycled 中的页帧并不连续
+     */
+    pub fn alloc_contiguous(&mut self, n: usize) -> Option<PhysPageNum> {
+        if self.end - self.current < n || self.limited() < n {
+            return None;
+        }
+        self.current += n;
+        self.add_allocated(n);
+        Some((self.current - n).into())
+    }
+    /// number of frames currently allocated
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+    /// most frames ever allocated at the same time
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+    /// number of frames that can still be allocated
+    pub fn available(&self) -> usize {
+        ((self.end - self.current) + self.recycled.len()).min(self.limited())
+    }
+    /// One more owner of the allocated frame `ppn`.
+    pub fn inc_ref(&mut self, ppn: PhysPageNum) {
+        debug_assert!(ppn.0 < self.current, "Frame ppn={:#x} has not been allocated!", ppn.0);
+        *self.refs.entry(ppn.0).or_insert(1) += 1;
+    }
+    /// One owner of `ppn` is gone, true if it was the last one and the frame
+    /// should be freed.
+    pub fn dec_ref(&mut self, ppn: PhysPageNum) -> bool {
+        match self.refs.get_mut(&ppn.0) {
+            // 没有记录说明只有一个所有者
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    self.refs.remove(&ppn.0);
+                }
+                false
+            }
+        }
+    }
+    /// number of owners of the allocated frame `ppn`
+    pub fn ref_count(&self, ppn: PhysPageNum) -> usize {
+        self.refs.get(&ppn.0).copied().unwrap_or(1)
+    }
+    /// Pretend only `n` more frames can be allocated from now on, so tests can
+    /// run out of memory without using up the real RAM.
+    #[cfg(feature = "self_test")]
+    pub fn set_limit(&mut self, n: usize) {
+        self.limit = Some(self.allocated + n);
+    }
+    /// undo [`StackFrameAllocator::set_limit`]
+    #[cfg(feature = "self_test")]
+    pub fn clear_limit(&mut self) {
+        self.limit = None;
+    }
+    /// number of frames the limit still allows, `usize::MAX` without a limit
+    fn limited(&self) -> usize {
+        #[cfg(feature = "self_test")]
+        if let Some(limit) = self.limit {
+            return limit.saturating_sub(self.allocated);
+        }
+        usize::MAX
+    }
+    fn add_allocated(&mut self, n: usize) {
+        self.allocated += n;
+        self.peak = self.peak.max(self.allocated);
+    }
 }
 impl FrameAllocator for StackFrameAllocator {
     fn new() -> Self {
@@ -88,6 +182,11 @@ impl FrameAllocator for StackFrameAllocator {
             current: 0,
             end: 0,
             recycled: Vec::new(),
+            allocated: 0,
+            peak: 0,
+            #[cfg(feature = "self_test")]
+            limit: None,
+            refs: BTreeMap::new(),
         }
     }
 
@@ -98,7 +197,10 @@ impl FrameAllocator for StackFrameAllocator {
     fn alloc(&mut self) -> Option<PhysPageNum> {
         // 如果从回收的物理内存中可以获取到可再利用的地址
         // 就返回可以回收的地址空间
-        if let Some(ppn) = self.recycled.pop() {
+        let ppn = if self.limited() == 0 {
+            // 测试设置的上限已经用完
+            None
+        } else if let Some(ppn) = self.recycled.pop() {
             Some(ppn.into())
         } else if self.current == self.end {
             // 否则，判断是否可用物理内存耗尽
@@ -108,7 +210,11 @@ impl FrameAllocator for StackFrameAllocator {
             self.current += 1;
             // 分配物理地址
             Some((self.current - 1).into())
+        };
+        if ppn.is_some() {
+            self.add_allocated(1);
         }
+        ppn
     }
     /**
      * 物理页帧的回收
@@ -126,10 +232,150 @@ impl FrameAllocator for StackFrameAllocator {
         // recycle
         // 回收地址空间
         self.recycled.push(ppn);
+        self.allocated -= 1;
+    }
+}
+
+/**
+ * 回收的页帧按物理页号从大到小保存在 recycled 中，pop 出来的总是最小的空闲页帧
+ * 分配顺序只取决于空闲页帧的集合，与之前分配和回收的先后无关，
+ * 页表的 dump 在不同的运行之间可以逐字比较
+ */
+/// a [`StackFrameAllocator`] always returning the lowest free frame
+pub struct OrderedFrameAllocator(StackFrameAllocator);
+
+impl Deref for OrderedFrameAllocator {
+    type Target = StackFrameAllocator;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for OrderedFrameAllocator {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl FrameAllocator for OrderedFrameAllocator {
+    fn new() -> Self {
+        Self(StackFrameAllocator::new())
+    }
+    /// recycled 中的页号都小于 current ，最后一个就是最小的空闲页帧
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        self.0.alloc()
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        self.0.dealloc(ppn);
+        // 把刚放到栈顶的页号挪到有序的位置上
+        let ppn = self.0.recycled.pop().unwrap();
+        let pos = self.0.recycled.partition_point(|v| *v > ppn);
+        self.0.recycled.insert(pos, ppn);
+    }
+}
+
+/**
+ * StackFrameAllocator 回收时要在 recycled 里线性查找来发现重复回收，
+ * 内存大、mmap 频繁时很慢。这里另外用一个位图记录每个页帧是否已分配，
+ * 分配和回收都只需要改一位，重复回收也只需要看一位
+ */
+/// a [`StackFrameAllocator`] checking frees against a bitset of allocated
+/// frames in constant time
+pub struct BitmapFrameAllocator {
+    inner: StackFrameAllocator,
+    /// first frame managed, bit `i` stands for `base + i`
+    base: usize,
+    /// set for every allocated frame
+    bits: Vec<u64>,
+}
+
+impl Deref for BitmapFrameAllocator {
+    type Target = StackFrameAllocator;
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+// 位图要跟着变的 init 和 alloc_contiguous 在下面重新实现，不会走到 inner 的版本
+impl DerefMut for BitmapFrameAllocator {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl BitmapFrameAllocator {
+    pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
+        self.inner.init(l, r);
+        self.base = l.0;
+        self.bits = Vec::new();
+        self.bits.resize((r.0 - l.0 + 63) / 64, 0);
+    }
+    /// like [`StackFrameAllocator::alloc_contiguous`], marking every frame
+    pub fn alloc_contiguous(&mut self, n: usize) -> Option<PhysPageNum> {
+        let start = self.inner.alloc_contiguous(n)?;
+        for ppn in start.0..start.0 + n {
+            self.set(ppn, true);
+        }
+        Some(start)
+    }
+    /// whether `ppn` is currently allocated
+    pub fn is_allocated(&self, ppn: PhysPageNum) -> bool {
+        match ppn.0.checked_sub(self.base) {
+            Some(i) if i < self.bits.len() * 64 => self.bits[i / 64] & (1 << (i % 64)) != 0,
+            _ => false,
+        }
+    }
+    /// Free `ppn`, false without changing anything if it is not allocated,
+    /// e.g. freed twice.
+    pub fn try_dealloc(&mut self, ppn: PhysPageNum) -> bool {
+        if !self.is_allocated(ppn) {
+            return false;
+        }
+        self.set(ppn.0, false);
+        // 位图已经排除了重复回收，不再走 StackFrameAllocator::dealloc 的线性检查
+        self.inner.recycled.push(ppn.0);
+        self.inner.allocated -= 1;
+        true
+    }
+    fn set(&mut self, ppn: usize, allocated: bool) {
+        let i = ppn - self.base;
+        if allocated {
+            self.bits[i / 64] |= 1 << (i % 64);
+        } else {
+            self.bits[i / 64] &= !(1 << (i % 64));
+        }
+    }
+}
+
+impl FrameAllocator for BitmapFrameAllocator {
+    fn new() -> Self {
+        Self {
+            inner: StackFrameAllocator::new(),
+            base: 0,
+            bits: Vec::new(),
+        }
+    }
+    fn alloc(&mut self) -> Option<PhysPageNum> {
+        let ppn = self.inner.alloc()?;
+        self.set(ppn.0, true);
+        Some(ppn)
+    }
+    fn dealloc(&mut self, ppn: PhysPageNum) {
+        if !self.try_dealloc(ppn) {
+            panic!("Frame ppn={:#x} has not been allocated!", ppn.0);
+        }
     }
 }
 
+#[cfg(all(feature = "ordered_frames", feature = "bitmap_frames"))]
+compile_error!("features `ordered_frames` and `bitmap_frames` pick different frame allocators, enable at most one");
+
+#[cfg(not(any(feature = "ordered_frames", feature = "bitmap_frames")))]
 type FrameAllocatorImpl = StackFrameAllocator;
+#[cfg(feature = "ordered_frames")]
+type FrameAllocatorImpl = OrderedFrameAllocator;
+#[cfg(all(feature = "bitmap_frames", not(feature = "ordered_frames")))]
+type FrameAllocatorImpl = BitmapFrameAllocator;
 
 lazy_static! {
     /// frame allocator instance through lazy_static!
@@ -137,14 +383,14 @@ lazy_static! {
         unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
 }
 
-/// initiate the frame allocator using `ekernel` and `MEMORY_END`
+/// initiate the frame allocator using `ekernel` and the detected end of memory
 pub fn init_frame_allocator() {
     extern "C" {
         fn ekernel();
     }
     FRAME_ALLOCATOR.exclusive_access().init(
         PhysAddr::from(ekernel as usize).ceil(),
-        PhysAddr::from(MEMORY_END).floor(),
+        PhysAddr::from(memory_end()).floor(),
     );
 }
 
@@ -160,9 +406,57 @@ pub fn frame_alloc() -> Option<FrameTracker> {
         .map(FrameTracker::new)
 }
 
-/// deallocate a frame
-fn frame_dealloc(ppn: PhysPageNum) {
-    FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
+/// allocate `n` frames with consecutive physical page numbers
+pub fn frame_alloc_contiguous(n: usize) -> Option<Vec<FrameTracker>> {
+    let start = FRAME_ALLOCATOR.exclusive_access().alloc_contiguous(n)?;
+    Some(
+        (start.0..start.0 + n)
+            .map(|ppn| FrameTracker::new(ppn.into()))
+            .collect(),
+    )
+}
+
+/// allocate a frame without zeroing it, see [`FrameTracker::new_uninit`]
+pub fn frame_alloc_uninit() -> Option<FrameTracker> {
+    FRAME_ALLOCATOR
+        .exclusive_access()
+        .alloc()
+        .map(FrameTracker::new_uninit)
+}
+
+/// number of frames currently allocated and the most ever allocated at once
+pub fn frame_usage() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.exclusive_access();
+    (allocator.allocated(), allocator.peak())
+}
+
+/// number of frames that can still be allocated
+pub fn frames_available() -> usize {
+    FRAME_ALLOCATOR.exclusive_access().available()
+}
+
+/// cap the frames left to allocate at `n`, `None` removes the cap
+#[cfg(feature = "self_test")]
+pub fn frame_set_limit(n: Option<usize>) {
+    let mut allocator = FRAME_ALLOCATOR.exclusive_access();
+    match n {
+        Some(n) => allocator.set_limit(n),
+        None => allocator.clear_limit(),
+    }
+}
+
+/// number of owners of the allocated frame `ppn`
+#[allow(unused)]
+pub fn frame_ref_count(ppn: PhysPageNum) -> usize {
+    FRAME_ALLOCATOR.exclusive_access().ref_count(ppn)
+}
+
+/// drop one reference to a frame, deallocating it with the last one
+fn frame_dec_ref(ppn: PhysPageNum) {
+    let mut allocator = FRAME_ALLOCATOR.exclusive_access();
+    if allocator.dec_ref(ppn) {
+        allocator.dealloc(ppn);
+    }
 }
 
 #[allow(unused)]
@@ -183,3 +477,197 @@ pub fn frame_allocator_test() {
     drop(v);
     info!("frame_allocator_test passed!");
 }
+
+#[allow(unused)]
+/// check a larger memory size reported at boot gives more frames
+pub fn memory_probe_test() {
+    use super::probe::{probe_memory_end, MemorySource};
+    use crate::config::MEMORY_END;
+    struct FixedMemory(Option<usize>);
+    impl MemorySource for FixedMemory {
+        fn memory_end(&self) -> Option<usize> {
+            self.0
+        }
+    }
+    fn allocatable(end: usize) -> usize {
+        let mut allocator = StackFrameAllocator::new();
+        allocator.init(PhysAddr::from(0x8040_0000).ceil(), PhysAddr::from(end).floor());
+        let mut count = 0;
+        while allocator.alloc().is_some() {
+            count += 1;
+        }
+        count
+    }
+    assert_eq!(probe_memory_end(&FixedMemory(None)), MEMORY_END);
+    assert_eq!(probe_memory_end(&FixedMemory(Some(0))), MEMORY_END);
+    let small = probe_memory_end(&FixedMemory(Some(0x8100_0000)));
+    let large = probe_memory_end(&FixedMemory(Some(0x8800_0000)));
+    assert_eq!(
+        allocatable(large) - allocatable(small),
+        (0x8800_0000 - 0x8100_0000) / 0x1000
+    );
+    info!("memory_probe_test passed!");
+}
+
+#[allow(unused)]
+/// check the high-water mark survives freeing frames
+pub fn frame_peak_test() {
+    let mut allocator = StackFrameAllocator::new();
+    allocator.init(PhysPageNum(0x80400), PhysPageNum(0x80500));
+    let ppns: Vec<PhysPageNum> = (0..10).map(|_| allocator.alloc().unwrap()).collect();
+    for ppn in &ppns[..5] {
+        allocator.dealloc(*ppn);
+    }
+    assert_eq!(allocator.allocated(), 5);
+    assert_eq!(allocator.peak(), 10);
+    // 重新分配回收的页帧不会超过之前的峰值
+    for _ in 0..5 {
+        allocator.alloc().unwrap();
+    }
+    assert_eq!(allocator.peak(), 10);
+    allocator.alloc().unwrap();
+    assert_eq!(allocator.peak(), 11);
+    info!("frame_peak_test passed!");
+}
+
+#[allow(unused)]
+/// check `frames_available` follows an allocation and its release
+pub fn frames_available_test() {
+    let before = frames_available();
+    assert!(before > 0);
+    let frame = frame_alloc().unwrap();
+    assert_eq!(frames_available(), before - 1);
+    drop(frame);
+    assert_eq!(frames_available(), before);
+    let frames = frame_alloc_contiguous(3).unwrap();
+    assert_eq!(frames_available(), before - 3);
+    drop(frames);
+    assert_eq!(frames_available(), before);
+    info!("frames_available_test passed!");
+}
+
+#[allow(unused)]
+/// check allocation fails cleanly once the test limit is used up
+#[cfg(feature = "self_test")]
+pub fn frame_limit_test() {
+    let before = frames_available();
+    frame_set_limit(Some(3));
+    assert_eq!(frames_available(), 3);
+    let mut frames: Vec<FrameTracker> = (0..3).map(|_| frame_alloc().unwrap()).collect();
+    assert_eq!(frames_available(), 0);
+    assert!(frame_alloc().is_none());
+    assert!(frame_alloc_uninit().is_none());
+    assert!(frame_alloc_contiguous(1).is_none());
+    // 释放之后上限内的页帧又可以分配
+    frames.pop();
+    assert_eq!(frames_available(), 1);
+    frames.push(frame_alloc().unwrap());
+    assert!(frame_alloc().is_none());
+    drop(frames);
+    frame_set_limit(None);
+    assert_eq!(frames_available(), before);
+    info!("frame_limit_test passed!");
+}
+
+#[allow(unused)]
+/// check `OrderedFrameAllocator` hands out frames in ascending order no
+/// matter in which order they were freed
+pub fn ordered_frame_allocator_test() {
+    fn run(free_order: &[usize]) -> Vec<usize> {
+        let mut allocator = OrderedFrameAllocator::new();
+        allocator.init(PhysPageNum(0x80400), PhysPageNum(0x80500));
+        let ppns: Vec<PhysPageNum> = (0..8).map(|_| allocator.alloc().unwrap()).collect();
+        for i in free_order {
+            allocator.dealloc(ppns[*i]);
+        }
+        (0..4).map(|_| allocator.alloc().unwrap().0).collect()
+    }
+    let expected = [0x80401, 0x80403, 0x80405, 0x80406];
+    assert_eq!(run(&[1, 3, 5, 6]), expected);
+    assert_eq!(run(&[6, 1, 5, 3]), expected);
+    assert_eq!(run(&[5, 6, 3, 1]), expected);
+    // 空闲页帧用完后继续从没分配过的区域按顺序分配
+    let mut allocator = OrderedFrameAllocator::new();
+    allocator.init(PhysPageNum(0x80400), PhysPageNum(0x80500));
+    let ppns: Vec<PhysPageNum> = (0..4).map(|_| allocator.alloc().unwrap()).collect();
+    allocator.dealloc(ppns[2]);
+    allocator.dealloc(ppns[0]);
+    let order: Vec<usize> = (0..3).map(|_| allocator.alloc().unwrap().0).collect();
+    assert_eq!(order, [0x80400, 0x80402, 0x80404]);
+    assert_eq!(allocator.allocated(), 5);
+    info!("ordered_frame_allocator_test passed!");
+}
+
+#[allow(unused)]
+/// check a frame with two trackers is freed once, by the last one dropped
+pub fn frame_ref_count_test() {
+    let (allocated, _) = frame_usage();
+    let frame = frame_alloc().unwrap();
+    let ppn = frame.ppn;
+    assert_eq!(frame_ref_count(ppn), 1);
+    let other = frame.share();
+    assert_eq!(other.ppn, ppn);
+    assert_eq!(frame_ref_count(ppn), 2);
+    assert_eq!(frame_usage().0, allocated + 1);
+    drop(frame);
+    // 还有一个所有者，页帧不能被回收
+    assert_eq!(frame_ref_count(ppn), 1);
+    assert_eq!(frame_usage().0, allocated + 1);
+    drop(other);
+    assert_eq!(frame_usage().0, allocated);
+    // 重新分配时拿到的页帧引用计数从 1 开始
+    let frame = frame_alloc().unwrap();
+    assert_eq!(frame_ref_count(frame.ppn), 1);
+    drop(frame);
+    assert_eq!(frame_usage().0, allocated);
+    info!("frame_ref_count_test passed!");
+}
+
+#[allow(unused)]
+/// check `BitmapFrameAllocator` through heavy alloc/dealloc churn, and that a
+/// double free is caught by its bit even with many frames recycled
+pub fn bitmap_frame_allocator_test() {
+    let mut allocator = BitmapFrameAllocator::new();
+    allocator.init(PhysPageNum(0x80400), PhysPageNum(0x80c00));
+    let mut ppns: Vec<PhysPageNum> = Vec::new();
+    for round in 0..16 {
+        while let Some(ppn) = allocator.alloc() {
+            assert!(allocator.is_allocated(ppn));
+            ppns.push(ppn);
+        }
+        assert_eq!(allocator.allocated(), 0x800);
+        // 每轮回收不同的一部分页帧，顺序也被打乱
+        let mut kept = Vec::new();
+        for (i, ppn) in ppns.drain(..).enumerate() {
+            if (i + round) % 3 == 0 {
+                kept.push(ppn);
+            } else {
+                allocator.dealloc(ppn);
+                assert!(!allocator.is_allocated(ppn));
+            }
+        }
+        ppns = kept;
+    }
+    for ppn in ppns.drain(..) {
+        allocator.dealloc(ppn);
+    }
+    assert_eq!(allocator.allocated(), 0);
+    assert_eq!(allocator.available(), 0x800);
+    // 2048 个页帧都在回收栈里，重复回收仍然只看一位就能发现
+    let ppn = allocator.alloc().unwrap();
+    assert!(allocator.try_dealloc(ppn));
+    assert!(!allocator.try_dealloc(ppn));
+    assert_eq!(allocator.allocated(), 0);
+    // 不归它管的页帧同样拒绝
+    assert!(!allocator.try_dealloc(PhysPageNum(0x80c00)));
+    assert!(!allocator.try_dealloc(PhysPageNum(0x80300)));
+    // 连续分配的页帧同样记在位图里
+    let mut allocator = BitmapFrameAllocator::new();
+    allocator.init(PhysPageNum(0x80400), PhysPageNum(0x80500));
+    let start = allocator.alloc_contiguous(4).unwrap();
+    assert!((start.0..start.0 + 4).all(|ppn| allocator.is_allocated(PhysPageNum(ppn))));
+    allocator.dealloc(PhysPageNum(start.0 + 1));
+    assert!(!allocator.try_dealloc(PhysPageNum(start.0 + 1)));
+    assert_eq!(allocator.allocated(), 3);
+    info!("bitmap_frame_allocator_test passed!");
+}
@@ -1,13 +1,19 @@
 //! Implementation of [`MapArea`] and [`MemorySet`].
 
-use super::{frame_alloc, FrameTracker};
-use super::{PTEFlags, PageTable, PageTableEntry};
+use super::frame_allocator::frame_ref_count;
+use super::{frame_alloc, frame_alloc_contiguous, frame_alloc_uninit, frames_available, FrameTracker};
+use super::{flush_tlb_vpn, translated_byte_buffer, PTEFlags, PageTable, PageTableEntry};
 use super::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use super::{StepByOne, VPNRange};
-use crate::config::{MEMORY_END, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
-use alloc::collections::BTreeMap;
+use super::memory_end;
+use crate::config::{MMAP_BASE, MMAP_TOP, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT, USER_STACK_SIZE};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::fmt;
+use core::mem::size_of;
 use lazy_static::*;
 use riscv::register::satp;
 use spin::Mutex;
@@ -29,6 +35,12 @@ lazy_static! {
     /// a memory set instance through lazy_static! managing kernel space
     pub static ref KERNEL_SPACE: Arc<Mutex<MemorySet>> =
         Arc::new(Mutex::new(MemorySet::new_kernel()));
+    /// Frames of the read-only executable segments of the apps, by app index
+    /// and page, see [`MemorySet::from_elf_shared`]. Holds a reference of its
+    /// own, so the text of an app stays in memory for the next time it loads
+    /// while some space still maps it; the last space dropped evicts it.
+    static ref SHARED_TEXT: Mutex<BTreeMap<(usize, VirtPageNum), FrameTracker>> =
+        Mutex::new(BTreeMap::new());
 }
 
 /**
@@ -45,6 +57,8 @@ lazy_static! {
 pub struct MemorySet {
     pub page_table: PageTable,
     pub areas: Vec<MapArea>,
+    // from_elf_lazy 加载的应用镜像，缺页时从这里读出 ELF 段的数据
+    pub elf_image: Option<&'static [u8]>,
 }
 
 impl MemorySet {
@@ -53,23 +67,168 @@ impl MemorySet {
         Self {
             page_table: PageTable::new(),
             areas: Vec::new(),
+            elf_image: None,
         }
     }
     pub fn token(&self) -> usize {
         self.page_table.token()
     }
-    /// Assume that no conflicts.
+    /// Range of the first area overlapping `[start_vpn, end_vpn)`, if any.
+    pub fn first_conflict(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> Option<VPNRange> {
+        let range = VPNRange::new(start_vpn, end_vpn);
+        self.areas
+            .iter()
+            .find(|area| area.vpn_range.overlaps(&range))
+            .map(|area| area.vpn_range)
+    }
+    /// Fails without mapping anything if the area overlaps an existing one.
     pub fn insert_framed_area(
         &mut self,
         start_va: VirtAddr,
         end_va: VirtAddr,
         permission: MapPermission,
-    ) {
+    ) -> Result<(), MapError> {
+        self.insert_framed_area_ad(start_va, end_va, permission, false)
+    }
+    /// `insert_framed_area`, with the A and D bits of every page set up front
+    /// if `preset_ad`, otherwise left for the hardware to set.
+    pub fn insert_framed_area_ad(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+        preset_ad: bool,
+    ) -> Result<(), MapError> {
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        map_area.preset_ad = preset_ad;
+        let (start_vpn, end_vpn) = (map_area.vpn_range.get_start(), map_area.vpn_range.get_end());
+        if let Some(range) = self.first_conflict(start_vpn, end_vpn) {
+            return Err(MapError::Overlap(range.get_start(), range.get_end()));
+        }
         // 调用 push ，可以在当前地址空间插入一个 Framed 方式映射到 物理内存的逻辑段
-        self.push(
-            MapArea::new(start_va, end_va, MapType::Framed, permission),
-            None,
-        );
+        self.try_push_at(map_area, None, 0)
+    }
+    /// Like `insert_framed_area`, but backs the area with physically
+    /// contiguous frames when the allocator has enough of them, and with
+    /// separate frames otherwise. Returns whether the frames are contiguous.
+    /// 仍然用 4KiB 的页表项逐页映射
+    #[allow(unused)]
+    pub fn insert_contiguous_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> Result<bool, MapError> {
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, permission);
+        let (start_vpn, end_vpn) = (map_area.vpn_range.get_start(), map_area.vpn_range.get_end());
+        if let Some(range) = self.first_conflict(start_vpn, end_vpn) {
+            return Err(MapError::Overlap(range.get_start(), range.get_end()));
+        }
+        let frames = match frame_alloc_contiguous(map_area.vpn_range.len()) {
+            Some(frames) => frames,
+            None => {
+                self.try_push_at(map_area, None, 0)?;
+                return Ok(false);
+            }
+        };
+        let flags = PTEFlags::from(permission);
+        let active = self.page_table.is_active();
+        for (vpn, frame) in map_area.vpn_range.into_iter().zip(frames) {
+            let err = match self.page_table.map(vpn, frame.ppn, flags) {
+                Some(true) => None,
+                // 页号只有低 27 位参与页表索引，别名地址会撞上已有的映射
+                Some(false) => Some(MapError::Overlap(vpn, VirtPageNum(vpn.0 + 1))),
+                None => Some(MapError::NoFrameForNode(vpn)),
+            };
+            if let Some(err) = err {
+                // 只撤销本次映射的页面，已有的映射属于别的逻辑段，页帧随 map_area 一起释放
+                for mapped in VPNRange::new(start_vpn, vpn).into_iter().rev() {
+                    map_area.unmap_one(&mut self.page_table, mapped);
+                }
+                return Err(err);
+            }
+            if active {
+                flush_tlb_vpn(vpn);
+            }
+            map_area.data_frames.insert(vpn, frame);
+        }
+        self.areas.push(map_area);
+        Ok(true)
+    }
+    /// Like `insert_framed_area`, but a copy made by `clone_full` keeps
+    /// mapping the same frames instead of copying them.
+    #[allow(unused)]
+    pub fn insert_shared_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) -> Result<(), MapError> {
+        self.insert_framed_area(start_va, end_va, permission)?;
+        self.areas.last_mut().unwrap().shared = true;
+        Ok(())
+    }
+    /// A bare space with the trampoline and a framed area for every
+    /// `(start, end, permission)`, for testing without an ELF image.
+    /// Panics if two regions overlap.
+    #[cfg(feature = "self_test")]
+    pub fn new_test(regions: &[(usize, usize, MapPermission)]) -> Self {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        for &(start, end, permission) in regions {
+            if let Err(err) = memory_set.insert_framed_area(start.into(), end.into(), permission) {
+                panic!("test region {:#x}..{:#x} {}", start, end, err);
+            }
+        }
+        memory_set
+    }
+    /// Copy of a user space for a child task. Private areas get their own
+    /// frames holding a copy of the data, shared areas map the same frames,
+    /// which stay alive until neither space maps them. Fails when frames run
+    /// out, the partial copy is freed again.
+    #[allow(unused)]
+    pub fn clone_full(&self) -> Result<Self, MapError> {
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        for area in self.areas.iter() {
+            let mut new_area = MapArea::new(
+                area.vpn_range.get_start().into(),
+                area.vpn_range.get_end().into(),
+                area.map_type,
+                area.map_perm,
+            );
+            new_area.shared = area.shared;
+            new_area.preset_ad = area.preset_ad;
+            new_area.elf_offset = area.elf_offset;
+            new_area.file_len = area.file_len;
+            new_area.elf_vaddr = area.elf_vaddr;
+            if area.map_type == MapType::Identical {
+                new_area.map(&mut memory_set.page_table)?;
+                memory_set.areas.push(new_area);
+                continue;
+            }
+            // 只处理 data_frames 中的页面，被 drop_pages 释放的页面在子空间中同样没有映射
+            let flags = area.pte_flags();
+            for (vpn, frame) in area.data_frames.iter() {
+                let frame = if area.shared {
+                    frame.share()
+                } else {
+                    let copy = frame_alloc_uninit().ok_or(MapError::NoFrameForData(*vpn))?;
+                    copy.ppn
+                        .get_bytes_array()
+                        .copy_from_slice(frame.ppn.get_bytes_array());
+                    copy
+                };
+                // 失败时 new_area 和已经建好的 memory_set 一起释放，不会留下没有映射的页帧
+                memory_set
+                    .page_table
+                    .map(*vpn, frame.ppn, flags)
+                    .ok_or(MapError::NoFrameForNode(*vpn))?;
+                new_area.data_frames.insert(*vpn, frame);
+            }
+            memory_set.areas.push(new_area);
+        }
+        Ok(memory_set)
     }
 
     /**
@@ -77,10 +236,66 @@ impl MemorySet {
      * 如果它是以 Framed 方式映射到 物理内存，
      * 还可以可选地在那些被映射到的物理页帧上写入一些初始化数据 data
      */
-    fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+    fn push(&mut self, map_area: MapArea, data: Option<&[u8]>) {
+        self.push_at(map_area, data, 0);
+    }
+    /// Like `push`, but `data` starts `offset` bytes into the area.
+    /// Panics when frames run out, see `try_push_at`.
+    fn push_at(&mut self, map_area: MapArea, data: Option<&[u8]>, offset: usize) {
+        if let Err(err) = self.try_push_at(map_area, data, offset) {
+            panic!("[kernel] {}", err);
+        }
+    }
+    /// `push_at` that fails without adding the area when frames run out.
+    fn try_push_at(
+        &mut self,
+        mut map_area: MapArea,
+        data: Option<&[u8]>,
+        offset: usize,
+    ) -> Result<(), MapError> {
+        // 有初始数据的逻辑段会被 copy_data 整页写一遍，没必要先清零
+        map_area.skip_zeroing = data.is_some();
+        map_area.map(&mut self.page_table)?;
         if let Some(data) = data {
-            map_area.copy_data(&mut self.page_table, data);
+            map_area.copy_data_at(&mut self.page_table, data, offset);
+        }
+        self.areas.push(map_area);
+        Ok(())
+    }
+    /// `push_at` for a read-only segment of app `app_index`: the first space
+    /// loading it fills the frames and leaves them in `SHARED_TEXT`, later
+    /// ones map the same frames. The area is shared, so `clone_full` does not
+    /// copy it either.
+    fn push_shared_text(
+        &mut self,
+        mut map_area: MapArea,
+        data: &[u8],
+        offset: usize,
+        app_index: usize,
+    ) {
+        let mut shared_text = SHARED_TEXT.lock();
+        let cached: Option<Vec<FrameTracker>> = map_area
+            .vpn_range
+            .into_iter()
+            .map(|vpn| shared_text.get(&(app_index, vpn)).map(FrameTracker::share))
+            .collect();
+        map_area.shared = true;
+        let frames = match cached {
+            Some(frames) => frames,
+            None => {
+                self.push_at(map_area, Some(data), offset);
+                for (vpn, frame) in self.areas.last().unwrap().data_frames.iter() {
+                    shared_text.insert((app_index, *vpn), frame.share());
+                }
+                return;
+            }
+        };
+        let flags = map_area.pte_flags();
+        for (vpn, frame) in map_area.vpn_range.into_iter().zip(frames) {
+            if self.page_table.map(vpn, frame.ppn, flags).is_none() {
+                panic!("[kernel] {}", MapError::NoFrameForNode(vpn));
+            }
+            map_area.data_frames.insert(vpn, frame);
         }
         self.areas.push(map_area);
     }
@@ -153,7 +368,7 @@ impl MemorySet {
         memory_set.push(
             MapArea::new(
                 (ekernel as usize).into(),
-                MEMORY_END.into(),
+                memory_end().into(),
                 MapType::Identical,
                 MapPermission::R | MapPermission::W,
             ),
@@ -161,33 +376,90 @@ impl MemorySet {
         );
         memory_set
     }
+    /// A fresh copy of the kernel space for tests that need to change one:
+    /// the trampoline and every identical area of `KERNEL_SPACE` mapped again
+    /// with the same permission. The framed areas, kernel stacks of the tasks,
+    /// are left out, and the copy is not behind the global lock.
+    #[allow(unused)]
+    pub fn clone_kernel() -> Self {
+        let kernel_space = KERNEL_SPACE.lock();
+        let mut memory_set = Self::new_bare();
+        memory_set.map_trampoline();
+        for area in kernel_space.areas.iter() {
+            if area.map_type != MapType::Identical {
+                continue;
+            }
+            memory_set.push(
+                MapArea::new(
+                    area.vpn_range.get_start().into(),
+                    area.vpn_range.get_end().into(),
+                    MapType::Identical,
+                    area.map_perm,
+                ),
+                None,
+            );
+        }
+        memory_set
+    }
     /// Include sections in elf and trampoline and TrapContext and user stack,
-    /// also returns user_sp and entry point.
+    /// also returns user_sp and entry point. Fails on a malformed ELF image.
     // from_elf 则可以应用的 ELF 格式可执行文件 解析出各数据段并对应生成应用的地址空间
-    pub fn from_elf(elf_data: &[u8]) -> (Self, usize, usize) {
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, usize), ElfError> {
+        Self::from_elf_text(elf_data, None, None)
+    }
+    /// [`MemorySet::from_elf`] mapping no page of the ELF segments up front,
+    /// each one is read from `elf_data` by `handle_page_fault` when it is
+    /// first touched.
+    #[allow(unused)]
+    pub fn from_elf_lazy(elf_data: &'static [u8]) -> Result<(Self, usize, usize), ElfError> {
+        Self::from_elf_text(elf_data, None, Some(elf_data))
+    }
+    /// [`MemorySet::from_elf`] for app `app_index`, whose read-only executable
+    /// segments map the same frames in every space loaded from it. Writable
+    /// segments, the user stack and the trap context stay private.
+    pub fn from_elf_shared(
+        elf_data: &[u8],
+        app_index: usize,
+    ) -> Result<(Self, usize, usize), ElfError> {
+        Self::from_elf_text(elf_data, Some(app_index), None)
+    }
+    /// `from_elf`, sharing the text with other spaces of `shared_text` if set,
+    /// or leaving the segments to be loaded from `lazy_image` on demand
+    fn from_elf_text(
+        elf_data: &[u8],
+        shared_text: Option<usize>,
+        lazy_image: Option<&'static [u8]>,
+    ) -> Result<(Self, usize, usize), ElfError> {
         let mut memory_set = Self::new_bare();
         // map trampoline
         // 我们将跳板插入到应用地址空间；
         memory_set.map_trampoline();
         // map program headers of elf, with U flag
         // 我们使用外部 crate xmas_elf 来解析传入的应用 ELF 数据并可以轻松取出各个部分
-        let elf = xmas_elf::ElfFile::new(elf_data).unwrap();
+        let elf = xmas_elf::ElfFile::new(elf_data).map_err(ElfError::Invalid)?;
         let elf_header = elf.header;
         let magic = elf_header.pt1.magic;
         // 我们取出 ELF 的魔数来判断 它是不是一个合法的 ELF
-        assert_eq!(magic, [0x7f, 0x45, 0x4c, 0x46], "invalid elf!");
+        if magic != [0x7f, 0x45, 0x4c, 0x46] {
+            return Err(ElfError::BadMagic);
+        }
         let ph_count = elf_header.pt2.ph_count();
         let mut max_end_vpn = VirtPageNum(0);
         for i in 0..ph_count {
             // 我们可以直接得到 program header 的数目，
             // 然后遍历所有的 program header 并将合适的区域加入 到应用地址空间中
-            let ph = elf.program_header(i).unwrap();
+            let ph = elf.program_header(i).map_err(ElfError::Invalid)?;
             // 确认 program header 的类型是 LOAD ， 这表明它有被内核加载的必要，
             // 此时不必理会其他类型的 program header 。
-            if ph.get_type().unwrap() == xmas_elf::program::Type::Load {
+            if ph.get_type().map_err(ElfError::Invalid)? == xmas_elf::program::Type::Load {
+                let data_start = ph.offset() as usize;
+                let data_end = (ph.offset() + ph.file_size()) as usize;
+                if data_end > elf.input.len() {
+                    return Err(ElfError::Truncated);
+                }
                 // 通过 ph.virtual_addr() 和 ph.mem_size() 来计算这一区域在应用地址空间中的位置
-                let start_va: VirtAddr = (ph.virtual_addr() as usize).into();
-                let end_va: VirtAddr = ((ph.virtual_addr() + ph.mem_size()) as usize).into();
+                let vaddr = ph.virtual_addr() as usize;
+                let (start_va, end_va) = segment_bounds(vaddr, ph.mem_size() as usize, ph.align() as usize);
                 let mut map_perm = MapPermission::U;
                 // 确认这一区域访问方式的 限制并将其转换为 MapPermission 类型
                 let ph_flags = ph.flags();
@@ -200,14 +472,34 @@ impl MemorySet {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                let mut map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
+                map_area.elf_offset = Some(data_start);
+                map_area.file_len = data_end - data_start;
+                map_area.elf_vaddr = vaddr;
+                // 两个段共用一个页面时，后一个段的映射和数据会覆盖前一个段
+                if memory_set
+                    .first_conflict(map_area.vpn_range.get_start(), map_area.vpn_range.get_end())
+                    .is_some()
+                {
+                    return Err(ElfError::SharedPage);
+                }
                 // max_end_vpn 记录目前涉及到的最大的虚拟页号
                 max_end_vpn = map_area.vpn_range.get_end();
                 // 当前 program header 数据被存放的位置可以通过 ph.offset() 和 ph.file_size() 来找到
-                memory_set.push(
-                    map_area,
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                // [file_size, mem_size) 是 bss ，由 copy_data 清零
+                let offset = vaddr - VirtAddr::from(map_area.vpn_range.get_start()).0;
+                let data = &elf.input[data_start..data_end];
+                match shared_text {
+                    // 延迟加载的段一页都不映射，由 handle_page_fault 读入
+                    _ if lazy_image.is_some() => memory_set.areas.push(map_area),
+                    Some(app_index)
+                        if map_perm.contains(MapPermission::X)
+                            && !map_perm.contains(MapPermission::W) =>
+                    {
+                        memory_set.push_shared_text(map_area, data, offset, app_index);
+                    }
+                    _ => memory_set.push_at(map_area, Some(data), offset),
+                }
             }
         }
         // map user stack with U flags
@@ -236,13 +528,49 @@ impl MemorySet {
             ),
             None,
         );
+        memory_set.elf_image = lazy_image;
         // 返回应用地址空间 memory_set ，也同时返回用户栈虚拟地址 user_stack_top
         // 以及从解析 ELF 得到的该应用入口点地址
-        (
+        Ok((
             memory_set,
             user_stack_top,
             elf.header.pt2.entry_point() as usize,
-        )
+        ))
+    }
+    /// [`MemorySet::from_elf`] with `args` pushed onto the user stack, laid
+    /// out the way the user library's `_start` reads them. Also returns argc
+    /// and the address of argv, a null-terminated array of pointers to the
+    /// nul-terminated strings. user_sp is 16-byte aligned.
+    #[allow(unused)]
+    pub fn from_elf_with_args(
+        elf_data: &[u8],
+        args: &[&str],
+    ) -> Result<(Self, usize, usize, usize, usize), ElfError> {
+        // argv 数组、所有字符串以及对齐最多占用的字节数，不能超过用户栈
+        let args_size = (args.len() + 1) * size_of::<usize>()
+            + args.iter().map(|arg| arg.len() + 1).sum::<usize>()
+            + 16;
+        if args_size > USER_STACK_SIZE {
+            return Err(ElfError::ArgsTooLong);
+        }
+        let (memory_set, user_stack_top, entry_point) = Self::from_elf(elf_data)?;
+        let token = memory_set.token();
+        // 栈顶紧挨着 argv 数组，字符串放在它下面
+        let mut user_sp = user_stack_top - (args.len() + 1) * size_of::<usize>();
+        let argv_base = user_sp;
+        let mut argv: Vec<usize> = Vec::with_capacity(args.len() + 1);
+        for arg in args {
+            user_sp -= arg.len() + 1;
+            copy_to_space(token, user_sp, arg.as_bytes());
+            copy_to_space(token, user_sp + arg.len(), &[0]);
+            argv.push(user_sp);
+        }
+        argv.push(0);
+        for (i, ptr) in argv.iter().enumerate() {
+            copy_to_space(token, argv_base + i * size_of::<usize>(), &ptr.to_ne_bytes());
+        }
+        user_sp &= !0xf;
+        Ok((memory_set, user_sp, entry_point, args.len(), argv_base))
     }
 
     pub fn activate(&self) {
@@ -266,35 +594,740 @@ impl MemorySet {
         self.page_table.translate(vpn)
     }
 
-    pub fn range(&self,start_vpn: usize, end_vpn: usize) -> bool{
+    /// Whether `self` and `other` map the same pages of their areas holding
+    /// the same bytes, no matter which frames back them. Meant for checking
+    /// `clone_full` and friends.
+    #[allow(unused)]
+    pub fn equal_contents(&self, other: &Self) -> bool {
+        let (ours, theirs) = (self.resident_map(), other.resident_map());
+        ours.len() == theirs.len()
+            && ours.iter().zip(theirs.iter()).all(|((vpn, ppn), (other_vpn, other_ppn))| {
+                vpn == other_vpn && ppn.get_bytes_array() == other_ppn.get_bytes_array()
+            })
+    }
+    /// every mapped page of the areas and the frame behind it, sorted by vpn
+    fn resident_map(&self) -> BTreeMap<VirtPageNum, PhysPageNum> {
+        self.areas
+            .iter()
+            .flat_map(|area| area.iter_mappings(&self.page_table))
+            .collect()
+    }
+
+    /// Frame holding the trap context, `None` if `TRAP_CONTEXT` is not mapped.
+    pub fn trap_context_ppn(&self) -> Option<PhysPageNum> {
+        let vpn = VirtAddr::from(TRAP_CONTEXT).floor();
+        self.find_area_containing(vpn)
+            .and_then(|area| area.data_frames.get(&vpn))
+            .map(|frame| frame.ppn)
+    }
+
+    /// Physical address `va` maps to, `None` if its page is not mapped.
+    pub fn translate_va(&self, va: VirtAddr) -> Option<PhysAddr> {
+        self.translate(va.floor())
+            .filter(|pte| pte.is_valid())
+            .map(|pte| PhysAddr::from(PhysAddr::from(pte.ppn()).0 + va.page_offset()))
+    }
+
+    /// Whether user code may access `va` with every bit of `perm`.
+    /// 页表项必须带有 V 和 U 位，以及 perm 要求的 R/W/X 位
+    pub fn is_accessible(&self, va: VirtAddr, perm: MapPermission) -> bool {
+        let required = PTEFlags::V | PTEFlags::U | PTEFlags::from(perm);
+        self.page_table
+            .translate(va.floor())
+            .map_or(false, |pte| pte.flags().contains(required))
+    }
+
+    /// Move the `Framed` area starting at `start_vpn` onto freshly allocated
+    /// physically contiguous frames, keeping its virtual range and contents.
+    /// The old frames are freed. Returns false if there is no such area, some
+    /// of its frames is also used elsewhere or there are not enough
+    /// contiguous frames.
+    #[allow(unused)]
+    pub fn remap_contiguous(&mut self, start_vpn: VirtPageNum) -> bool {
+        let area = match self.areas.iter_mut().find(|area| {
+            area.vpn_range.get_start() == start_vpn && area.map_type == MapType::Framed
+        }) {
+            Some(area) => area,
+            None => return false,
+        };
+        // 别的地址空间、别名或共享代码段的缓存会继续使用旧页帧，共享就断开了
+        if area.shared
+            || area
+                .data_frames
+                .values()
+                .any(|frame| frame_ref_count(frame.ppn) > 1)
+        {
+            return false;
+        }
+        let pages = area.vpn_range.len();
+        let frames = match frame_alloc_contiguous(pages) {
+            Some(frames) => frames,
+            None => return false,
+        };
+        let active = self.page_table.is_active();
+        for (vpn, frame) in area.vpn_range.into_iter().zip(frames) {
+            let pte = match self.page_table.translate(vpn) {
+                Some(pte) if pte.is_valid() => pte,
+                // 被 drop_pages 释放掉的页面，继续保持未映射
+                _ => continue,
+            };
+            frame
+                .ppn
+                .get_bytes_array()
+                .copy_from_slice(pte.ppn().get_bytes_array());
+            self.page_table.map_force(vpn, frame.ppn, pte.flags());
+            if active {
+                flush_tlb_vpn(vpn);
+            }
+            // 替换掉旧的 FrameTracker ，旧页帧随之被回收
+            area.data_frames.insert(vpn, frame);
+        }
+        true
+    }
+
+    /// Extend the `Framed` area starting at `start_vpn` with `perm` by
+    /// `additional_pages` zeroed pages right after its end. Returns false,
+    /// mapping nothing, if there is no such area, the new pages would overlap
+    /// another area or frames run out.
+    #[allow(unused)]
+    pub fn grow_area(
+        &mut self,
+        start_vpn: VirtPageNum,
+        additional_pages: usize,
+        perm: MapPermission,
+    ) -> bool {
+        let pos = match self.areas.iter().position(|area| {
+            area.vpn_range.get_start() == start_vpn
+                && area.map_type == MapType::Framed
+                && area.map_perm == perm
+        }) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let old_end = self.areas[pos].vpn_range.get_end();
+        let new_end = match old_end.0.checked_add(additional_pages) {
+            Some(end) if end <= VirtAddr::from(TRAP_CONTEXT).floor().0 => VirtPageNum(end),
+            _ => return false,
+        };
+        if self.first_conflict(old_end, new_end).is_some() {
+            return false;
+        }
+        let area = &mut self.areas[pos];
+        // 有初始数据的逻辑段不清零页帧，新增的页面必须清零
+        let skip_zeroing = area.skip_zeroing;
+        area.skip_zeroing = false;
+        let mut grown = true;
+        for vpn in VPNRange::new(old_end, new_end) {
+            if area.map_one(&mut self.page_table, vpn) != Ok(true) {
+                for mapped in VPNRange::new(old_end, vpn).into_iter().rev() {
+                    area.unmap_one(&mut self.page_table, mapped);
+                }
+                grown = false;
+                break;
+            }
+        }
+        area.skip_zeroing = skip_zeroing;
+        if grown {
+            area.vpn_range = VPNRange::new(start_vpn, new_end);
+        }
+        grown
+    }
+
+    /// Map the page at `new_va` to the same frame as `existing_vpn` with
+    /// `perm`, without allocating. The frame is shared by both areas and only
+    /// freed once neither maps it. Returns false if `existing_vpn` is not a
+    /// framed page or `new_va` is already mapped.
+    #[allow(unused)]
+    pub fn map_alias(
+        &mut self,
+        existing_vpn: VirtPageNum,
+        new_va: VirtAddr,
+        perm: MapPermission,
+    ) -> bool {
+        let frame = match self
+            .find_area_containing(existing_vpn)
+            .and_then(|area| area.data_frames.get(&existing_vpn))
+        {
+            Some(frame) => frame.share(),
+            None => return false,
+        };
+        let new_vpn = new_va.floor();
+        // 被 drop_pages 释放或者还没有加载的页面没有页表项，但仍然属于某个逻辑段
+        if self.translate(new_vpn).map_or(false, |pte| pte.is_valid())
+            || self.first_conflict(new_vpn, VirtPageNum(new_vpn.0 + 1)).is_some()
+        {
+            return false;
+        }
+        let mut area = MapArea::new(
+            new_vpn.into(),
+            VirtPageNum(new_vpn.0 + 1).into(),
+            MapType::Framed,
+            perm,
+        );
+        // 页表节点分配不到时 frame 随之释放，引用计数恢复原样
+        if self.page_table.map(new_vpn, frame.ppn, PTEFlags::from(perm)) != Some(true) {
+            return false;
+        }
+        if self.page_table.is_active() {
+            flush_tlb_vpn(new_vpn);
+        }
+        area.data_frames.insert(new_vpn, frame);
+        self.areas.push(area);
+        true
+    }
 
-        for (index,item) in self.areas.iter().enumerate(){
-            let startv = item.vpn_range.get_start();
-            let endv = item.vpn_range.get_end();
-            if start_vpn ==  startv.0 && endv.0 == end_vpn {
-                return true;
+    /// Set `add` and clear `remove` on every mapped leaf PTE in
+    /// `[start_vpn, end_vpn)` in place, keeping the physical pages.
+    /// 与 range 重叠的逻辑段先在边界处拆开，只修改范围内逻辑段的 map_perm
+    #[allow(unused)]
+    pub fn protect_range(
+        &mut self,
+        start_vpn: VirtPageNum,
+        end_vpn: VirtPageNum,
+        add: PTEFlags,
+        remove: PTEFlags,
+    ) {
+        self.split_area_at(start_vpn);
+        self.split_area_at(end_vpn);
+        let active = self.page_table.is_active();
+        for vpn in VPNRange::new(start_vpn, end_vpn) {
+            if let Some(pte) = self.page_table.translate(vpn) {
+                if pte.is_valid() {
+                    let flags = (pte.flags() | add) - remove;
+                    self.page_table.map_force(vpn, pte.ppn(), flags);
+                    // 页表项被修改，快表中可能还缓存着旧的标志位
+                    if active {
+                        flush_tlb_vpn(vpn);
+                    }
+                }
+            }
+        }
+        for area in self.areas.iter_mut() {
+            if start_vpn <= area.vpn_range.get_start() && area.vpn_range.get_end() <= end_vpn {
+                area.map_perm =
+                    (area.map_perm | MapPermission::from(add)) - MapPermission::from(remove);
             }
         }
-        false
     }
 
+    /// Whether every page of `[start_vpn, end_vpn)` is mapped right now.
+    /// 逐页查页表，逻辑段还在但页面已经被 drop_pages 释放的情况也能发现
+    pub fn range(&self,start_vpn: usize, end_vpn: usize) -> bool{
+        start_vpn < end_vpn
+            && (start_vpn..end_vpn).all(|vpn| {
+                self.page_table
+                    .translate(VirtPageNum(vpn))
+                    .map_or(false, |pte| pte.is_valid())
+            })
+    }
+
+    /// Unmap `[start, start + len)`, with `len` rounded up to whole pages,
+    /// see [`MemorySet::unmap_area_by_range`]. `start` must be page aligned.
     pub fn remove(&mut self,start: usize, len: usize) -> isize{
-        // 如果取整将会导致结果 +1 与 0x10000000 结果相同
-        let start_vpn = VirtAddr::from(start);
-        let end_vpn = VirtAddr::from(start+len);
-        for (index,item) in self.areas.iter_mut().enumerate(){
-            let startv:VirtAddr = item.vpn_range.get_start().into();
-            let endv:VirtAddr = item.vpn_range.get_end().into();
-            if start_vpn.0 ==  startv.0 && endv.0 == end_vpn.0 {
-                item.unmap(&mut self.page_table);
-                self.areas.remove(index);
-                if start == 0x10000001{
-                    println!("0x10000000+1")
+        // 不对齐的 start 向下取整会把前面的数据一起解除映射，直接拒绝
+        if !VirtAddr::from(start).aligned() {
+            return -1;
+        }
+        let start_vpn = VirtAddr::from(start).floor();
+        let end_vpn = VirtAddr::from(start+len).ceil();
+        if self.unmap_area_by_range(start_vpn, end_vpn) < 0 {
+            return -1;
+        }
+        0
+    }
+
+    /// Unmap exactly the pages in `[start_vpn, end_vpn)`, splitting the areas
+    /// that stick out of it, and return how many pages were unmapped. Fails
+    /// with -1 without changing anything if the range is empty or some page
+    /// in it belongs to no area.
+    pub fn unmap_area_by_range(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> isize {
+        // 被 drop_pages 释放了页帧的页面仍然属于逻辑段，可以解除映射
+        if start_vpn >= end_vpn
+            || !VPNRange::new(start_vpn, end_vpn)
+                .into_iter()
+                .all(|vpn| self.find_area_containing(vpn).is_some())
+        {
+            return -1;
+        }
+        let mut freed = 0;
+        let mut index = 0;
+        while index < self.areas.len() {
+            let range = self.areas[index].vpn_range;
+            let lo = range.get_start().max(start_vpn);
+            let hi = range.get_end().min(end_vpn);
+            if lo >= hi {
+                index += 1;
+                continue;
+            }
+            // 把逻辑段拆成 [start, lo) [lo, hi) [hi, end) ，只解除中间一段的映射
+            let mut area = self.areas.remove(index);
+            let tail = if hi < range.get_end() {
+                Some(area.split_off(hi))
+            } else {
+                None
+            };
+            let mut middle = if lo > range.get_start() {
+                let middle = area.split_off(lo);
+                self.areas.insert(index, area);
+                index += 1;
+                middle
+            } else {
+                area
+            };
+            freed += middle.vpn_range.len();
+            middle.unmap(&mut self.page_table);
+            if let Some(tail) = tail {
+                self.areas.insert(index, tail);
+                index += 1;
+            }
+        }
+        freed as isize
+    }
+
+    /// Clear the W bit of every page in `[start_vpn, end_vpn)`, relro-style
+    /// once a program has set up its data, so later writes fault. Areas
+    /// sticking out of the range are split and keep their permission outside
+    /// it. Fails without changing anything if the range is empty or some page
+    /// in it belongs to no area.
+    pub fn make_readonly(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        if start_vpn >= end_vpn
+            || !VPNRange::new(start_vpn, end_vpn)
+                .into_iter()
+                .all(|vpn| self.find_area_containing(vpn).is_some())
+        {
+            return false;
+        }
+        self.split_area_at(start_vpn);
+        self.split_area_at(end_vpn);
+        let active = self.page_table.is_active();
+        for area in self.areas.iter_mut() {
+            if area.vpn_range.get_start() < start_vpn || end_vpn < area.vpn_range.get_end() {
+                continue;
+            }
+            area.map_perm.remove(MapPermission::W);
+            // 写时复制的页面不能再在写入时换成可写的私有页帧
+            area.cow_pages.clear();
+            for vpn in area.vpn_range {
+                let pte = match self.page_table.translate(vpn) {
+                    Some(pte) if pte.is_valid() => pte,
+                    _ => continue,
+                };
+                self.page_table.map_force(vpn, pte.ppn(), pte.flags() - PTEFlags::W);
+                if active {
+                    flush_tlb_vpn(vpn);
+                }
+            }
+        }
+        true
+    }
+
+    /// Split the area strictly containing `vpn` into `[start, vpn)` and
+    /// `[vpn, end)`, nothing to do if `vpn` starts an area or lies in none.
+    fn split_area_at(&mut self, vpn: VirtPageNum) {
+        let pos = self.areas.iter().position(|area| {
+            area.vpn_range.get_start() < vpn && vpn < area.vpn_range.get_end()
+        });
+        if let Some(pos) = pos {
+            let tail = self.areas[pos].split_off(vpn);
+            self.areas.insert(pos + 1, tail);
+        }
+    }
+
+    /// Log the layout of this address space, one area per line sorted by
+    /// start VPN. Returns how many areas were visited.
+    pub fn dump(&self) -> usize {
+        let mut areas: Vec<&MapArea> = self.areas.iter().collect();
+        areas.sort_by_key(|area| area.vpn_range.get_start());
+        info!("{:>12} {:>12} {:>7}  type/perm", "start vpn", "end vpn", "frames");
+        for area in areas.iter() {
+            info!(
+                "{:>#12x} {:>#12x} {:>7}  {:?} {:?}",
+                area.vpn_range.get_start().0,
+                area.vpn_range.get_end().0,
+                area.data_frames.len(),
+                area.map_type,
+                area.map_perm
+            );
+        }
+        areas.len()
+    }
+
+    /// Summary of every area sorted by start address, like the lines of
+    /// `/proc/self/maps`: `(start, end, permission, type)`.
+    pub fn regions(
+        &self,
+    ) -> impl Iterator<Item = (VirtAddr, VirtAddr, MapPermission, MapType)> + '_ {
+        let mut areas: Vec<&MapArea> = self.areas.iter().collect();
+        areas.sort_by_key(|area| area.vpn_range.get_start());
+        areas.into_iter().map(|area| {
+            (
+                area.vpn_range.get_start().into(),
+                area.vpn_range.get_end().into(),
+                area.map_perm,
+                area.map_type,
+            )
+        })
+    }
+
+    /// Bytes of virtual memory reserved by the areas, like `VmSize` in
+    /// `/proc/self/status`. Identical areas only count with
+    /// `include_identical`, they never hold frames of their own.
+    pub fn virtual_size(&self, include_identical: bool) -> usize {
+        self.areas
+            .iter()
+            .filter(|area| include_identical || area.map_type != MapType::Identical)
+            .map(|area| {
+                VirtAddr::from(area.vpn_range.get_end()).0
+                    - VirtAddr::from(area.vpn_range.get_start()).0
+            })
+            .sum()
+    }
+
+    /// Pages backed by a frame of their own, like `VmRSS` in
+    /// `/proc/self/status`. Pages dropped by `drop_pages` or not faulted in
+    /// yet do not count, identical areas never do.
+    pub fn resident_pages(&self) -> usize {
+        self.areas.iter().map(|area| area.data_frames.len()).sum()
+    }
+
+    /// Find the area whose `vpn_range` contains `vpn`.
+    pub fn find_area_containing(&self, vpn: VirtPageNum) -> Option<&MapArea> {
+        self.areas
+            .iter()
+            .find(|area| area.contains(vpn))
+    }
+
+    /// Lowest `pages` pages long gap between `MMAP_BASE` and `MMAP_TOP` not
+    /// covered by any area, returns its first page.
+    pub fn find_free_range(&self, pages: usize) -> Option<VirtPageNum> {
+        let top = VirtAddr::from(MMAP_TOP).floor();
+        let mut areas: Vec<&MapArea> = self.areas.iter().collect();
+        areas.sort_by_key(|area| area.vpn_range.get_start());
+        let mut candidate = VirtAddr::from(MMAP_BASE).floor();
+        for area in areas {
+            if area.vpn_range.get_end() <= candidate {
+                continue;
+            }
+            if area.vpn_range.get_start().0 >= candidate.0 + pages {
+                break;
+            }
+            // 与这个逻辑段冲突，从它的结尾继续找
+            candidate = area.vpn_range.get_end();
+        }
+        if candidate.0 + pages <= top.0 {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Panic if the bookkeeping of this address space is inconsistent, see
+    /// `find_invariant_violation`. Does nothing in release builds.
+    pub fn check_invariants(&self) {
+        if cfg!(debug_assertions) {
+            if let Some(violation) = self.find_invariant_violation() {
+                panic!("MemorySet invariant broken: {}", violation);
+            }
+        }
+    }
+
+    /// Describe the first broken invariant, if any:
+    /// - no two areas overlap
+    /// - every `data_frames` key of a `Framed` area lies in its `vpn_range`
+    /// - a page of a `Framed` area has a valid PTE exactly when it has a
+    ///   frame (pages freed by `drop_pages` have neither), and the PTE points
+    ///   at that frame
+    fn find_invariant_violation(&self) -> Option<String> {
+        for (i, a) in self.areas.iter().enumerate() {
+            for b in self.areas[i + 1..].iter() {
+                if a.vpn_range.overlaps(&b.vpn_range) {
+                    return Some(format!(
+                        "areas [{:?}, {:?}) and [{:?}, {:?}) overlap",
+                        a.vpn_range.get_start(),
+                        a.vpn_range.get_end(),
+                        b.vpn_range.get_start(),
+                        b.vpn_range.get_end()
+                    ));
+                }
+            }
+        }
+        for area in self.areas.iter().filter(|area| area.map_type == MapType::Framed) {
+            let (start, end) = (area.vpn_range.get_start(), area.vpn_range.get_end());
+            if let Some(vpn) = area.data_frames.keys().find(|vpn| **vpn < start || **vpn >= end) {
+                return Some(format!("frame of {:?} outside its area [{:?}, {:?})", vpn, start, end));
+            }
+            for vpn in area.vpn_range {
+                let pte = self.page_table.translate(vpn).filter(|pte| pte.is_valid());
+                match (pte, area.data_frames.get(&vpn)) {
+                    (Some(pte), Some(frame)) if pte.ppn() != frame.ppn => {
+                        return Some(format!(
+                            "{:?} maps {:?} but its frame is {:?}",
+                            vpn,
+                            pte.ppn(),
+                            frame.ppn
+                        ));
+                    }
+                    (Some(_), None) => return Some(format!("{:?} is mapped without a frame", vpn)),
+                    (None, Some(_)) => return Some(format!("{:?} has a frame but is not mapped", vpn)),
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+
+    /// Free the frames backing `[start_vpn, end_vpn)` but keep the areas, the
+    /// next access faults and gets a zeroed page from `handle_page_fault`.
+    /// Returns false without freeing anything if some page is outside every
+    /// user accessible anonymous `Framed` area.
+    pub fn drop_pages(&mut self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        // 只允许用户可访问的逻辑段，Trap 上下文所在的页帧被内核直接引用，不能释放。
+        // ELF 段的内容重新缺页时会变成全零，共享的页帧还被别的地址空间使用，同样不能释放
+        let covered = VPNRange::new(start_vpn, end_vpn).into_iter().all(|vpn| {
+            self.find_area_containing(vpn).map_or(false, |area| {
+                area.map_type == MapType::Framed
+                    && area.map_perm.contains(MapPermission::U)
+                    && area.elf_offset.is_none()
+                    && !area.shared
+            })
+        });
+        if !covered {
+            return false;
+        }
+        for area in self.areas.iter_mut() {
+            for vpn in VPNRange::new(start_vpn, end_vpn) {
+                if area.contains(vpn) {
+                    // 已经释放过的页面 unmap_one 返回 false ，忽略即可
+                    area.unmap_one(&mut self.page_table, vpn);
                 }
-                return 0;
             }
         }
-        -1
+        true
+    }
+
+    /// Map a zeroed frame at `va` if it lies in a `Framed` area allowing
+    /// `access` from U mode but has no frame yet. In a space from
+    /// `from_elf_lazy` the page is filled from the ELF image. Returns false if
+    /// the fault is a real error the task must be killed for.
+    pub fn handle_page_fault(&mut self, va: VirtAddr, access: MapPermission) -> bool {
+        let vpn = va.floor();
+        if self.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+            // 页面已经映射，除了写时复制的页面，都说明是权限不对
+            return access.contains(MapPermission::W) && self.handle_cow_fault(vpn);
+        }
+        let area = match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area) => area,
+            None => return false,
+        };
+        if area.map_type != MapType::Framed || !area.map_perm.contains(access | MapPermission::U) {
+            return false;
+        }
+        match area.map_one(&mut self.page_table, vpn) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(err) => {
+                debug!("[kernel] page fault at {:?}: {}", va, err);
+                return false;
+            }
+        }
+        if area.skip_zeroing {
+            // 该逻辑段的页帧分配时没有清零，这里补上
+            let ppn = self.page_table.translate(vpn).unwrap().ppn();
+            for byte in ppn.get_bytes_array() {
+                *byte = 0;
+            }
+        }
+        if let Some(image) = self.elf_image {
+            let ppn = self.page_table.translate(vpn).unwrap().ppn();
+            area.load_elf_page(image, vpn, ppn);
+        }
+        true
+    }
+
+    /// Make the mapped page `vpn` copy-on-write: its PTE loses W until the
+    /// next write to it. Returns false unless `vpn` has a frame in a
+    /// writable `Framed` area.
+    #[allow(unused)]
+    pub fn mark_cow(&mut self, vpn: VirtPageNum) -> bool {
+        let area = match self.areas.iter_mut().find(|area| area.contains(vpn)) {
+            Some(area) => area,
+            None => return false,
+        };
+        if area.map_type != MapType::Framed || !area.map_perm.contains(MapPermission::W) {
+            return false;
+        }
+        let ppn = match area.data_frames.get(&vpn) {
+            Some(frame) => frame.ppn,
+            None => return false,
+        };
+        area.cow_pages.insert(vpn);
+        self.page_table
+            .map_force(vpn, ppn, area.pte_flags() - PTEFlags::W);
+        if self.page_table.is_active() {
+            flush_tlb_vpn(vpn);
+        }
+        true
+    }
+
+    /// Service a write fault on the copy-on-write page `vpn`: map it writable
+    /// to a private copy of its frame and drop this space's reference to the
+    /// old one. The last owner keeps the frame and only gets W back. Returns
+    /// false if `vpn` is not a copy-on-write page or there is no free frame.
+    pub fn handle_cow_fault(&mut self, vpn: VirtPageNum) -> bool {
+        let area = match self.areas.iter_mut().find(|area| area.cow_pages.contains(&vpn)) {
+            Some(area) => area,
+            None => return false,
+        };
+        let old = area.data_frames.get(&vpn).unwrap();
+        // 引用计数由页帧分配器维护，其他地址空间、别名和共享代码段的缓存都算作所有者
+        let ppn = if frame_ref_count(old.ppn) == 1 {
+            old.ppn
+        } else {
+            let copy = match frame_alloc_uninit() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            copy.ppn
+                .get_bytes_array()
+                .copy_from_slice(old.ppn.get_bytes_array());
+            let ppn = copy.ppn;
+            // 替换掉旧的 FrameTracker ，旧页帧的引用计数随之减一
+            area.data_frames.insert(vpn, copy);
+            ppn
+        };
+        self.page_table
+            .map_force(vpn, ppn, area.pte_flags());
+        if self.page_table.is_active() {
+            flush_tlb_vpn(vpn);
+        }
+        area.cow_pages.remove(&vpn);
+        true
+    }
+
+    /// Classify a page fault at `va`: a fault inside some area means the
+    /// access broke the area's permission, otherwise nothing is mapped there.
+    pub fn classify_fault(&self, va: VirtAddr) -> FaultKind {
+        match self.find_area_containing(va.floor()) {
+            Some(_) => FaultKind::PermissionViolation,
+            None => FaultKind::Unmapped,
+        }
+    }
+}
+
+impl Drop for MemorySet {
+    /// Give back this space's frames first, then evict the text of apps no
+    /// space maps any more from `SHARED_TEXT`.
+    fn drop(&mut self) {
+        if self.areas.iter().any(|area| area.shared) {
+            self.areas.clear();
+            prune_shared_text();
+        }
+    }
+}
+
+/// Drop the `SHARED_TEXT` entries whose frame only the cache still holds,
+/// i.e. every space of that app is gone.
+fn prune_shared_text() {
+    SHARED_TEXT
+        .lock()
+        .retain(|_, frame| frame_ref_count(frame.ppn) > 1);
+}
+
+/// why an area could not be added to a [`MemorySet`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MapError {
+    /// overlaps the existing area `[start, end)`
+    Overlap(VirtPageNum, VirtPageNum),
+    /// no frame left for a page table node on the way to this page
+    NoFrameForNode(VirtPageNum),
+    /// no frame left to hold the data of this page
+    NoFrameForData(VirtPageNum),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::Overlap(start, end) => {
+                write!(f, "overlaps existing area [{:?}, {:?})", start, end)
+            }
+            MapError::NoFrameForNode(vpn) => {
+                write!(f, "out of frames creating page table node for {:?}", vpn)
+            }
+            MapError::NoFrameForData(vpn) => write!(f, "out of frames for data of {:?}", vpn),
+        }
+    }
+}
+
+/// largest `p_align` of an ELF segment that is honored, 2MiB like a megapage
+const MAX_SEGMENT_ALIGN: usize = 0x20_0000;
+
+/// Bounds of the area for a segment at `vaddr` of `mem_size` bytes. An
+/// alignment above `PAGE_SIZE` aligns the area start down and end up to it,
+/// alignments larger than `MAX_SEGMENT_ALIGN` or not a power of two fall back
+/// to pages.
+fn segment_bounds(vaddr: usize, mem_size: usize, align: usize) -> (VirtAddr, VirtAddr) {
+    let end = vaddr + mem_size;
+    if align > PAGE_SIZE && align <= MAX_SEGMENT_ALIGN && align.is_power_of_two() {
+        let start = vaddr & !(align - 1);
+        let end = (end + align - 1) & !(align - 1);
+        (start.into(), end.into())
+    } else {
+        (vaddr.into(), end.into())
+    }
+}
+
+/// copy `data` to `va` in the address space of `token`, which does not have
+/// to be the active one. Every page must already be mapped.
+fn copy_to_space(token: usize, va: usize, data: &[u8]) {
+    let mut start = 0;
+    for buffer in translated_byte_buffer(token, va as *const u8, data.len()) {
+        buffer.copy_from_slice(&data[start..start + buffer.len()]);
+        start += buffer.len();
+    }
+}
+
+/// why [`MemorySet::from_elf`] rejected an app image
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ElfError {
+    /// `xmas_elf` could not parse the image
+    Invalid(&'static str),
+    /// the image does not start with the ELF magic number
+    BadMagic,
+    /// a segment's data lies beyond the end of the image
+    Truncated,
+    /// two loadable segments share a page
+    SharedPage,
+    /// the arguments do not fit on the user stack
+    ArgsTooLong,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::Invalid(reason) => write!(f, "invalid elf: {}", reason),
+            ElfError::BadMagic => write!(f, "invalid elf: bad magic number"),
+            ElfError::Truncated => write!(f, "invalid elf: segment data out of the file"),
+            ElfError::SharedPage => write!(f, "invalid elf: segments share a page"),
+            ElfError::ArgsTooLong => write!(f, "arguments too long for the user stack"),
+        }
+    }
+}
+
+/// why a page fault happened, used for diagnostics in the trap handler
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FaultKind {
+    Unmapped,
+    PermissionViolation,
+    GuardPage,
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultKind::Unmapped => f.write_str("unmapped"),
+            FaultKind::PermissionViolation => f.write_str("permission violation"),
+            FaultKind::GuardPage => f.write_str("guard page"),
+        }
     }
 }
 
@@ -314,11 +1347,53 @@ pub struct MapArea {
     // data_frames 是一个保存了该逻辑段内的每个虚拟页面 和它
     // 被映射到的物理页帧 FrameTracker 的一个键值对容器 BTreeMap 中，
     // 这些物理页帧被用来存放实际内存数据而不是 作为多级页表中的中间节点
+    // 同一个物理页帧可以通过 FrameTracker::share 被 map_alias 映射到多个虚拟页面，
+    // 由页帧分配器计数，最后一个映射被删除时才会回收
     pub data_frames: BTreeMap<VirtPageNum, FrameTracker>,
     pub map_type: MapType,
     // MapPermission 表示控制该逻辑段的访问方式，它是页表项标志位
     // PTEFlags 的一个子集，仅保留 U/R/W/X 四个标志位
     pub map_perm: MapPermission,
+    // 为 true 时分配的物理页帧不预先清零，由 copy_data 负责写满每一页；
+    // BSS、用户栈等没有初始数据的逻辑段必须保持 false
+    pub skip_zeroing: bool,
+    // 为 true 时 clone_full 出来的地址空间与原空间共享物理页帧，否则各自拷贝一份
+    pub shared: bool,
+    // 写时复制的页面，页表项去掉了 W ，第一次写入时由 handle_cow_fault 换成私有页帧
+    pub cow_pages: BTreeSet<VirtPageNum>,
+    // 为 true 时 map_one 映射的页面一开始就带有 A/D 位，否则由硬件在访问时设置
+    pub preset_ad: bool,
+    // 由 ELF 段生成的逻辑段：段数据在文件中的偏移、文件中的长度以及数据开始的虚拟地址，
+    // [elf_vaddr + file_len, 逻辑段结尾) 是 bss
+    pub elf_offset: Option<usize>,
+    pub file_len: usize,
+    pub elf_vaddr: usize,
+}
+
+/// One line like `VPN:0x10000..VPN:0x10002 Framed rw-u frames: 2`, the
+/// frames themselves are not listed.
+impl fmt::Debug for MapArea {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let letter = |perm: MapPermission, c: char| {
+            if self.map_perm.contains(perm) {
+                c
+            } else {
+                '-'
+            }
+        };
+        write!(
+            f,
+            "{:?}..{:?} {:?} {}{}{}{} frames: {}",
+            self.vpn_range.get_start(),
+            self.vpn_range.get_end(),
+            self.map_type,
+            letter(MapPermission::R, 'r'),
+            letter(MapPermission::W, 'w'),
+            letter(MapPermission::X, 'x'),
+            letter(MapPermission::U, 'u'),
+            self.data_frames.len()
+        )
+    }
 }
 
 impl MapArea {
@@ -339,7 +1414,49 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            skip_zeroing: false,
+            shared: false,
+            cow_pages: BTreeSet::new(),
+            preset_ad: false,
+            elf_offset: None,
+            file_len: 0,
+            elf_vaddr: 0,
+        }
+    }
+    /// Copy the bytes of page `vpn` that come from the ELF file out of `image`
+    /// into its zeroed frame `ppn`, leaving bss and padding zero. Does nothing
+    /// for an area not made from an ELF segment.
+    fn load_elf_page(&self, image: &[u8], vpn: VirtPageNum, ppn: PhysPageNum) {
+        let elf_offset = match self.elf_offset {
+            Some(elf_offset) => elf_offset,
+            None => return,
+        };
+        let page_va = VirtAddr::from(vpn).0;
+        let start = page_va.max(self.elf_vaddr);
+        let end = (page_va + PAGE_SIZE).min(self.elf_vaddr + self.file_len);
+        if start >= end {
+            return;
         }
+        let src = elf_offset + (start - self.elf_vaddr);
+        ppn.get_bytes_array()[start - page_va..end - page_va]
+            .copy_from_slice(&image[src..src + (end - start)]);
+    }
+    /// flags of a newly mapped leaf PTE, without V
+    fn pte_flags(&self) -> PTEFlags {
+        let flags = PTEFlags::from(self.map_perm);
+        if self.preset_ad {
+            flags | PTEFlags::A | PTEFlags::D
+        } else {
+            flags
+        }
+    }
+    /// whether `vpn` lies in `vpn_range`, the end is exclusive
+    pub fn contains(&self, vpn: VirtPageNum) -> bool {
+        self.vpn_range.get_start() <= vpn && vpn < self.vpn_range.get_end()
+    }
+    /// whether the page containing `va` lies in `vpn_range`
+    pub fn contains_va(&self, va: VirtAddr) -> bool {
+        self.contains(va.floor())
     }
     /**
      * 单个虚拟页面进行映射逻辑段被映射到物理内存的方式
@@ -347,8 +1464,11 @@ impl MapArea {
      * 页表项的标志位来源于当前逻辑段的类型为 MapPermission 的统一配置，
      * 只需将其转换为 PTEFlags ；而页表项的 物理页号则取决于当前逻辑段映射到物理内存的方式
      */
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool{
+    /// `Ok(false)` if `vpn` is already mapped. Nothing is left mapped or
+    /// allocated when it fails.
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> Result<bool, MapError> {
         let mut ppn: PhysPageNum = PhysPageNum(0);
+        let mut data_frame = None;
         match self.map_type {
             MapType::Identical => {
                 ppn = PhysPageNum(vpn.0);
@@ -363,14 +1483,31 @@ impl MapArea {
                 //     },
                 //     _=>{},
                 // }
-                let frame = frame_alloc().unwrap();
+                let frame = if self.skip_zeroing {
+                    frame_alloc_uninit()
+                } else {
+                    frame_alloc()
+                }
+                .ok_or(MapError::NoFrameForData(vpn))?;
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                data_frame = Some(frame);
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        // 调用多级页表 PageTable 的 map 接口来插入键值对
-        page_table.map(vpn, ppn, pte_flags)
+        let pte_flags = self.pte_flags();
+        // 调用多级页表 PageTable 的 map 接口来插入键值对，失败时 data_frame 随之释放
+        let mapped = page_table
+            .map(vpn, ppn, pte_flags)
+            .ok_or(MapError::NoFrameForNode(vpn))?;
+        if !mapped {
+            return Ok(false);
+        }
+        if let Some(frame) = data_frame {
+            self.data_frames.insert(vpn, frame);
+        }
+        if page_table.is_active() {
+            flush_tlb_vpn(vpn);
+        }
+        Ok(true)
     }
 
     #[allow(unused)]
@@ -379,36 +1516,101 @@ impl MapArea {
         match self.map_type {
             MapType::Framed => {
                 self.data_frames.remove(&vpn);
+                self.cow_pages.remove(&vpn);
             }
             _ => {}
         }
-        page_table.unmap(vpn)
+        let unmapped = page_table.unmap(vpn);
+        // 不刷新的话，快表中可能还留着已经被回收的物理页帧的映射
+        if unmapped && page_table.is_active() {
+            flush_tlb_vpn(vpn);
+        }
+        unmapped
     }
     /**
      * 可以将当前逻辑段到物理内存的映射从传入的该逻辑段所属的地址空间的 多级页表中加入
      */
-    pub fn map(&mut self, page_table: &mut PageTable) -> bool{
+    /// Stops at the first page already mapped and returns `Ok(false)`. When
+    /// frames run out the pages mapped so far are unmapped again.
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<bool, MapError> {
         for vpn in self.vpn_range {
             // 每个虚拟页面为单位依次在多级页表中进行 键值对的插入
-            if !self.map_one(page_table, vpn){
-                return false;
+            match self.map_one(page_table, vpn) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(err) => {
+                    for mapped in VPNRange::new(self.vpn_range.get_start(), vpn).into_iter().rev() {
+                        self.unmap_one(page_table, mapped);
+                    }
+                    return Err(err);
+                }
             }
         }
-        true
+        Ok(true)
     }
 
     /**
      * 可以将当前逻辑段到物理内存的映射从传入的该逻辑段所属的地址空间的 多级页表中删除
      */
+    /// Split at `vpn`: `self` keeps `[start, vpn)` and the returned area gets
+    /// `[vpn, end)` together with the frames mapped there. `vpn` must lie
+    /// strictly inside the range.
+    pub fn split_off(&mut self, vpn: VirtPageNum) -> MapArea {
+        assert!(
+            self.vpn_range.get_start() < vpn && vpn < self.vpn_range.get_end(),
+            "split {:?} outside of {:?}",
+            vpn,
+            self
+        );
+        let tail = MapArea {
+            vpn_range: VPNRange::new(vpn, self.vpn_range.get_end()),
+            data_frames: self.data_frames.split_off(&vpn),
+            map_type: self.map_type,
+            map_perm: self.map_perm,
+            skip_zeroing: self.skip_zeroing,
+            shared: self.shared,
+            cow_pages: self.cow_pages.split_off(&vpn),
+            preset_ad: self.preset_ad,
+            elf_offset: self.elf_offset,
+            file_len: self.file_len,
+            elf_vaddr: self.elf_vaddr,
+        };
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), vpn);
+        tail
+    }
     #[allow(unused)]
+    /// Unmap from the highest page down, so `PageTable::unmap` can tell a
+    /// node became empty without scanning all of it and free it right away.
     pub fn unmap(&mut self, page_table: &mut PageTable) -> bool {
-        for vpn in self.vpn_range {
+        let mut all_mapped = true;
+        for vpn in self.vpn_range.into_iter().rev() {
             // 每个虚拟页面为单位依次在多级页表中进行 键值对的删除
+            // 被 drop_pages 释放掉的页面本来就没有映射，跳过它继续删除后面的页面
             if !self.unmap_one(page_table, vpn){
-                return false;
+                all_mapped = false;
             }
         }
-        true
+        all_mapped
+    }
+
+    /// Iterate over every resident `(vpn, ppn)` mapping of this area.
+    /// 对 Framed 逻辑段读取 data_frames ，对 Identical 逻辑段 ppn == vpn ，
+    /// 只返回在 page_table 中已经映射的页面
+    #[allow(unused)]
+    pub fn iter_mappings<'a>(
+        &'a self,
+        page_table: &'a PageTable,
+    ) -> impl Iterator<Item = (VirtPageNum, PhysPageNum)> + 'a {
+        let is_identical = self.map_type == MapType::Identical;
+        let framed = self.data_frames.iter().map(|(vpn, frame)| (*vpn, frame.ppn));
+        let identical = self
+            .vpn_range
+            .into_iter()
+            .filter(move |vpn| {
+                is_identical && page_table.translate(*vpn).map_or(false, |pte| pte.is_valid())
+            })
+            .map(|vpn| (vpn, PhysPageNum(vpn.0)));
+        framed.chain(identical)
     }
 
     /**
@@ -419,23 +1621,45 @@ impl MapArea {
      * 到逻辑段的开头，然后逐页拷贝到实际的物理页帧。
      */
     /// data: start-aligned but maybe with shorter length
-    /// assume that all frames were cleared before
     pub fn copy_data(&mut self, page_table: &mut PageTable, data: &[u8]) {
+        self.copy_data_at(page_table, data, 0);
+    }
+    /// Copy `data` to `offset` bytes into the area.
+    ///
+    /// The rest of the page holding the end of `data` is always cleared here,
+    /// so the bss of an ELF segment reads zero even where it shares a page
+    /// with the file data. Frames are normally cleared when allocated, so only
+    /// the pages holding `data` are visited. With `skip_zeroing` the frames
+    /// hold stale data and every page of the area is visited instead: the
+    /// parts before `offset` and past `data` are cleared here, so each byte is
+    /// written exactly once rather than cleared and then overwritten.
+    /// `skip_zeroing_cost_test` measures what that saves when loading an app.
+    pub fn copy_data_at(&mut self, page_table: &mut PageTable, data: &[u8], offset: usize) {
         assert_eq!(self.map_type, MapType::Framed);
+        // start 为当前页面在逻辑段内的偏移，数据占据逻辑段内的 [offset, end)
         let mut start: usize = 0;
         let mut current_vpn = self.vpn_range.get_start();
-        let len = data.len();
+        let end = offset + data.len();
         loop {
             // 循环会遍历每一个需要拷贝数据的虚拟页面，
-            let src = &data[start..len.min(start + PAGE_SIZE)];
-            let dst = &mut page_table
+            // [from, to) 是这一页中属于数据的部分，可能为空
+            let from = offset.max(start).min(start + PAGE_SIZE);
+            let to = end.max(from).min(start + PAGE_SIZE);
+            let dst = page_table
                 .translate(current_vpn)
                 .unwrap()
                 .ppn()
-                .get_bytes_array()[..src.len()];
-            dst.copy_from_slice(src);
+                .get_bytes_array();
+            if from < to {
+                dst[from - start..to - start].copy_from_slice(&data[from - offset..to - offset]);
+            }
+            // 数据没有覆盖到的部分清零：最后一个数据页中属于 bss 的部分，
+            // offset 之前的部分，以及 skip_zeroing 时没有预先清零的其余页面
+            let (from, to) = (from - start, to - start);
+            dst[..from].fill(0);
+            dst[to..].fill(0);
             start += PAGE_SIZE;
-            if start >= len {
+            if start >= end && !self.skip_zeroing {
                 break;
             }
             // 在数据拷贝完成后调用 step 方法，该 方法来自于 os/src/mm/address.rs
@@ -443,6 +1667,9 @@ impl MapArea {
             // 每个页面的数据拷贝需要确定源 src 和目标 dst 两个切片并直接使用
             // copy_from_slice 完成复制
             current_vpn.step();
+            if current_vpn == self.vpn_range.get_end() {
+                break;
+            }
         }
     }
 }
@@ -476,6 +1703,73 @@ bitflags! {
     }
 }
 
+impl MapPermission {
+    /// Permission with the given bits set, usable in constants.
+    pub const fn from_rwx(r: bool, w: bool, x: bool, user: bool) -> Self {
+        let mut bits = 0;
+        if r {
+            bits |= Self::R.bits();
+        }
+        if w {
+            bits |= Self::W.bits();
+        }
+        if x {
+            bits |= Self::X.bits();
+        }
+        if user {
+            bits |= Self::U.bits();
+        }
+        Self::from_bits_truncate(bits)
+    }
+    /// Turn the `port` argument of `sys_mmap` into a permission.
+    /// 只允许低三位 R/W/X 且至少有一位，结果总是带有 U 位，保证用户态可以访问
+    pub fn from_port(port: usize) -> Option<Self> {
+        if port & !0x7 != 0 || port & 0x7 == 0 {
+            return None;
+        }
+        Some(Self::from_rwx(port & 1 != 0, port & 2 != 0, port & 4 != 0, true))
+    }
+}
+
+impl From<MapPermission> for PTEFlags {
+    fn from(perm: MapPermission) -> Self {
+        let mut flags = PTEFlags::empty();
+        if perm.contains(MapPermission::R) {
+            flags |= PTEFlags::R;
+        }
+        if perm.contains(MapPermission::W) {
+            flags |= PTEFlags::W;
+        }
+        if perm.contains(MapPermission::X) {
+            flags |= PTEFlags::X;
+        }
+        if perm.contains(MapPermission::U) {
+            flags |= PTEFlags::U;
+        }
+        flags
+    }
+}
+
+/// V/G/A/D are dropped, only `R W X U` survive
+impl From<PTEFlags> for MapPermission {
+    fn from(flags: PTEFlags) -> Self {
+        let mut perm = MapPermission::empty();
+        if flags.contains(PTEFlags::R) {
+            perm |= MapPermission::R;
+        }
+        if flags.contains(PTEFlags::W) {
+            perm |= MapPermission::W;
+        }
+        if flags.contains(PTEFlags::X) {
+            perm |= MapPermission::X;
+        }
+        if flags.contains(PTEFlags::U) {
+            perm |= MapPermission::U;
+        }
+        perm
+    }
+}
+
 #[allow(unused)]
 pub fn remap_test() {
     let mut kernel_space = KERNEL_SPACE.lock();
@@ -499,3 +1793,1440 @@ pub fn remap_test() {
         .executable());
     info!("remap_test passed!");
 }
+
+#[allow(unused)]
+/// a simple test for page fault classification
+pub fn fault_classify_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_1000),
+        MapPermission::R | MapPermission::U,
+    ).unwrap();
+    assert_eq!(
+        memory_set.classify_fault(VirtAddr::from(0x1000_0010)),
+        FaultKind::PermissionViolation
+    );
+    assert_eq!(
+        memory_set.classify_fault(VirtAddr::from(0x1000_1000)),
+        FaultKind::Unmapped
+    );
+    info!("fault_classify_test passed!");
+}
+
+#[allow(unused)]
+/// check every `R W X U` combination survives MapPermission -> PTEFlags -> MapPermission
+pub fn map_permission_test() {
+    for bits in 0u8..16 {
+        let perm = MapPermission::from_bits(bits << 1).unwrap();
+        let flags = PTEFlags::from(perm);
+        assert!(!flags.contains(PTEFlags::V));
+        assert_eq!(flags.contains(PTEFlags::R), perm.contains(MapPermission::R));
+        assert_eq!(flags.contains(PTEFlags::W), perm.contains(MapPermission::W));
+        assert_eq!(flags.contains(PTEFlags::X), perm.contains(MapPermission::X));
+        assert_eq!(flags.contains(PTEFlags::U), perm.contains(MapPermission::U));
+        assert_eq!(MapPermission::from(flags | PTEFlags::V | PTEFlags::A), perm);
+    }
+    info!("map_permission_test passed!");
+}
+
+#[allow(unused)]
+/// check `MapPermission::from_port` for every port value and `from_rwx`
+pub fn map_permission_from_port_test() {
+    for port in 0..16 {
+        let perm = MapPermission::from_port(port);
+        if port == 0 || port >= 8 {
+            assert!(perm.is_none());
+            continue;
+        }
+        let perm = perm.unwrap();
+        assert!(perm.contains(MapPermission::U));
+        assert_eq!(perm.contains(MapPermission::R), port & 1 != 0);
+        assert_eq!(perm.contains(MapPermission::W), port & 2 != 0);
+        assert_eq!(perm.contains(MapPermission::X), port & 4 != 0);
+    }
+    const RW: MapPermission = MapPermission::from_rwx(true, true, false, false);
+    assert_eq!(RW, MapPermission::R | MapPermission::W);
+    assert_eq!(
+        MapPermission::from_rwx(false, false, true, true),
+        MapPermission::X | MapPermission::U
+    );
+    assert!(MapPermission::from_rwx(false, false, false, false).is_empty());
+    info!("map_permission_from_port_test passed!");
+}
+
+#[allow(unused)]
+/// smoke test for `MemorySet::dump`
+pub fn memory_set_dump_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x2000_0000),
+        VirtAddr::from(0x2000_2000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_1000),
+        MapPermission::R | MapPermission::U,
+    ).unwrap();
+    assert_eq!(memory_set.dump(), 2);
+    info!("memory_set_dump_test passed!");
+}
+
+#[allow(unused)]
+/// check `MapArea::iter_mappings` agrees with the page table
+pub fn iter_mappings_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_3000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    let area = &memory_set.areas[0];
+    let mut count = 0;
+    for (vpn, ppn) in area.iter_mappings(&memory_set.page_table) {
+        assert_eq!(memory_set.page_table.translate(vpn).unwrap().ppn(), ppn);
+        count += 1;
+    }
+    assert_eq!(count, 3);
+    info!("iter_mappings_test passed!");
+}
+
+#[allow(unused)]
+/// check frames of areas with initial data are fully written without being
+/// cleared first, and areas without data are still cleared
+pub fn skip_zeroing_test() {
+    let mut memory_set = MemorySet::new_bare();
+    // 先弄脏一批物理页帧再回收，让后面的分配拿到的都是脏页
+    let dirty: Vec<FrameTracker> = (0..8).map(|_| frame_alloc().unwrap()).collect();
+    for frame in dirty.iter() {
+        for byte in frame.ppn.get_bytes_array() {
+            *byte = 0xa5;
+        }
+    }
+    drop(dirty);
+    let data = [0x5au8; PAGE_SIZE + PAGE_SIZE / 2];
+    memory_set.push(
+        MapArea::new(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_3000),
+            MapType::Framed,
+            MapPermission::R | MapPermission::U,
+        ),
+        Some(&data),
+    );
+    assert!(memory_set.areas[0].skip_zeroing);
+    for (vpn, ppn) in memory_set.areas[0].iter_mappings(&memory_set.page_table) {
+        let offset = (vpn.0 - 0x1000_0) * PAGE_SIZE;
+        for (i, byte) in ppn.get_bytes_array().iter().enumerate() {
+            let expected = if offset + i < data.len() { 0x5a } else { 0 };
+            assert_eq!(*byte, expected);
+        }
+    }
+    // BSS 之类没有初始数据的逻辑段仍然是全零的
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x2000_0000),
+        VirtAddr::from(0x2000_2000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    assert!(!memory_set.areas[1].skip_zeroing);
+    for (_, ppn) in memory_set.areas[1].iter_mappings(&memory_set.page_table) {
+        assert!(ppn.get_bytes_array().iter().all(|byte| *byte == 0));
+    }
+    info!("skip_zeroing_test passed!");
+}
+
+#[allow(unused)]
+/// Load the image of app 0 into an area with and without `skip_zeroing`,
+/// check both give the same bytes and log the time each took.
+pub fn skip_zeroing_cost_test() {
+    use crate::loader::get_app_data;
+    use crate::timer::get_time;
+    let data = get_app_data(0);
+    let (start, end) = (VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_0000 + data.len()));
+    let perm = MapPermission::R | MapPermission::U;
+    const ROUNDS: usize = 8;
+    let (mut skipped, mut zeroed) = (0, 0);
+    for _ in 0..ROUNDS {
+        // push 不清零，copy_data_at 把每个字节写一次
+        let mut fast = MemorySet::new_bare();
+        let begin = get_time();
+        fast.push(MapArea::new(start, end, MapType::Framed, perm), Some(data));
+        skipped += get_time() - begin;
+        // 分配时先清零，再被数据覆盖
+        let mut slow = MemorySet::new_bare();
+        let begin = get_time();
+        let mut area = MapArea::new(start, end, MapType::Framed, perm);
+        area.map(&mut slow.page_table).unwrap();
+        area.copy_data_at(&mut slow.page_table, data, 0);
+        zeroed += get_time() - begin;
+        slow.areas.push(area);
+        assert!(fast.equal_contents(&slow));
+    }
+    info!(
+        "skip_zeroing_cost_test: {} bytes x {}, {} ticks with skip_zeroing, {} ticks zeroing first",
+        data.len(),
+        ROUNDS,
+        skipped,
+        zeroed
+    );
+    info!("skip_zeroing_cost_test passed!");
+}
+
+#[allow(unused)]
+/// check `MemorySet::protect_range` clears the W bit in place
+pub fn protect_range_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_2000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    let vpn = VirtPageNum(0x1000_0);
+    let ppn = memory_set.translate(vpn).unwrap().ppn();
+    memory_set.protect_range(vpn, VirtPageNum(0x1000_2), PTEFlags::empty(), PTEFlags::W);
+    for vpn in VPNRange::new(vpn, VirtPageNum(0x1000_2)) {
+        let pte = memory_set.translate(vpn).unwrap();
+        assert!(pte.is_valid() && pte.readable() && !pte.writable());
+    }
+    assert_eq!(memory_set.translate(vpn).unwrap().ppn(), ppn);
+    assert_eq!(memory_set.areas[0].map_perm, MapPermission::R | MapPermission::U);
+    // 只覆盖逻辑段一部分时先拆开，范围外的页面与逻辑段的权限保持一致
+    memory_set.protect_range(vpn, VirtPageNum(0x1000_1), PTEFlags::W, PTEFlags::empty());
+    memory_set.check_invariants();
+    assert_eq!(memory_set.areas.len(), 2);
+    for area in memory_set.areas.iter() {
+        let pte = memory_set.translate(area.vpn_range.get_start()).unwrap();
+        assert_eq!(pte.writable(), area.map_perm.contains(MapPermission::W));
+        assert_eq!(pte.writable(), area.vpn_range.get_start() == vpn);
+    }
+    info!("protect_range_test passed!");
+}
+
+#[allow(unused)]
+/// check remapping a page of the active kernel space is seen at once,
+/// i.e. `unmap_one` leaves no stale TLB entry behind
+pub fn flush_tlb_test() {
+    let mut kernel_space = KERNEL_SPACE.lock();
+    // 物理内存之上一块内核没有用到的虚拟地址
+    let vpn = VirtPageNum(0x10_0000);
+    let va: VirtAddr = vpn.into();
+    let mut area = MapArea::new(
+        va,
+        VirtAddr::from(va.0 + PAGE_SIZE),
+        MapType::Framed,
+        MapPermission::R | MapPermission::W,
+    );
+    assert!(kernel_space.page_table.is_active());
+    assert_eq!(area.map_one(&mut kernel_space.page_table, vpn), Ok(true));
+    let ptr = va.0 as *mut u8;
+    // 访问一次，让这一页的映射进入快表
+    unsafe { ptr.write_volatile(1) };
+    let old_ppn = kernel_space.translate(vpn).unwrap().ppn();
+    assert!(area.unmap_one(&mut kernel_space.page_table, vpn));
+    // 占住刚回收的物理页帧并写入标记，保证重新映射到另一个页帧。
+    // 分配器不一定先返回刚回收的页帧（例如 ordered_frames），找不到时只检查新映射
+    let mut frames = Vec::new();
+    let mut holder = None;
+    while frames.len() < 64 {
+        let frame = match frame_alloc() {
+            Some(frame) => frame,
+            None => break,
+        };
+        if frame.ppn == old_ppn {
+            holder = Some(frame);
+            break;
+        }
+        frames.push(frame);
+    }
+    if let Some(holder) = holder.as_ref() {
+        holder.ppn.get_bytes_array()[0] = 0xaa;
+    }
+    assert_eq!(area.map_one(&mut kernel_space.page_table, vpn), Ok(true));
+    // 命中旧的快表项会读到 0xaa ，写入也会落到旧页帧上
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    unsafe { ptr.write_volatile(0x33) };
+    let new_ppn = kernel_space.translate(vpn).unwrap().ppn();
+    assert_eq!(new_ppn.get_bytes_array()[0], 0x33);
+    if let Some(holder) = holder {
+        assert_ne!(new_ppn, old_ppn);
+        assert_eq!(holder.ppn.get_bytes_array()[0], 0xaa);
+    } else {
+        info!("flush_tlb_test: freed frame not reused, only checked the new mapping");
+    }
+    area.unmap_one(&mut kernel_space.page_table, vpn);
+    info!("flush_tlb_test passed!");
+}
+
+#[allow(unused)]
+/// a simple test for `MemorySet::is_accessible`
+pub fn is_accessible_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_1000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    let va = VirtAddr::from(0x1000_0010);
+    assert!(memory_set.is_accessible(va, MapPermission::R));
+    assert!(memory_set.is_accessible(va, MapPermission::W));
+    assert!(memory_set.is_accessible(va, MapPermission::R | MapPermission::W));
+    assert!(!memory_set.is_accessible(va, MapPermission::X));
+    assert!(!memory_set.is_accessible(VirtAddr::from(0x1000_1000), MapPermission::R));
+    info!("is_accessible_test passed!");
+}
+
+#[allow(unused)]
+/// check `MemorySet::remap_contiguous` keeps the data at the same address
+pub fn remap_contiguous_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let start_vpn = VirtPageNum(0x1000_0);
+    memory_set.insert_framed_area(
+        start_vpn.into(),
+        VirtPageNum(start_vpn.0 + 3).into(),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    for (vpn, ppn) in memory_set.areas[0].iter_mappings(&memory_set.page_table) {
+        for (i, byte) in ppn.get_bytes_array().iter_mut().enumerate() {
+            *byte = (vpn.0 + i) as u8;
+        }
+    }
+    assert!(memory_set.remap_contiguous(start_vpn));
+    let mut ppns = Vec::new();
+    for vpn in memory_set.areas[0].vpn_range {
+        let pte = memory_set.translate(vpn).unwrap();
+        assert!(pte.readable() && pte.writable());
+        for (i, byte) in pte.ppn().get_bytes_array().iter().enumerate() {
+            assert_eq!(*byte, (vpn.0 + i) as u8);
+        }
+        ppns.push(pte.ppn().0);
+    }
+    assert!(ppns.windows(2).all(|w| w[1] == w[0] + 1));
+    assert!(!memory_set.remap_contiguous(VirtPageNum(start_vpn.0 + 1)));
+    // 有页面被别名引用，或者逻辑段是共享的，都不能换页帧
+    let alias_va = VirtAddr::from(0x2000_0000);
+    assert!(memory_set.map_alias(start_vpn, alias_va, MapPermission::R | MapPermission::U));
+    let ppn = memory_set.translate(start_vpn).unwrap().ppn();
+    assert!(!memory_set.remap_contiguous(start_vpn));
+    assert_eq!(memory_set.translate(start_vpn).unwrap().ppn(), ppn);
+    assert_eq!(memory_set.translate(alias_va.floor()).unwrap().ppn(), ppn);
+    let shared = VirtPageNum(0x3000_0);
+    memory_set
+        .insert_shared_area(
+            shared.into(),
+            VirtPageNum(shared.0 + 2).into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    assert!(!memory_set.remap_contiguous(shared));
+    info!("remap_contiguous_test passed!");
+}
+
+#[allow(unused)]
+/// check a frame mapped twice by `MemorySet::map_alias` is seen through both
+pub fn map_alias_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let vpn = VirtPageNum(0x1000_0);
+    memory_set.insert_framed_area(
+        vpn.into(),
+        VirtPageNum(vpn.0 + 1).into(),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    let alias_va = VirtAddr::from(0x2000_0000);
+    // 落在逻辑段内已被释放的页面上也算重叠
+    let dropped = VirtPageNum(0x3000_0);
+    let ro = MapPermission::R | MapPermission::U;
+    memory_set
+        .insert_framed_area(dropped.into(), VirtPageNum(dropped.0 + 1).into(), ro)
+        .unwrap();
+    assert!(memory_set.drop_pages(dropped, VirtPageNum(dropped.0 + 1)));
+    assert!(!memory_set.map_alias(vpn, dropped.into(), ro));
+    assert!(memory_set.translate(dropped).map_or(true, |pte| !pte.is_valid()));
+    assert_eq!(memory_set.remove(VirtAddr::from(dropped).0, PAGE_SIZE), 0);
+    // 页表节点分配不到时失败，不留下逻辑段和多出来的引用
+    #[cfg(feature = "self_test")]
+    {
+        use super::frame_set_limit;
+        let frame_ppn = memory_set.translate(vpn).unwrap().ppn();
+        let areas = memory_set.areas.len();
+        frame_set_limit(Some(0));
+        assert!(!memory_set.map_alias(vpn, alias_va, MapPermission::R | MapPermission::U));
+        frame_set_limit(None);
+        assert_eq!(memory_set.areas.len(), areas);
+        assert_eq!(frame_ref_count(frame_ppn), 1);
+        memory_set.check_invariants();
+    }
+    assert!(memory_set.map_alias(vpn, alias_va, MapPermission::R | MapPermission::U));
+    assert!(!memory_set.map_alias(vpn, alias_va, MapPermission::R | MapPermission::U));
+    let ppn = memory_set.translate(vpn).unwrap().ppn();
+    let alias = memory_set.translate(alias_va.floor()).unwrap();
+    assert_eq!(alias.ppn(), ppn);
+    assert!(alias.readable() && !alias.writable());
+    ppn.get_bytes_array()[0x10] = 0x5a;
+    assert_eq!(alias.ppn().get_bytes_array()[0x10], 0x5a);
+    // 删除原映射后页帧仍然被别名持有，不会被回收
+    assert_eq!(memory_set.remove(0x1000_0000, PAGE_SIZE), 0);
+    let frame = memory_set.areas[0].data_frames.get(&alias_va.floor()).unwrap();
+    assert_eq!(frame_ref_count(frame.ppn), 1);
+    assert_eq!(frame.ppn.get_bytes_array()[0x10], 0x5a);
+    info!("map_alias_test passed!");
+}
+
+#[allow(unused)]
+/// check `first_conflict` reports the exact range an overlapping mmap hits
+pub fn first_conflict_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+    for (start, end) in [(0x1000_0, 0x1000_2), (0x1000_4, 0x1000_8)] {
+        memory_set
+            .insert_framed_area(VirtPageNum(start).into(), VirtPageNum(end).into(), permission)
+            .unwrap();
+    }
+    let conflict = memory_set
+        .first_conflict(VirtPageNum(0x1000_3), VirtPageNum(0x1000_5))
+        .unwrap();
+    assert_eq!(conflict.get_start(), VirtPageNum(0x1000_4));
+    assert_eq!(conflict.get_end(), VirtPageNum(0x1000_8));
+    // 落在两个逻辑段之间的空隙里则没有冲突
+    assert!(memory_set
+        .first_conflict(VirtPageNum(0x1000_2), VirtPageNum(0x1000_4))
+        .is_none());
+    assert_eq!(
+        memory_set.insert_framed_area(
+            VirtPageNum(0x1000_1).into(),
+            VirtPageNum(0x1000_3).into(),
+            permission,
+        ),
+        Err(MapError::Overlap(VirtPageNum(0x1000_0), VirtPageNum(0x1000_2)))
+    );
+    info!("first_conflict_test passed!");
+}
+
+#[allow(unused)]
+/// check `regions` reports every area sorted with its own permission
+pub fn regions_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let rx = MapPermission::R | MapPermission::X | MapPermission::U;
+    // 故意先插入地址较高的逻辑段
+    memory_set
+        .insert_framed_area(VirtAddr::from(0x2000_0000), VirtAddr::from(0x2000_1000), rx)
+        .unwrap();
+    memory_set
+        .insert_framed_area(VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_2000), rw)
+        .unwrap();
+    let regions: Vec<_> = memory_set.regions().collect();
+    assert_eq!(regions.len(), 2);
+    assert_eq!(
+        regions[0],
+        (VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_2000), rw, MapType::Framed)
+    );
+    assert_eq!(
+        regions[1],
+        (VirtAddr::from(0x2000_0000), VirtAddr::from(0x2000_1000), rx, MapType::Framed)
+    );
+    info!("regions_test passed!");
+}
+
+#[allow(unused)]
+/// check `virtual_size` sums every area and leaves out identical ones on request
+pub fn virtual_size_test() {
+    let mut memory_set = MemorySet::new_bare();
+    assert_eq!(memory_set.virtual_size(true), 0);
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    memory_set
+        .insert_framed_area(VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_2000), rw)
+        .unwrap();
+    // 不足一页的部分按整页计算
+    memory_set
+        .insert_framed_area(VirtAddr::from(0x2000_0000), VirtAddr::from(0x2000_0010), rw)
+        .unwrap();
+    memory_set.push(
+        MapArea::new(
+            VirtAddr::from(0x8020_0000),
+            VirtAddr::from(0x8020_3000),
+            MapType::Identical,
+            MapPermission::R,
+        ),
+        None,
+    );
+    assert_eq!(memory_set.virtual_size(false), 3 * PAGE_SIZE);
+    assert_eq!(memory_set.virtual_size(true), 6 * PAGE_SIZE);
+    assert_eq!(memory_set.remove(0x1000_0000, 2 * PAGE_SIZE), 0);
+    assert_eq!(memory_set.virtual_size(false), PAGE_SIZE);
+    info!("virtual_size_test passed!");
+}
+
+#[allow(unused)]
+/// check `from_elf` reports garbage images as errors instead of panicking
+pub fn from_elf_error_test() {
+    assert!(MemorySet::from_elf(&[]).is_err());
+    assert!(MemorySet::from_elf(&[0xa5u8; 256]).is_err());
+    // 只有魔数，后面的头部被截断
+    assert!(MemorySet::from_elf(&[0x7f, 0x45, 0x4c, 0x46, 2, 1, 1]).is_err());
+    info!("from_elf_error_test passed!");
+}
+
+#[allow(unused)]
+/// Build a minimal RISC-V ELF64 image with one `R | W` loadable segment per
+/// `(vaddr, file_size, mem_size, align)`, whose file data is filled with
+/// `0xa5`. The data of segment `i` sits at file offset `(i + 1) * PAGE_SIZE`.
+fn build_test_elf(segments: &[(usize, usize, usize, usize)]) -> Vec<u8> {
+    fn put(image: &mut Vec<u8>, value: u64, size: usize) {
+        image.extend_from_slice(&value.to_le_bytes()[..size]);
+    }
+    let mut image = Vec::new();
+    image.extend_from_slice(&[0x7f, 0x45, 0x4c, 0x46, 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // e_type, e_machine, e_version, e_entry, e_phoff, e_shoff, e_flags
+    put(&mut image, 2, 2);
+    put(&mut image, 0xf3, 2);
+    put(&mut image, 1, 4);
+    put(&mut image, segments[0].0 as u64, 8);
+    put(&mut image, 64, 8);
+    put(&mut image, 0, 8);
+    put(&mut image, 0, 4);
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for value in [64, 56, segments.len() as u64, 64, 0, 0] {
+        put(&mut image, value, 2);
+    }
+    // 每个段的文件数据从一个新页开始
+    let data_offset = |i: usize| (i + 1) * PAGE_SIZE;
+    for (i, &(vaddr, file_size, mem_size, align)) in segments.iter().enumerate() {
+        put(&mut image, 1, 4); // PT_LOAD
+        put(&mut image, 6, 4); // PF_R | PF_W
+        put(&mut image, data_offset(i) as u64, 8);
+        put(&mut image, vaddr as u64, 8);
+        put(&mut image, vaddr as u64, 8);
+        put(&mut image, file_size as u64, 8);
+        put(&mut image, mem_size as u64, 8);
+        put(&mut image, align as u64, 8);
+    }
+    for (i, &(_, file_size, _, _)) in segments.iter().enumerate() {
+        image.resize(data_offset(i), 0);
+        image.resize(data_offset(i) + file_size, 0xa5);
+    }
+    image
+}
+
+#[allow(unused)]
+/// check the bss tail `[file_size, mem_size)` of a segment reads zero even on
+/// dirty frames, and segments sharing a page are rejected
+pub fn elf_bss_test() {
+    // 先弄脏一批物理页帧再回收，让后面的分配拿到的都是脏页
+    let dirty: Vec<FrameTracker> = (0..16).map(|_| frame_alloc().unwrap()).collect();
+    for frame in dirty.iter() {
+        for byte in frame.ppn.get_bytes_array() {
+            *byte = 0x5a;
+        }
+    }
+    drop(dirty);
+    let elf = build_test_elf(&[(0x1000_0000, 0x10, 2 * PAGE_SIZE, PAGE_SIZE)]);
+    let (memory_set, _, entry) = MemorySet::from_elf(&elf).unwrap();
+    assert_eq!(entry, 0x1000_0000);
+    let area = memory_set.find_area_containing(VirtPageNum(0x1000_0)).unwrap();
+    assert_eq!(area.vpn_range.get_end(), VirtPageNum(0x1000_2));
+    let first = memory_set.translate(VirtPageNum(0x1000_0)).unwrap().ppn();
+    assert!(first.get_bytes_array()[..0x10].iter().all(|byte| *byte == 0xa5));
+    assert!(first.get_bytes_array()[0x10..].iter().all(|byte| *byte == 0));
+    let second = memory_set.translate(VirtPageNum(0x1000_1)).unwrap().ppn();
+    assert!(second.get_bytes_array().iter().all(|byte| *byte == 0));
+    // 第二个段从第一个段最后一页的中间开始
+    let elf = build_test_elf(&[
+        (0x1000_0000, 0x10, 0x800, PAGE_SIZE),
+        (0x1000_0800, 0x10, 0x10, PAGE_SIZE),
+    ]);
+    assert_eq!(MemorySet::from_elf(&elf).err(), Some(ElfError::SharedPage));
+    info!("elf_bss_test passed!");
+}
+
+#[allow(unused)]
+/// check a segment declaring a 2-page `p_align` gets an area aligned to it,
+/// with its data at the right offset inside
+pub fn elf_align_test() {
+    // 数据在文件中的偏移 0x1000 与虚拟地址对 0x2000 同余
+    let elf = build_test_elf(&[(0x1000_1000, 0x10, 0x10, 2 * PAGE_SIZE)]);
+    let (memory_set, _, _) = MemorySet::from_elf(&elf).unwrap();
+    let area = memory_set.find_area_containing(VirtPageNum(0x1000_1)).unwrap();
+    assert_eq!(area.vpn_range.get_start(), VirtPageNum(0x1000_0));
+    assert_eq!(area.vpn_range.get_end(), VirtPageNum(0x1000_2));
+    let first = memory_set.translate(VirtPageNum(0x1000_0)).unwrap().ppn();
+    assert!(first.get_bytes_array().iter().all(|byte| *byte == 0));
+    let second = memory_set.translate(VirtPageNum(0x1000_1)).unwrap().ppn();
+    assert!(second.get_bytes_array()[..0x10].iter().all(|byte| *byte == 0xa5));
+    assert!(second.get_bytes_array()[0x10..].iter().all(|byte| *byte == 0));
+    // 不合理的对齐要求退回按页对齐
+    assert_eq!(
+        segment_bounds(0x1000_1000, 0x10, 3 * PAGE_SIZE),
+        (VirtAddr::from(0x1000_1000), VirtAddr::from(0x1000_1010))
+    );
+    assert_eq!(
+        segment_bounds(0x1000_1000, 0x10, 2 * MAX_SEGMENT_ALIGN).0,
+        VirtAddr::from(0x1000_1000)
+    );
+    info!("elf_align_test passed!");
+}
+
+#[allow(unused)]
+/// check `MapArea::contains` and `contains_va` at the edges of the range
+pub fn map_area_contains_test() {
+    let area = MapArea::new(
+        VirtPageNum(0x1000_0).into(),
+        VirtPageNum(0x1000_4).into(),
+        MapType::Framed,
+        MapPermission::R,
+    );
+    // 起点包含在内，终点不包含
+    assert!(area.contains(VirtPageNum(0x1000_0)));
+    assert!(area.contains(VirtPageNum(0x1000_2)));
+    assert!(area.contains(VirtPageNum(0x1000_3)));
+    assert!(!area.contains(VirtPageNum(0x1000_4)));
+    assert!(!area.contains(VirtPageNum(0x0fff_f)));
+    assert!(!area.contains(VirtPageNum(0x2000_0)));
+    assert!(area.contains_va(VirtAddr::from(0x1000_0000)));
+    assert!(area.contains_va(VirtAddr::from(0x1000_3fff)));
+    assert!(!area.contains_va(VirtAddr::from(0x1000_4000)));
+    assert!(!area.contains_va(VirtAddr::from(0x0fff_ffff)));
+    info!("map_area_contains_test passed!");
+}
+
+#[allow(unused)]
+/// check `insert_framed_area` rejects an area overlapping an existing one
+pub fn insert_overlap_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let (start, end) = (VirtPageNum(0x1000_0), VirtPageNum(0x1000_4));
+    memory_set
+        .insert_framed_area(start.into(), end.into(), MapPermission::R | MapPermission::W)
+        .unwrap();
+    assert_eq!(
+        memory_set.insert_framed_area(
+            VirtPageNum(0x1000_3).into(),
+            VirtPageNum(0x1000_6).into(),
+            MapPermission::R | MapPermission::W,
+        ),
+        Err(MapError::Overlap(start, end))
+    );
+    assert_eq!(memory_set.areas.len(), 1);
+    // 紧挨着的逻辑段不算重叠
+    memory_set
+        .insert_framed_area(end.into(), VirtPageNum(0x1000_6).into(), MapPermission::R)
+        .unwrap();
+    info!("insert_overlap_test passed!");
+}
+
+#[allow(unused)]
+/// check `find_free_range` picks a 2-page gap nothing uses and it can be mapped
+pub fn find_free_range_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let base = VirtAddr::from(MMAP_BASE).floor();
+    let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+    // 在 base 处占一页，base + 3 处再占一页，中间恰好留下两页空隙
+    for offset in [0, 3] {
+        memory_set
+            .insert_framed_area(
+                VirtPageNum(base.0 + offset).into(),
+                VirtPageNum(base.0 + offset + 1).into(),
+                permission,
+            )
+            .unwrap();
+    }
+    assert_eq!(memory_set.find_free_range(3), Some(VirtPageNum(base.0 + 4)));
+    let start = memory_set.find_free_range(2).unwrap();
+    assert_eq!(start, VirtPageNum(base.0 + 1));
+    let end = VirtPageNum(start.0 + 2);
+    for vpn in start.0..end.0 {
+        assert!(memory_set.find_area_containing(VirtPageNum(vpn)).is_none());
+    }
+    memory_set
+        .insert_framed_area(start.into(), end.into(), permission)
+        .unwrap();
+    for vpn in start.0..end.0 {
+        let vpn = VirtPageNum(vpn);
+        assert!(memory_set.is_accessible(vpn.into(), MapPermission::W));
+        let ppn = memory_set.translate(vpn).unwrap().ppn();
+        ppn.get_bytes_array()[0] = 0x5a;
+        assert_eq!(ppn.get_bytes_array()[0], 0x5a);
+    }
+    assert_eq!(memory_set.find_free_range(1), Some(VirtPageNum(base.0 + 4)));
+    info!("find_free_range_test passed!");
+}
+
+#[allow(unused)]
+/// check `remove` splits an area for a range covering part of it, and a
+/// range reaching past the area is rejected and leaves it mapped
+pub fn munmap_partial_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_3000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    // 超出结尾的范围不会解除任何映射
+    assert_eq!(memory_set.remove(0x1000_0000, 4 * PAGE_SIZE), -1);
+    assert_eq!(memory_set.areas.len(), 1);
+    for vpn in memory_set.areas[0].vpn_range {
+        assert!(memory_set.translate(vpn).unwrap().is_valid());
+    }
+    // 中间一页把逻辑段拆成两段
+    assert_eq!(memory_set.remove(0x1000_1000, PAGE_SIZE), 0);
+    assert_eq!(memory_set.areas.len(), 2);
+    assert_eq!(memory_set.remove(0x1000_1000, PAGE_SIZE), -1);
+    assert_eq!(memory_set.remove(0x1000_0000, 3 * PAGE_SIZE), -1);
+    assert_eq!(memory_set.remove(0x1000_0000, PAGE_SIZE), 0);
+    assert_eq!(memory_set.remove(0x1000_2000, PAGE_SIZE), 0);
+    assert!(memory_set.areas.is_empty());
+    assert!(memory_set
+        .translate(VirtAddr::from(0x1000_1000).floor())
+        .map_or(true, |pte| !pte.is_valid()));
+    info!("munmap_partial_test passed!");
+}
+
+#[allow(unused)]
+/// check removing a multi-page area frees its data frames and the page table
+/// nodes created for it
+pub fn unmap_area_reclaim_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let available = frames_available();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_4000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    // 4 个数据页帧，外加一个 level-1 和一个 level-0 节点
+    assert_eq!(memory_set.page_table.node_count(), 3);
+    assert_eq!(frames_available(), available - 6);
+    let vpns: Vec<VirtPageNum> = memory_set.areas[0].vpn_range.into_iter().rev().collect();
+    assert_eq!(vpns.first(), Some(&VirtPageNum(0x1000_3)));
+    assert_eq!(vpns.last(), Some(&VirtPageNum(0x1000_0)));
+    assert_eq!(memory_set.remove(0x1000_0000, 4 * PAGE_SIZE), 0);
+    assert_eq!(memory_set.page_table.node_count(), 1);
+    assert_eq!(frames_available(), available);
+    info!("unmap_area_reclaim_test passed!");
+}
+
+#[allow(unused)]
+/// check dropped pages read back as zero after the next fault
+pub fn drop_pages_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let start = VirtPageNum(0x1000_0);
+    let end = VirtPageNum(0x1000_2);
+    memory_set
+        .insert_framed_area(
+            start.into(),
+            end.into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    for vpn in VPNRange::new(start, end) {
+        memory_set.translate(vpn).unwrap().ppn().get_bytes_array()[0x10] = 0x5a;
+    }
+    assert!(!memory_set.drop_pages(start, VirtPageNum(0x1000_3)));
+    assert!(memory_set.drop_pages(start, end));
+    assert_eq!(memory_set.areas.len(), 1);
+    for vpn in VPNRange::new(start, end) {
+        assert!(memory_set.translate(vpn).map_or(true, |pte| !pte.is_valid()));
+    }
+    // 没有执行权限的访问仍然是错误
+    assert!(!memory_set.handle_page_fault(start.into(), MapPermission::X));
+    assert!(memory_set.handle_page_fault(VirtAddr::from(0x1000_0010), MapPermission::R));
+    let pte = memory_set.translate(start).unwrap();
+    assert!(pte.is_valid() && pte.writable());
+    assert_eq!(pte.ppn().get_bytes_array()[0x10], 0);
+    // 已经映射的页面上的缺页不能再处理
+    assert!(!memory_set.handle_page_fault(start.into(), MapPermission::W));
+    // ELF 段和共享的逻辑段不能释放，否则代码和初始数据会变成全零
+    let elf = build_test_elf(&[(0x2000_0000, 0x10, 0x10, PAGE_SIZE)]);
+    let (mut elf_set, _, _) = MemorySet::from_elf(&elf).unwrap();
+    let segment = VirtPageNum(0x2000_0);
+    let ppn = elf_set.translate(segment).unwrap().ppn();
+    assert!(!elf_set.drop_pages(segment, VirtPageNum(segment.0 + 1)));
+    assert_eq!(elf_set.translate(segment).unwrap().ppn(), ppn);
+    let shared = VirtPageNum(0x3000_0);
+    memory_set
+        .insert_shared_area(
+            shared.into(),
+            VirtPageNum(shared.0 + 1).into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    assert!(!memory_set.drop_pages(shared, VirtPageNum(shared.0 + 1)));
+    assert!(memory_set.translate(shared).unwrap().is_valid());
+    info!("drop_pages_test passed!");
+}
+
+#[allow(unused)]
+/// check `find_invariant_violation` notices a corrupted area
+pub fn check_invariants_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let start = VirtPageNum(0x1000_0);
+    memory_set
+        .insert_framed_area(
+            start.into(),
+            VirtPageNum(0x1000_2).into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    assert!(memory_set.find_invariant_violation().is_none());
+    // 被 drop_pages 释放的页面既没有页帧也没有映射，是合法状态
+    assert!(memory_set.drop_pages(start, VirtPageNum(0x1000_1)));
+    memory_set.check_invariants();
+    // 让第二页的页表项指向另一个页帧
+    let frame = frame_alloc().unwrap();
+    memory_set
+        .page_table
+        .map_force(VirtPageNum(0x1000_1), frame.ppn, PTEFlags::R | PTEFlags::U);
+    assert!(memory_set.find_invariant_violation().is_some());
+    info!("check_invariants_test passed!");
+}
+
+#[allow(unused)]
+/// check `translate_va` keeps the page offset and rejects unmapped addresses
+pub fn translate_va_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_2000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    let ppn = memory_set.translate(VirtPageNum(0x1000_0)).unwrap().ppn();
+    let pa = memory_set.translate_va(VirtAddr::from(0x1000_0000)).unwrap();
+    assert_eq!(pa, PhysAddr::from(ppn));
+    let pa = memory_set.translate_va(VirtAddr::from(0x1000_0123)).unwrap();
+    assert_eq!(pa.floor(), ppn);
+    assert_eq!(pa.page_offset(), 0x123);
+    assert_eq!(memory_set.translate_va(VirtAddr::from(0x1000_2000)), None);
+    assert_eq!(memory_set.translate_va(VirtAddr::from(0x2000_0008)), None);
+    // 解除映射后不再返回物理地址
+    assert_eq!(memory_set.remove(0x1000_0000, 2 * PAGE_SIZE), 0);
+    assert_eq!(memory_set.translate_va(VirtAddr::from(0x1000_0123)), None);
+    info!("translate_va_test passed!");
+}
+
+#[allow(unused)]
+/// check `clone_full` shares the frames of a shared area and copies a private one
+pub fn clone_full_shared_test() {
+    let mut parent = MemorySet::new_bare();
+    let perm = MapPermission::R | MapPermission::W | MapPermission::U;
+    let (shared, private) = (VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_1000));
+    parent
+        .insert_shared_area(shared, VirtAddr::from(0x1000_1000), perm)
+        .unwrap();
+    parent
+        .insert_framed_area(private, VirtAddr::from(0x1000_2000), perm)
+        .unwrap();
+    *parent.translate_va(shared).unwrap().get_mut::<u8>() = 1;
+    *parent.translate_va(private).unwrap().get_mut::<u8>() = 1;
+    let child = parent.clone_full().unwrap();
+    assert!(child.areas[0].shared);
+    assert!(!child.areas[1].shared);
+    assert_eq!(*child.translate_va(private).unwrap().get_mut::<u8>(), 1);
+    // 子空间写入共享页面对父空间可见，写入私有页面则不可见
+    *child.translate_va(shared).unwrap().get_mut::<u8>() = 2;
+    *child.translate_va(private).unwrap().get_mut::<u8>() = 2;
+    assert_eq!(*parent.translate_va(shared).unwrap().get_mut::<u8>(), 2);
+    assert_eq!(*parent.translate_va(private).unwrap().get_mut::<u8>(), 1);
+    assert_eq!(child.translate_va(shared), parent.translate_va(shared));
+    // 子空间回收后父空间的共享页帧仍然有效
+    let available = frames_available();
+    drop(child);
+    assert!(frames_available() > available);
+    assert_eq!(*parent.translate_va(shared).unwrap().get_mut::<u8>(), 2);
+    info!("clone_full_shared_test passed!");
+}
+
+#[allow(unused)]
+/// check `trap_context_ppn` agrees with translating `TRAP_CONTEXT` by hand
+pub fn trap_context_ppn_test() {
+    let elf = build_test_elf(&[(0x1000_0000, 0x10, 0x10, PAGE_SIZE)]);
+    let (memory_set, _, _) = MemorySet::from_elf(&elf).unwrap();
+    let pte = memory_set.translate(VirtAddr::from(TRAP_CONTEXT).floor()).unwrap();
+    assert!(pte.is_valid());
+    assert_eq!(memory_set.trap_context_ppn(), Some(pte.ppn()));
+    assert_eq!(MemorySet::new_bare().trap_context_ppn(), None);
+    info!("trap_context_ppn_test passed!");
+}
+
+#[allow(unused)]
+/// check a cloned space compares equal until one side writes a private page
+pub fn equal_contents_test() {
+    let mut parent = MemorySet::new_bare();
+    let perm = MapPermission::R | MapPermission::W | MapPermission::U;
+    let (shared, private) = (VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_1000));
+    parent
+        .insert_shared_area(shared, VirtAddr::from(0x1000_1000), perm)
+        .unwrap();
+    parent
+        .insert_framed_area(private, VirtAddr::from(0x1000_3000), perm)
+        .unwrap();
+    *parent.translate_va(private).unwrap().get_mut::<u8>() = 1;
+    assert!(parent.equal_contents(&parent));
+    let mut child = parent.clone_full().unwrap();
+    assert!(parent.equal_contents(&child));
+    assert!(child.equal_contents(&parent));
+    // 共享页面两边都能看到同样的修改
+    *child.translate_va(shared).unwrap().get_mut::<u8>() = 2;
+    assert!(parent.equal_contents(&child));
+    *child.translate_va(VirtAddr::from(0x1000_2fff)).unwrap().get_mut::<u8>() = 3;
+    assert!(!parent.equal_contents(&child));
+    *child.translate_va(VirtAddr::from(0x1000_2fff)).unwrap().get_mut::<u8>() = 0;
+    assert!(parent.equal_contents(&child));
+    // 多映射一页也不相等
+    child
+        .insert_framed_area(VirtAddr::from(0x2000_0000), VirtAddr::from(0x2000_1000), perm)
+        .unwrap();
+    assert!(!parent.equal_contents(&child));
+    assert!(!MemorySet::new_bare().equal_contents(&parent));
+    info!("equal_contents_test passed!");
+}
+
+#[allow(unused)]
+/// check the `Debug` output of a `MapArea` names its range, type and permission
+pub fn map_area_debug_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_2000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    let text = format!("{:?}", memory_set.areas[0]);
+    assert_eq!(text, "VPN:0x10000..VPN:0x10002 Framed rw-u frames: 2");
+    let area = MapArea::new(
+        VirtAddr::from(0x8020_0000),
+        VirtAddr::from(0x8020_1000),
+        MapType::Identical,
+        MapPermission::R | MapPermission::X,
+    );
+    let text = format!("{:?}", area);
+    assert!(text.contains("VPN:0x80200..VPN:0x80201"));
+    assert!(text.contains("Identical r-x-"));
+    info!("map_area_debug_test passed!");
+}
+
+#[allow(unused)]
+/// check a 512-page area gets consecutive frames while the allocator has them
+pub fn contiguous_area_test() {
+    use crate::config::HUGE_PAGE_SIZE;
+    let mut memory_set = MemorySet::new_bare();
+    let start = VirtAddr::from(0x1000_0000);
+    let end = VirtAddr::from(0x1000_0000 + HUGE_PAGE_SIZE);
+    let perm = MapPermission::R | MapPermission::W | MapPermission::U;
+    assert_eq!(memory_set.insert_contiguous_area(start, end, perm), Ok(true));
+    let ppns: Vec<usize> = memory_set.areas[0]
+        .vpn_range
+        .into_iter()
+        .map(|vpn| memory_set.translate(vpn).unwrap().ppn().0)
+        .collect();
+    assert_eq!(ppns.len(), 512);
+    assert!(ppns.windows(2).all(|w| w[1] == w[0] + 1));
+    assert!(memory_set.translate(start.floor()).unwrap().flags().contains(PTEFlags::U));
+    assert_eq!(
+        memory_set.insert_contiguous_area(start, VirtAddr::from(0x1000_1000), perm),
+        Err(MapError::Overlap(start.floor(), end.floor()))
+    );
+    memory_set.check_invariants();
+    // 别名地址：页号超出 27 位的部分被页表忽略，第二页落在已映射的 start 上
+    let alias = VirtPageNum(start.floor().0 - 1 + (1 << 27));
+    assert_eq!(
+        memory_set.insert_contiguous_area(alias.into(), VirtPageNum(alias.0 + 2).into(), perm),
+        Err(MapError::Overlap(VirtPageNum(alias.0 + 1), VirtPageNum(alias.0 + 2)))
+    );
+    memory_set.check_invariants();
+    assert_eq!(memory_set.areas.len(), 1);
+    assert!(memory_set
+        .translate(VirtPageNum(start.floor().0 - 1))
+        .map_or(true, |pte| !pte.is_valid()));
+    assert_eq!(memory_set.translate(start.floor()).unwrap().ppn().0, ppns[0]);
+    assert_eq!(memory_set.remove(start.0, HUGE_PAGE_SIZE), 0);
+    info!("contiguous_area_test passed!");
+}
+
+#[allow(unused)]
+/// check `range` looks at the page table instead of the area bounds
+pub fn range_present_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let start = VirtPageNum(0x1000_0);
+    memory_set
+        .insert_framed_area(
+            start.into(),
+            VirtPageNum(start.0 + 3).into(),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    assert!(memory_set.range(start.0, start.0 + 3));
+    assert!(memory_set.range(start.0 + 1, start.0 + 2));
+    assert!(!memory_set.range(start.0, start.0 + 4));
+    assert!(!memory_set.range(start.0, start.0));
+    // 逻辑段还在，但中间一页已经没有映射
+    assert!(memory_set.drop_pages(VirtPageNum(start.0 + 1), VirtPageNum(start.0 + 2)));
+    assert!(!memory_set.range(start.0, start.0 + 3));
+    assert!(memory_set.range(start.0, start.0 + 1));
+    assert!(!memory_set.range(0x2000_0, 0x2000_1));
+    info!("range_present_test passed!");
+}
+
+#[allow(unused)]
+/// check `from_elf_with_args` leaves argc, the argv array and both strings
+/// readable from the new space's stack, with an aligned user_sp
+pub fn elf_args_test() {
+    use super::read_user_struct;
+    let elf = build_test_elf(&[(0x1000_0000, 0x10, 0x10, PAGE_SIZE)]);
+    let (memory_set, stack_top, _) = MemorySet::from_elf(&elf).unwrap();
+    drop(memory_set);
+    let (memory_set, user_sp, entry, argc, argv) =
+        MemorySet::from_elf_with_args(&elf, &["hello", "rcore!"]).unwrap();
+    assert_eq!(entry, 0x1000_0000);
+    assert_eq!(argc, 2);
+    assert_eq!(user_sp % 16, 0);
+    assert!(user_sp < argv && argv < stack_top);
+    let token = memory_set.token();
+    let read_ptr = |i: usize| {
+        read_user_struct(token, (argv + i * size_of::<usize>()) as *const usize).unwrap()
+    };
+    // 按 nul 结尾读出字符串
+    let read_str = |ptr: usize| -> Vec<u8> {
+        (ptr..)
+            .map(|va| read_user_struct(token, va as *const u8).unwrap())
+            .take_while(|byte| *byte != 0)
+            .collect()
+    };
+    assert!(read_ptr(0) >= user_sp && read_ptr(1) >= user_sp);
+    assert_eq!(read_str(read_ptr(0)), b"hello");
+    assert_eq!(read_str(read_ptr(1)), b"rcore!");
+    assert_eq!(read_ptr(2), 0);
+    // 没有参数时 argv 只有结尾的空指针
+    let (memory_set, user_sp, _, argc, argv) = MemorySet::from_elf_with_args(&elf, &[]).unwrap();
+    assert_eq!(argc, 0);
+    assert_eq!(user_sp % 16, 0);
+    assert_eq!(read_user_struct(memory_set.token(), argv as *const usize).unwrap(), 0);
+    let long: String = (0..USER_STACK_SIZE).map(|_| 'a').collect();
+    assert_eq!(
+        MemorySet::from_elf_with_args(&elf, &[long.as_str()]).err(),
+        Some(ElfError::ArgsTooLong)
+    );
+    info!("elf_args_test passed!");
+}
+
+#[allow(unused)]
+/// check a write fault on a copy-on-write page leaves a private, writable
+/// copy and gives up the reference to the shared frame
+pub fn cow_fault_test() {
+    let mut memory_set = MemorySet::new_bare();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_2000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    let (vpn, last) = (VirtPageNum(0x1000_0), VirtPageNum(0x1000_1));
+    let old_ppn = memory_set.translate(vpn).unwrap().ppn();
+    old_ppn.get_bytes_array()[..4].copy_from_slice(b"cow!");
+    assert!(!memory_set.handle_cow_fault(vpn));
+    assert!(memory_set.mark_cow(vpn));
+    assert!(!memory_set.translate(vpn).unwrap().writable());
+    // 模拟另一个地址空间仍然引用着这个页帧
+    let other = memory_set
+        .find_area_containing(vpn)
+        .unwrap()
+        .data_frames
+        .get(&vpn)
+        .unwrap()
+        .share();
+    assert_eq!(frame_ref_count(other.ppn), 2);
+    // 写入触发的缺页
+    assert!(memory_set.handle_page_fault(VirtAddr::from(vpn), MapPermission::W));
+    let pte = memory_set.translate(vpn).unwrap();
+    assert!(pte.writable());
+    assert_ne!(pte.ppn(), old_ppn);
+    assert_eq!(&pte.ppn().get_bytes_array()[..4], b"cow!");
+    assert_eq!(frame_ref_count(other.ppn), 1);
+    assert!(!memory_set.handle_cow_fault(vpn));
+    // 没有别的引用时不用拷贝，直接恢复 W
+    let last_ppn = memory_set.translate(last).unwrap().ppn();
+    assert!(memory_set.mark_cow(last));
+    assert!(memory_set.handle_cow_fault(last));
+    let pte = memory_set.translate(last).unwrap();
+    assert!(pte.writable());
+    assert_eq!(pte.ppn(), last_ppn);
+    // 只读页面不能标记为写时复制
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_4000),
+            VirtAddr::from(0x1000_5000),
+            MapPermission::R | MapPermission::U,
+        )
+        .unwrap();
+    assert!(!memory_set.mark_cow(VirtPageNum(0x1000_4)));
+    info!("cow_fault_test passed!");
+}
+
+#[allow(unused)]
+/// check unmapping 3 pages from the middle of a 5-page area returns 3 and
+/// leaves the first and last page mapped in areas of their own
+pub fn unmap_area_by_range_test() {
+    let mut memory_set = MemorySet::new_bare();
+    let available = frames_available();
+    memory_set
+        .insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_5000),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        )
+        .unwrap();
+    let first = memory_set.translate(VirtPageNum(0x1000_0)).unwrap().ppn();
+    let last = memory_set.translate(VirtPageNum(0x1000_4)).unwrap().ppn();
+    assert_eq!(memory_set.unmap_area_by_range(VirtPageNum(0x1000_1), VirtPageNum(0x1000_4)), 3);
+    let mut ranges: Vec<(VirtPageNum, VirtPageNum)> = memory_set
+        .areas
+        .iter()
+        .map(|area| (area.vpn_range.get_start(), area.vpn_range.get_end()))
+        .collect();
+    ranges.sort_by_key(|range| range.0);
+    assert_eq!(
+        ranges,
+        [
+            (VirtPageNum(0x1000_0), VirtPageNum(0x1000_1)),
+            (VirtPageNum(0x1000_4), VirtPageNum(0x1000_5))
+        ]
+    );
+    // 剩下的两页还映射着原来的页帧，中间三页的页帧已经回收
+    assert_eq!(memory_set.translate(VirtPageNum(0x1000_0)).unwrap().ppn(), first);
+    assert_eq!(memory_set.translate(VirtPageNum(0x1000_4)).unwrap().ppn(), last);
+    for vpn in 0x1000_1..0x1000_4 {
+        assert!(memory_set
+            .translate(VirtPageNum(vpn))
+            .map_or(true, |pte| !pte.is_valid()));
+    }
+    assert!(memory_set.areas.iter().all(|area| area.data_frames.len() == 1));
+    memory_set.check_invariants();
+    // 范围内有未映射的页面时什么都不做
+    assert_eq!(memory_set.unmap_area_by_range(VirtPageNum(0x1000_0), VirtPageNum(0x1000_2)), -1);
+    assert_eq!(memory_set.unmap_area_by_range(VirtPageNum(0x1000_0), VirtPageNum(0x1000_0)), -1);
+    assert!(memory_set.translate(VirtPageNum(0x1000_0)).unwrap().is_valid());
+    // 剩下的两页分别解除映射
+    assert_eq!(memory_set.unmap_area_by_range(VirtPageNum(0x1000_0), VirtPageNum(0x1000_1)), 1);
+    assert_eq!(memory_set.unmap_area_by_range(VirtPageNum(0x1000_4), VirtPageNum(0x1000_5)), 1);
+    assert!(memory_set.areas.is_empty());
+    drop(memory_set);
+    assert_eq!(frames_available(), available);
+    info!("unmap_area_by_range_test passed!");
+}
+
+#[allow(unused)]
+/// check `new_test` maps the trampoline and every region with its permission
+#[cfg(feature = "self_test")]
+pub fn new_test_space_test() {
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let rx = MapPermission::R | MapPermission::X | MapPermission::U;
+    let memory_set = MemorySet::new_test(&[
+        (0x1000_0000, 0x1000_2000, rw),
+        (0x1000_8000, 0x1000_9000, rx),
+    ]);
+    assert_eq!(memory_set.areas.len(), 2);
+    for vpn in [0x1000_0, 0x1000_1, 0x1000_8] {
+        assert!(memory_set.translate(VirtPageNum(vpn)).unwrap().is_valid());
+    }
+    assert!(memory_set.translate(VirtPageNum(0x1000_0)).unwrap().writable());
+    let pte = memory_set.translate(VirtPageNum(0x1000_8)).unwrap();
+    assert!(pte.executable() && !pte.writable());
+    // 两个区域之间没有映射
+    assert!(memory_set
+        .translate(VirtPageNum(0x1000_2))
+        .map_or(true, |pte| !pte.is_valid()));
+    let trampoline = memory_set.translate(VirtAddr::from(TRAMPOLINE).into()).unwrap();
+    assert_eq!(trampoline.ppn(), PhysAddr::from(strampoline as usize).into());
+    info!("new_test_space_test passed!");
+}
+
+#[allow(unused)]
+/// Map a kernel page with A/D cleared, read it and then write it, watching
+/// the hardware set A and then D. A page with `preset_ad` has both at once.
+pub fn ad_bits_test() {
+    let start = VirtAddr::from(0x1000_0000);
+    let end = VirtAddr::from(0x1000_2000);
+    let (vpn, preset_vpn) = (VirtPageNum(0x1000_0), VirtPageNum(0x1000_1));
+    let rw = MapPermission::R | MapPermission::W;
+    let flags = |memory_set: &MemorySet, vpn: VirtPageNum| {
+        memory_set.translate(vpn).unwrap().flags()
+    };
+    let mut kernel_space = KERNEL_SPACE.lock();
+    assert!(kernel_space.first_conflict(start.floor(), end.ceil()).is_none());
+    kernel_space
+        .insert_framed_area_ad(start, VirtAddr::from(0x1000_1000), rw, false)
+        .unwrap();
+    kernel_space
+        .insert_framed_area_ad(VirtAddr::from(0x1000_1000), end, rw, true)
+        .unwrap();
+    assert!(!flags(&kernel_space, vpn).intersects(PTEFlags::A | PTEFlags::D));
+    assert!(flags(&kernel_space, preset_vpn).contains(PTEFlags::A | PTEFlags::D));
+    // 内核空间正在使用，直接通过虚拟地址访问
+    let ptr = start.0 as *mut u64;
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    assert!(flags(&kernel_space, vpn).contains(PTEFlags::A));
+    assert!(!flags(&kernel_space, vpn).contains(PTEFlags::D));
+    unsafe { ptr.write_volatile(0x5a) };
+    assert!(flags(&kernel_space, vpn).contains(PTEFlags::D));
+    assert_eq!(kernel_space.remove(start.0, 2 * PAGE_SIZE), 0);
+    drop(kernel_space);
+    info!("ad_bits_test passed!");
+}
+
+#[allow(unused)]
+/// check running out of frames for a page table node or for the data of a
+/// page fails with the matching error and leaves nothing mapped
+#[cfg(feature = "self_test")]
+pub fn map_out_of_frames_test() {
+    use super::frame_set_limit;
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let mut memory_set = MemorySet::new_bare();
+    let (start, end) = (VirtPageNum(0x1000_0), VirtPageNum(0x1000_2));
+    // 数据页帧和 level-1 节点能分配到，level-0 节点分配不到
+    frame_set_limit(Some(2));
+    assert_eq!(
+        memory_set.insert_framed_area(start.into(), VirtPageNum(0x1000_1).into(), rw),
+        Err(MapError::NoFrameForNode(start))
+    );
+    assert_eq!(frames_available(), 2);
+    assert_eq!(memory_set.page_table.node_count(), 1);
+    // 第一页连同两个节点用掉 3 个页帧，第二页的数据页帧分配不到
+    frame_set_limit(Some(3));
+    assert_eq!(
+        memory_set.insert_framed_area(start.into(), end.into(), rw),
+        Err(MapError::NoFrameForData(VirtPageNum(0x1000_1)))
+    );
+    assert_eq!(frames_available(), 3);
+    assert_eq!(memory_set.page_table.node_count(), 1);
+    assert!(memory_set.areas.is_empty());
+    assert!(memory_set.translate(start).map_or(true, |pte| !pte.is_valid()));
+    // 页帧够用之后同一个区域可以正常映射
+    frame_set_limit(Some(4));
+    assert_eq!(memory_set.insert_framed_area(start.into(), end.into(), rw), Ok(()));
+    frame_set_limit(None);
+    assert!(memory_set.translate(VirtPageNum(0x1000_1)).unwrap().is_valid());
+    info!("map_out_of_frames_test passed!");
+}
+
+#[allow(unused)]
+/// check `grow_area` extends an area in place and refuses to run into a
+/// neighbor or to grow an area with a different permission
+pub fn grow_area_test() {
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let mut memory_set = MemorySet::new_bare();
+    let start = VirtPageNum(0x1000_0);
+    memory_set.insert_framed_area(start.into(), VirtPageNum(0x1000_1).into(), rw).unwrap();
+    memory_set
+        .insert_framed_area(VirtPageNum(0x1000_5).into(), VirtPageNum(0x1000_6).into(), rw)
+        .unwrap();
+    let first = memory_set.translate(start).unwrap().ppn();
+    first.get_bytes_array()[0] = 0x5a;
+    assert!(memory_set.grow_area(start, 2, rw));
+    memory_set.check_invariants();
+    assert_eq!(memory_set.areas.len(), 2);
+    let area = memory_set.areas.iter().find(|area| area.contains(start)).unwrap();
+    assert_eq!(
+        (area.vpn_range.get_start(), area.vpn_range.get_end()),
+        (start, VirtPageNum(0x1000_3))
+    );
+    for vpn in 0x1000_0..0x1000_3 {
+        assert!(area.contains(VirtPageNum(vpn)));
+        assert!(memory_set.translate(VirtPageNum(vpn)).unwrap().writable());
+    }
+    // 原来的页面保持不动，新页面是清零的
+    assert_eq!(memory_set.translate(start).unwrap().ppn(), first);
+    assert_eq!(first.get_bytes_array()[0], 0x5a);
+    let grown = memory_set.translate(VirtPageNum(0x1000_2)).unwrap().ppn();
+    assert!(grown.get_bytes_array().iter().all(|byte| *byte == 0));
+    // 再长 3 页就碰到 0x1000_5 处的逻辑段，什么都不映射
+    assert!(!memory_set.grow_area(start, 3, rw));
+    assert!(memory_set
+        .translate(VirtPageNum(0x1000_3))
+        .map_or(true, |pte| !pte.is_valid()));
+    // 权限不符或者不是逻辑段起点
+    assert!(!memory_set.grow_area(start, 1, MapPermission::R | MapPermission::U));
+    assert!(!memory_set.grow_area(VirtPageNum(0x1000_1), 1, rw));
+    assert!(memory_set.grow_area(start, 2, rw));
+    memory_set.check_invariants();
+    info!("grow_area_test passed!");
+}
+
+#[allow(unused)]
+/// check two spaces of the same app map its text to the same frames, one
+/// reference each, while data stays private
+pub fn shared_text_test() {
+    use crate::loader::get_app_data;
+    let (first, _, entry) = MemorySet::from_elf_shared(get_app_data(0), 0).unwrap();
+    let entry_vpn = VirtAddr::from(entry).floor();
+    let text = first.translate(entry_vpn).unwrap().ppn();
+    let refs = frame_ref_count(text);
+    let (second, _, _) = MemorySet::from_elf_shared(get_app_data(0), 0).unwrap();
+    assert_eq!(second.translate(entry_vpn).unwrap().ppn(), text);
+    assert_eq!(frame_ref_count(text), refs + 1);
+    let pte = second.translate(entry_vpn).unwrap();
+    assert!(pte.executable() && !pte.writable());
+    // 可写的段、用户栈和 Trap 上下文各自有自己的页帧
+    for (a, b) in first.areas.iter().zip(second.areas.iter()) {
+        let vpn = a.vpn_range.get_start();
+        let same = first.translate(vpn).unwrap().ppn() == second.translate(vpn).unwrap().ppn();
+        assert_eq!(same, a.shared && !a.map_perm.contains(MapPermission::W));
+        assert_eq!(same, b.shared);
+    }
+    // 不共享时 text 也是私有的
+    let (private, _, _) = MemorySet::from_elf(get_app_data(0)).unwrap();
+    assert_ne!(private.translate(entry_vpn).unwrap().ppn(), text);
+    drop(second);
+    assert_eq!(frame_ref_count(text), refs);
+    // 没有任何地址空间使用的 app 不会一直占着缓存，用一个不存在的 app 编号测试
+    let app_index = usize::MAX;
+    let cached = || SHARED_TEXT.lock().keys().filter(|key| key.0 == app_index).count();
+    let (only, _, _) = MemorySet::from_elf_shared(get_app_data(0), app_index).unwrap();
+    let page = only.translate(entry_vpn).unwrap().ppn();
+    assert!(cached() > 0);
+    assert_eq!(frame_ref_count(page), 2);
+    drop(only);
+    assert_eq!(cached(), 0);
+    assert!(SHARED_TEXT.lock().values().all(|frame| frame_ref_count(frame.ppn) > 1));
+    info!("shared_text_test passed!");
+}
+
+#[allow(unused)]
+/// check a lazily loaded space maps no segment page up front and faults in
+/// the same bytes `from_elf` copies eagerly, code page first
+pub fn elf_lazy_load_test() {
+    use crate::loader::get_app_data;
+    let image = get_app_data(0);
+    let (eager, _, entry) = MemorySet::from_elf(image).unwrap();
+    let (mut lazy, _, lazy_entry) = MemorySet::from_elf_lazy(image).unwrap();
+    assert_eq!(entry, lazy_entry);
+    let entry_vpn = VirtAddr::from(entry).floor();
+    assert!(lazy.translate(entry_vpn).map_or(true, |pte| !pte.is_valid()));
+    assert!(lazy.handle_page_fault(VirtAddr::from(entry), MapPermission::X));
+    let pte = lazy.translate(entry_vpn).unwrap();
+    assert!(pte.is_valid() && pte.executable());
+    assert_eq!(
+        pte.ppn().get_bytes_array(),
+        eager.translate(entry_vpn).unwrap().ppn().get_bytes_array()
+    );
+    // 其余 ELF 段的页面，包括 bss ，同样和 from_elf 的结果一致
+    let segments: Vec<VPNRange> = lazy
+        .areas
+        .iter()
+        .filter(|area| area.elf_offset.is_some())
+        .map(|area| area.vpn_range)
+        .collect();
+    assert!(!segments.is_empty());
+    for range in segments {
+        for vpn in range {
+            let va = VirtAddr::from(vpn);
+            if lazy.translate(vpn).map_or(true, |pte| !pte.is_valid()) {
+                let perm = lazy.find_area_containing(vpn).unwrap().map_perm;
+                let access = if perm.contains(MapPermission::R) {
+                    MapPermission::R
+                } else {
+                    MapPermission::X
+                };
+                assert!(lazy.handle_page_fault(va, access));
+            }
+            assert_eq!(
+                lazy.translate(vpn).unwrap().ppn().get_bytes_array(),
+                eager.translate(vpn).unwrap().ppn().get_bytes_array()
+            );
+        }
+    }
+    lazy.check_invariants();
+    info!("elf_lazy_load_test passed!");
+}
+
+#[allow(unused)]
+/// check `clone_kernel` maps `.text` R|X and `.data` R|W like the kernel
+/// space, and changing the copy leaves `KERNEL_SPACE` alone
+pub fn clone_kernel_test() {
+    let mut copy = MemorySet::clone_kernel();
+    let text = VirtAddr::from(stext as usize).floor();
+    let data = VirtAddr::from(sdata as usize).floor();
+    let pte = copy.translate(text).unwrap();
+    assert!(pte.readable() && pte.executable() && !pte.writable());
+    let pte = copy.translate(data).unwrap();
+    assert!(pte.readable() && pte.writable() && !pte.executable());
+    let trampoline = copy.translate(VirtAddr::from(TRAMPOLINE).into()).unwrap();
+    assert_eq!(trampoline.ppn(), PhysAddr::from(strampoline as usize).into());
+    assert_ne!(copy.token(), KERNEL_SPACE.lock().token());
+    assert_eq!(
+        copy.translate(text).unwrap().ppn(),
+        KERNEL_SPACE.lock().translate(text).unwrap().ppn()
+    );
+    // 只改副本，内核地址空间里 .data 仍然可写
+    copy.protect_range(data, VirtPageNum(data.0 + 1), PTEFlags::empty(), PTEFlags::W);
+    assert!(!copy.translate(data).unwrap().writable());
+    assert!(KERNEL_SPACE.lock().translate(data).unwrap().writable());
+    info!("clone_kernel_test passed!");
+}
+
+#[allow(unused)]
+/// Write a page, make it and its neighbor read-only and check the W bit is
+/// gone from the PTEs, a write fault is refused and the rest of the area
+/// stays writable.
+pub fn make_readonly_test() {
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let mut memory_set = MemorySet::new_bare();
+    memory_set
+        .insert_framed_area(VirtAddr::from(0x1000_0000), VirtAddr::from(0x1000_4000), rw)
+        .unwrap();
+    let (start, end) = (VirtPageNum(0x1000_1), VirtPageNum(0x1000_3));
+    let ppn = memory_set.translate(start).unwrap().ppn();
+    ppn.get_bytes_array()[0] = 0x5a;
+    assert!(memory_set.make_readonly(start, end));
+    memory_set.check_invariants();
+    for vpn in 0x1000_1..0x1000_3 {
+        let pte = memory_set.translate(VirtPageNum(vpn)).unwrap();
+        assert!(pte.is_valid() && pte.readable() && !pte.writable());
+    }
+    // 页帧和数据都不变
+    assert_eq!(memory_set.translate(start).unwrap().ppn(), ppn);
+    assert_eq!(ppn.get_bytes_array()[0], 0x5a);
+    // 之后的写入缺页不会被处理，任务会被杀掉
+    assert!(!memory_set.handle_page_fault(VirtAddr::from(start), MapPermission::W));
+    // 范围外的页面还是可写的，逻辑段被拆成三段
+    assert!(memory_set.translate(VirtPageNum(0x1000_0)).unwrap().writable());
+    assert!(memory_set.translate(VirtPageNum(0x1000_3)).unwrap().writable());
+    assert_eq!(memory_set.areas.len(), 3);
+    let area = memory_set.find_area_containing(start).unwrap();
+    assert_eq!((area.vpn_range.get_start(), area.vpn_range.get_end()), (start, end));
+    assert!(!area.map_perm.contains(MapPermission::W));
+    // 空范围、没有逻辑段的页面
+    assert!(!memory_set.make_readonly(start, start));
+    assert!(!memory_set.make_readonly(VirtPageNum(0x1000_3), VirtPageNum(0x1000_5)));
+    assert!(memory_set.translate(VirtPageNum(0x1000_3)).unwrap().writable());
+    info!("make_readonly_test passed!");
+}
+
+#[cfg(feature = "self_test")]
+#[allow(unused)]
+/// check `clone_full` fails cleanly when frames run out for a data copy or
+/// for a page table node, and frees the partial copy
+pub fn clone_full_out_of_frames_test() {
+    use super::frame_set_limit;
+    let rw = MapPermission::R | MapPermission::W | MapPermission::U;
+    let mut parent = MemorySet::new_bare();
+    let vpn = VirtPageNum(0x1000_0);
+    parent.insert_framed_area(vpn.into(), VirtPageNum(0x1000_1).into(), rw).unwrap();
+    // 根节点和跳板页的两个节点之后，私有页面的副本分配不到
+    frame_set_limit(Some(3));
+    assert_eq!(parent.clone_full().err(), Some(MapError::NoFrameForData(vpn)));
+    assert_eq!(frames_available(), 3);
+    // 副本分配到了，页表节点分配不到
+    frame_set_limit(Some(4));
+    assert_eq!(parent.clone_full().err(), Some(MapError::NoFrameForNode(vpn)));
+    assert_eq!(frames_available(), 4);
+    frame_set_limit(None);
+    let child = parent.clone_full().unwrap();
+    child.check_invariants();
+    assert!(child.translate(vpn).unwrap().is_valid());
+    info!("clone_full_out_of_frames_test passed!");
+}
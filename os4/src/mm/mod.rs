@@ -12,17 +12,47 @@ mod frame_allocator;
 mod heap_allocator;
 pub mod memory_set;
 pub mod page_table;
+mod probe;
+mod user;
 
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum};
 use address::{StepByOne, VPNRange};
-pub use frame_allocator::{frame_alloc, FrameTracker};
+pub use frame_allocator::{
+    frame_alloc, frame_alloc_contiguous, frame_alloc_uninit, frame_usage, frames_available,
+    FrameTracker,
+};
 pub use memory_set::remap_test;
-pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE};
-pub use page_table::{translated_byte_buffer, PageTableEntry};
+pub use memory_set::{FaultKind, MapPermission, MemorySet, KERNEL_SPACE};
+pub use page_table::{flush_tlb_vpn, translated_byte_buffer, PageTableEntry};
 use page_table::{PTEFlags, PageTable};
+pub use probe::memory_end;
+#[allow(unused)]
+pub use user::{
+    read_user_struct, user_byte_buffer, user_byte_buffer_mut, write_user_struct, UserAccessError,
+};
+#[cfg(feature = "self_test")]
+pub use address::{
+    address_arith_test, address_conversion_test, get_mut_test, paging_scheme_test,
+    phys_addr_get_mut_test, unaligned_vpn_test, vpn_range_index_test,
+};
+#[cfg(feature = "self_test")]
+pub use frame_allocator::{
+    bitmap_frame_allocator_test, frame_allocator_test, frame_limit_test, frame_peak_test,
+    frame_ref_count_test, frames_available_test, memory_probe_test, ordered_frame_allocator_test,
+};
+#[cfg(feature = "self_test")]
+#[allow(unused)]
+pub use frame_allocator::frame_set_limit;
+#[cfg(feature = "self_test")]
+pub use heap_allocator::heap_test;
+#[cfg(feature = "self_test")]
+pub use user::{read_user_struct_test, write_user_struct_test};
 
-/// initiate heap allocator, frame allocator and kernel space
-pub fn init() {
+/// initiate heap allocator, frame allocator and kernel space, using the
+/// device tree at `dtb` to find the end of physical memory
+pub fn init(dtb: usize) {
+    // 先确定物理内存的范围，物理页帧分配器和内核地址空间都依赖它
+    probe::init(&probe::DeviceTree::new(dtb));
     // 全局动态内存分配器的初始化
     heap_allocator::init_heap();
     // 初始化物理页帧 管理器使能可用物理页帧的分配和回收能力。
@@ -1,9 +1,11 @@
 //! Implementation of [`PageTableEntry`] and [`PageTable`].
 
 use super::{frame_alloc, FrameTracker, PhysPageNum, StepByOne, VirtAddr, VirtPageNum};
+use crate::config::PAGE_SIZE;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
+use riscv::register::satp;
 
 bitflags! {
     /// page table entry flags
@@ -112,6 +114,9 @@ impl PageTable {
     /**
      * 根据虚拟地址查找或者创建一个新的页表项
      */
+    /// `None` if a missing intermediate node cannot get a frame. A node
+    /// created earlier in the same walk is freed again, so a failed walk
+    /// leaves the page table as it was.
     fn find_pte_create(&mut self, vpn: VirtPageNum) -> Option<&mut PageTableEntry> {
         // 取出虚拟页表三级页索引
         let mut idxs = vpn.indexes();
@@ -123,6 +128,8 @@ impl PageTable {
         //      -- (*(root[idxs[0]])[idxs[1]])[idxs[2]]
         // 获取结果
         let mut result: Option<&mut PageTableEntry> = None;
+        // 本次遍历新建的节点挂在哪个节点的哪一项上，失败时要撤销
+        let mut created: Option<(PhysPageNum, usize)> = None;
         for (i, idx) in idxs.iter_mut().enumerate() {
             let pte = &mut ppn.get_pte_array()[*idx];
             if i == 2 {
@@ -133,10 +140,23 @@ impl PageTable {
             // 如果当前页表不可用，说明未创建过
             if !pte.is_valid() {
                 // 分配一个新的物理页号
-                let frame = frame_alloc().unwrap();
+                let frame = match frame_alloc() {
+                    Some(frame) => frame,
+                    None => {
+                        if let Some((parent, parent_idx)) = created {
+                            let node = parent.get_pte_array()[parent_idx].ppn();
+                            parent.get_pte_array()[parent_idx] = PageTableEntry::empty();
+                            self.free_node(node);
+                        }
+                        return None;
+                    }
+                };
                 *pte = PageTableEntry::new(frame.ppn, PTEFlags::V);
                 // 将使用的物理页号保存关联
                 self.frames.push(frame);
+                if created.is_none() {
+                    created = Some((ppn, *idx));
+                }
             }
             ppn = pte.ppn();
         }
@@ -170,31 +190,96 @@ impl PageTable {
      * 通过 map 方法来在多级页表中插入一个键值对，注意这里我们将物理页号 ppn 和
      * 页表项标志位 flags 作为 不同的参数传入而不是整合为一个页表项
      */
+    /// `Some(false)` if `vpn` is already mapped, `None` if there is no frame
+    /// left for a page table node on the way to it.
     #[allow(unused)]
-    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> bool{
-        let pte = self.find_pte_create(vpn).unwrap();
+    pub fn map(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) -> Option<bool> {
+        let pte = self.find_pte_create(vpn)?;
         // assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
         if pte.is_valid() {
-            return false;
+            return Some(false);
         }
         *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
-        true
+        Some(true)
+    }
+
+    /// 与 map 不同，不检查该虚拟页面是否已经被映射，直接覆盖叶子页表项，
+    /// 用于原地修改已有映射的标志位
+    #[allow(unused)]
+    pub fn map_force(&mut self, vpn: VirtPageNum, ppn: PhysPageNum, flags: PTEFlags) {
+        let pte = self
+            .find_pte_create(vpn)
+            .expect("out of frames creating page table node");
+        *pte = PageTableEntry::new(ppn, flags | PTEFlags::V);
     }
 
     /**
      * 通过 unmap 方法来删除一个键值对，在调用时仅需给出作为索引的虚拟页号即可。
      */
+    /// Intermediate nodes left without any valid entry are freed on the way
+    /// back up, the root is kept. Checking a node for emptiness stops at its
+    /// first valid entry, so unmapping a range from high to low addresses
+    /// finds the remaining low entries right away.
     #[allow(unused)]
     pub fn unmap(&mut self, vpn: VirtPageNum) -> bool {
-        let pte = self.find_pte_create(vpn).unwrap();
+        let idxs = vpn.indexes();
+        // nodes[i] 为第 i 级遍历到的节点所在的物理页帧，nodes[0] 是根节点
+        let mut nodes = [self.root_ppn; 3];
+        for i in 0..2 {
+            let pte = nodes[i].get_pte_array()[idxs[i]];
+            if !pte.is_valid() {
+                return false;
+            }
+            nodes[i + 1] = pte.ppn();
+        }
+        let pte = &mut nodes[2].get_pte_array()[idxs[2]];
         // assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         if !pte.is_valid() {
             return false;
         }
         *pte = PageTableEntry::empty();
+        // 自底向上回收变空的中间节点
+        for i in (1..3).rev() {
+            if nodes[i].get_pte_array().iter().any(|pte| pte.is_valid()) {
+                break;
+            }
+            nodes[i - 1].get_pte_array()[idxs[i - 1]] = PageTableEntry::empty();
+            self.free_node(nodes[i]);
+        }
         true
     }
 
+    /// Drop the frame of node `ppn`, unless this page table does not own it.
+    fn free_node(&mut self, ppn: PhysPageNum) {
+        if let Some(pos) = self.frames.iter().position(|frame| frame.ppn == ppn) {
+            self.frames.swap_remove(pos);
+        }
+    }
+
+    /// number of frames holding nodes of this page table, the root included
+    #[allow(unused)]
+    pub fn node_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// 记录三级页表遍历过程中遇到的页表项，依次为 level-2(根节点)、level-1、level-0(叶子)。
+    /// 遇到不合法的页表项时遍历结束，该级及其后面的各级都是 None 。
+    #[allow(unused)]
+    pub fn walk(&self, vpn: VirtPageNum) -> [Option<PageTableEntry>; 3] {
+        let idxs = vpn.indexes();
+        let mut ppn = self.root_ppn;
+        let mut chain: [Option<PageTableEntry>; 3] = [None; 3];
+        for (i, idx) in idxs.iter().enumerate() {
+            let pte = ppn.get_pte_array()[*idx];
+            if !pte.is_valid() {
+                break;
+            }
+            chain[i] = Some(pte);
+            ppn = pte.ppn();
+        }
+        chain
+    }
+
     // 如果能够找到页表项，那么它会将页表项拷贝一份并返回，否则就 返回一个 None 。
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.find_pte(vpn).copied()
@@ -205,6 +290,19 @@ impl PageTable {
     pub fn token(&self) -> usize {
         8usize << 60 | self.root_ppn.0
     }
+    /// 当前 satp 是否指向这个页表，只有这时才需要刷新快表
+    pub fn is_active(&self) -> bool {
+        satp::read().bits() == self.token()
+    }
+}
+
+/// Flush the TLB entry of a single virtual page with `sfence.vma vaddr`.
+/// 修改了当前地址空间中某一页的映射之后，只需要让这一页在快表中失效
+pub fn flush_tlb_vpn(vpn: VirtPageNum) {
+    let va: VirtAddr = vpn.into();
+    unsafe {
+        core::arch::asm!("sfence.vma {}, zero", in(reg) va.0);
+    }
 }
 
 /// translate a pointer to a mutable u8 Vec through page table
@@ -214,6 +312,13 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     let page_table = PageTable::from_token(token);
     let mut start = ptr as usize;
     let end = start + len;
+    // 缓冲区落在同一页内（最常见的情况），只需查一次页表
+    if len > 0 && VirtAddr::from(start).floor() == VirtAddr::from(end - 1).floor() {
+        let start_va = VirtAddr::from(start);
+        let ppn = page_table.translate(start_va.floor()).unwrap().ppn();
+        let offset = start_va.page_offset();
+        return vec![&mut ppn.get_bytes_array()[offset..offset + len]];
+    }
     let mut v = Vec::new();
     while start < end {
         let start_va = VirtAddr::from(start);
@@ -231,3 +336,82 @@ pub fn translated_byte_buffer(token: usize, ptr: *const u8, len: usize) -> Vec<&
     }
     v
 }
+
+#[allow(unused)]
+/// a simple test for the three-level page table walk
+pub fn page_table_walk_test() {
+    let mut page_table = PageTable::new();
+    let frame = frame_alloc().unwrap();
+    let vpn = VirtPageNum(0x1_0000);
+    assert_eq!(page_table.map(vpn, frame.ppn, PTEFlags::R | PTEFlags::W), Some(true));
+    let chain = page_table.walk(vpn);
+    assert!(chain.iter().all(|pte| pte.map_or(false, |pte| pte.is_valid())));
+    assert_eq!(chain[2].unwrap().ppn(), frame.ppn);
+    // 与 vpn 共享 level-2 和 level-1 节点，只在叶子处断开
+    let chain = page_table.walk(VirtPageNum(vpn.0 + 1));
+    assert!(chain[0].is_some() && chain[1].is_some() && chain[2].is_none());
+    // 根节点处就断开
+    let chain = page_table.walk(VirtPageNum(vpn.0 + (1 << 18)));
+    assert!(chain.iter().all(|pte| pte.is_none()));
+    info!("page_table_walk_test passed!");
+}
+
+#[allow(unused)]
+/// check the single-page fast path of `translated_byte_buffer` agrees with
+/// the general cross-page path
+pub fn translated_byte_buffer_test() {
+    let mut page_table = PageTable::new();
+    let frames: Vec<FrameTracker> = (0..2).map(|_| frame_alloc().unwrap()).collect();
+    let base = VirtPageNum(0x1_0000);
+    for (i, frame) in frames.iter().enumerate() {
+        page_table.map(VirtPageNum(base.0 + i), frame.ppn, PTEFlags::R | PTEFlags::W);
+        for (j, byte) in frame.ppn.get_bytes_array().iter_mut().enumerate() {
+            *byte = (i * 7 + j) as u8;
+        }
+    }
+    let base_va: usize = VirtAddr::from(base).into();
+    // 返回切片个数以及拼接后的字节
+    let collect = |ptr: usize, len: usize| -> (usize, Vec<u8>) {
+        let buffers = translated_byte_buffer(page_table.token(), ptr as *const u8, len);
+        let bytes = buffers.iter().flat_map(|b| b.iter().copied()).collect();
+        (buffers.len(), bytes)
+    };
+    // 同一页内
+    let (count, inner) = collect(base_va + 0x100, 0x80);
+    assert_eq!(count, 1);
+    assert_eq!(&inner[..], &frames[0].ppn.get_bytes_array()[0x100..0x180]);
+    // 跨页
+    let (count, crossed) = collect(base_va + 0xf80, 0x100);
+    assert_eq!(count, 2);
+    assert_eq!(&crossed[..0x80], &frames[0].ppn.get_bytes_array()[0xf80..]);
+    assert_eq!(&crossed[0x80..], &frames[1].ppn.get_bytes_array()[..0x80]);
+    // 恰好填满一页
+    let (count, page) = collect(base_va, PAGE_SIZE);
+    assert_eq!(count, 1);
+    assert_eq!(&page[..], &frames[0].ppn.get_bytes_array()[..]);
+    info!("translated_byte_buffer_test passed!");
+}
+
+#[allow(unused)]
+/// check unmapping the last leaf of a node frees the intermediate nodes
+pub fn unmap_reclaim_test() {
+    let mut page_table = PageTable::new();
+    let frames: Vec<FrameTracker> = (0..3).map(|_| frame_alloc().unwrap()).collect();
+    let vpns: Vec<VirtPageNum> = (0..3).map(|i| VirtPageNum(0x1_0000 + i)).collect();
+    for (vpn, frame) in vpns.iter().zip(frames.iter()) {
+        assert_eq!(page_table.map(*vpn, frame.ppn, PTEFlags::R | PTEFlags::W), Some(true));
+    }
+    // 根节点加上一个 level-1 和一个 level-0 节点
+    assert_eq!(page_table.node_count(), 3);
+    // 从高到低解除映射，最后一页解除后两个中间节点都被回收
+    for vpn in vpns.iter().rev() {
+        assert_eq!(page_table.node_count(), 3);
+        assert!(page_table.unmap(*vpn));
+    }
+    assert_eq!(page_table.node_count(), 1);
+    assert!(page_table.walk(vpns[0]).iter().all(|pte| pte.is_none()));
+    // 解除没有映射的页面既不会失败得很难看，也不会创建节点
+    assert!(!page_table.unmap(vpns[0]));
+    assert_eq!(page_table.node_count(), 1);
+    info!("unmap_reclaim_test passed!");
+}
@@ -0,0 +1,128 @@
+//! Detecting the end of physical memory at boot.
+//!
+//! The bootloader passes a flattened device tree in `a1`, its `/memory` node
+//! tells how much RAM the machine really has. When there is no usable device
+//! tree the kernel falls back to the compile-time [`MEMORY_END`].
+
+use crate::config::{KERNEL_START_END, MEMORY_END};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// where the end of physical memory can be read from
+pub trait MemorySource {
+    /// exclusive end address of physical memory, if known
+    fn memory_end(&self) -> Option<usize>;
+}
+
+/// flattened device tree at physical address `addr`
+pub struct DeviceTree {
+    addr: usize,
+}
+
+impl DeviceTree {
+    pub fn new(addr: usize) -> Self {
+        Self { addr }
+    }
+}
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// 设备树中的整数都是大端序
+fn be32(addr: usize) -> u32 {
+    u32::from_be(unsafe { (addr as *const u32).read_volatile() })
+}
+
+/// 读取由 cells 个 32 位整数组成的一个数
+fn read_cells(addr: usize, cells: usize) -> usize {
+    (0..cells).fold(0, |value, i| value << 32 | be32(addr + i * 4) as usize)
+}
+
+/// 以 0 结尾的字符串，不含结尾的 0
+fn c_str(addr: usize) -> &'static [u8] {
+    let mut len = 0;
+    while unsafe { *((addr + len) as *const u8) } != 0 {
+        len += 1;
+    }
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+}
+
+fn align4(addr: usize) -> usize {
+    (addr + 3) & !3
+}
+
+impl MemorySource for DeviceTree {
+    /// Base plus size of the first `reg` entry of the `/memory` node.
+    fn memory_end(&self) -> Option<usize> {
+        if self.addr == 0 || be32(self.addr) != FDT_MAGIC {
+            return None;
+        }
+        let mut p = self.addr + be32(self.addr + 8) as usize;
+        let strings = self.addr + be32(self.addr + 12) as usize;
+        // 规范规定的默认值，一般会被根节点的属性覆盖
+        let (mut address_cells, mut size_cells) = (2, 1);
+        let mut depth = 0;
+        let mut in_memory = false;
+        loop {
+            let token = be32(p);
+            p += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(p);
+                    p = align4(p + name.len() + 1);
+                    depth += 1;
+                    // 根节点深度为 1 ，memory@80000000 是它的子节点
+                    in_memory = depth == 2 && name.starts_with(b"memory");
+                }
+                FDT_END_NODE => {
+                    depth -= 1;
+                    in_memory = false;
+                }
+                FDT_PROP => {
+                    let len = be32(p) as usize;
+                    let name = c_str(strings + be32(p + 4) as usize);
+                    let value = p + 8;
+                    p = align4(value + len);
+                    if depth == 1 && name == b"#address-cells" {
+                        address_cells = be32(value) as usize;
+                    } else if depth == 1 && name == b"#size-cells" {
+                        size_cells = be32(value) as usize;
+                    } else if in_memory && name == b"reg" {
+                        let base = read_cells(value, address_cells);
+                        let size = read_cells(value + address_cells * 4, size_cells);
+                        return Some(base + size);
+                    }
+                }
+                FDT_NOP => {}
+                // FDT_END 或者无法识别的内容
+                _ => return None,
+            }
+        }
+    }
+}
+
+static DETECTED_MEMORY_END: AtomicUsize = AtomicUsize::new(MEMORY_END);
+
+/// End of physical memory reported by `source`, or `MEMORY_END` if it reports
+/// nothing usable.
+pub fn probe_memory_end(source: &impl MemorySource) -> usize {
+    match source.memory_end() {
+        Some(end) if end > KERNEL_START_END => end,
+        _ => MEMORY_END,
+    }
+}
+
+/// Detect the end of physical memory once at boot, before the frame
+/// allocator and kernel space are set up.
+pub fn init(source: &impl MemorySource) {
+    let end = probe_memory_end(source);
+    DETECTED_MEMORY_END.store(end, Ordering::Relaxed);
+    info!("physical memory end: {:#x}", end);
+}
+
+/// End of physical memory detected at boot.
+pub fn memory_end() -> usize {
+    DETECTED_MEMORY_END.load(Ordering::Relaxed)
+}
@@ -0,0 +1,211 @@
+//! Checked access to data in user space.
+//!
+//! Syscalls get raw pointers from the application. Before touching them the
+//! kernel checks that every page the data spans is mapped, accessible from U
+//! mode and carries the needed permission, so a bad pointer is reported to
+//! the caller instead of panicking in [`translated_byte_buffer`].
+
+use super::{translated_byte_buffer, PTEFlags, PageTable, VirtAddr, VirtPageNum};
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::{size_of, MaybeUninit};
+
+/// why a user pointer could not be accessed
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UserAccessError {
+    /// the page containing this address is not mapped
+    Unmapped(VirtAddr),
+    /// the page is mapped but not accessible from U mode with the needed permission
+    PermissionDenied(VirtAddr),
+}
+
+impl fmt::Display for UserAccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserAccessError::Unmapped(va) => write!(f, "unmapped user address {:?}", va),
+            UserAccessError::PermissionDenied(va) => {
+                write!(f, "permission denied at user address {:?}", va)
+            }
+        }
+    }
+}
+
+/// Check every page of `[start, start + len)` is mapped with `V | U | flags`.
+fn check_user_range(
+    page_table: &PageTable,
+    start: usize,
+    len: usize,
+    flags: PTEFlags,
+) -> Result<(), UserAccessError> {
+    if len == 0 {
+        return Ok(());
+    }
+    // 用户给出的 start + len 回绕时直接当作没有映射，不能让检查的循环变成空的
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return Err(UserAccessError::Unmapped(VirtAddr::from(start))),
+    };
+    let required = PTEFlags::V | PTEFlags::U | flags;
+    let start_vpn = VirtAddr::from(start).floor();
+    let end_vpn = VirtAddr::from(end - 1).floor();
+    for vpn in start_vpn.0..=end_vpn.0 {
+        let vpn = VirtPageNum(vpn);
+        // 报告出错页面中属于这段数据的第一个地址
+        let va = VirtAddr::from(start.max(VirtAddr::from(vpn).0));
+        match page_table.translate(vpn) {
+            Some(pte) if pte.is_valid() => {
+                if !pte.flags().contains(required) {
+                    return Err(UserAccessError::PermissionDenied(va));
+                }
+            }
+            _ => return Err(UserAccessError::Unmapped(va)),
+        }
+    }
+    Ok(())
+}
+
+/// Like [`translated_byte_buffer`], but every page of `[ptr, ptr + len)` must
+/// be user readable, otherwise nothing is translated.
+pub fn user_byte_buffer(
+    token: usize,
+    ptr: *const u8,
+    len: usize,
+) -> Result<Vec<&'static mut [u8]>, UserAccessError> {
+    let page_table = PageTable::from_token(token);
+    check_user_range(&page_table, ptr as usize, len, PTEFlags::R)?;
+    Ok(translated_byte_buffer(token, ptr, len))
+}
+
+/// Like [`user_byte_buffer`], but the pages must be user writable.
+pub fn user_byte_buffer_mut(
+    token: usize,
+    ptr: *mut u8,
+    len: usize,
+) -> Result<Vec<&'static mut [u8]>, UserAccessError> {
+    let page_table = PageTable::from_token(token);
+    check_user_range(&page_table, ptr as usize, len, PTEFlags::W)?;
+    Ok(translated_byte_buffer(token, ptr, len))
+}
+
+/// Copy a `T` out of the address space of `token`, the data may cross pages.
+pub fn read_user_struct<T: Copy>(token: usize, ptr: *const T) -> Result<T, UserAccessError> {
+    let page_table = PageTable::from_token(token);
+    check_user_range(&page_table, ptr as usize, size_of::<T>(), PTEFlags::R)?;
+    let mut value = MaybeUninit::<T>::uninit();
+    let dst =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    let mut start = 0;
+    for buffer in translated_byte_buffer(token, ptr as *const u8, size_of::<T>()) {
+        dst[start..start + buffer.len()].copy_from_slice(buffer);
+        start += buffer.len();
+    }
+    // 每个字节都已经从用户空间拷贝过来，T: Copy 保证逐字节拷贝是合法的
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Copy `value` into the address space of `token`. Every destination page is
+/// checked to be user writable before any byte is written, so on error the
+/// destination is left untouched.
+pub fn write_user_struct<T: Copy>(
+    token: usize,
+    ptr: *mut T,
+    value: &T,
+) -> Result<(), UserAccessError> {
+    let page_table = PageTable::from_token(token);
+    check_user_range(&page_table, ptr as usize, size_of::<T>(), PTEFlags::W)?;
+    let src =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    let mut start = 0;
+    for buffer in translated_byte_buffer(token, ptr as *const u8, size_of::<T>()) {
+        buffer.copy_from_slice(&src[start..start + buffer.len()]);
+        start += buffer.len();
+    }
+    Ok(())
+}
+
+#[allow(unused)]
+/// a simple test for `read_user_struct`
+pub fn read_user_struct_test() {
+    use super::{MapPermission, MemorySet};
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    #[repr(C)]
+    struct Pair {
+        a: usize,
+        b: usize,
+    }
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_2000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    // 没有 U 位的页面
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x2000_0000),
+        VirtAddr::from(0x2000_1000),
+        MapPermission::R | MapPermission::W,
+    ).unwrap();
+    let token = memory_set.token();
+    // 对齐且在同一页内
+    let value = Pair { a: 1, b: 2 };
+    write_user_struct(token, 0x1000_0100 as *mut Pair, &value).unwrap();
+    assert_eq!(read_user_struct(token, 0x1000_0100 as *const Pair), Ok(value));
+    // 跨页
+    let value = Pair { a: 3, b: 4 };
+    write_user_struct(token, 0x1000_0ff8 as *mut Pair, &value).unwrap();
+    assert_eq!(read_user_struct(token, 0x1000_0ff8 as *const Pair), Ok(value));
+    // 第二页没有映射
+    assert_eq!(
+        read_user_struct(token, 0x1000_1ff8 as *const Pair),
+        Err(UserAccessError::Unmapped(VirtAddr::from(0x1000_2000)))
+    );
+    assert_eq!(
+        read_user_struct(token, 0x3000_0000 as *const Pair),
+        Err(UserAccessError::Unmapped(VirtAddr::from(0x3000_0000)))
+    );
+    assert_eq!(
+        read_user_struct(token, 0x2000_0000 as *const Pair),
+        Err(UserAccessError::PermissionDenied(VirtAddr::from(0x2000_0000)))
+    );
+    // 结尾回绕到地址 0 的范围
+    let top = 0xffff_ffff_ffff_fff0;
+    assert_eq!(
+        user_byte_buffer(token, top as *const u8, 0x20).err(),
+        Some(UserAccessError::Unmapped(VirtAddr::from(top)))
+    );
+    assert_eq!(
+        read_user_struct(token, (usize::MAX - 7) as *const Pair),
+        Err(UserAccessError::Unmapped(VirtAddr::from(usize::MAX - 7)))
+    );
+    info!("read_user_struct_test passed!");
+}
+
+#[allow(unused)]
+/// check `write_user_struct` writes nothing when a later page is read-only
+pub fn write_user_struct_test() {
+    use super::{MapPermission, MemorySet};
+    let mut memory_set = MemorySet::new_bare();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_0000),
+        VirtAddr::from(0x1000_1000),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    ).unwrap();
+    memory_set.insert_framed_area(
+        VirtAddr::from(0x1000_1000),
+        VirtAddr::from(0x1000_2000),
+        MapPermission::R | MapPermission::U,
+    ).unwrap();
+    let token = memory_set.token();
+    let value = [0xa5u8; 16];
+    assert_eq!(
+        write_user_struct(token, 0x1000_0ff8 as *mut [u8; 16], &value),
+        Err(UserAccessError::PermissionDenied(VirtAddr::from(0x1000_1000)))
+    );
+    // 第一页中属于目标的 8 个字节也没有被写入
+    let ppn = memory_set.translate(VirtAddr::from(0x1000_0000).floor()).unwrap().ppn();
+    assert!(ppn.get_bytes_array()[0xff8..].iter().all(|byte| *byte == 0));
+    // 完全落在可写页面内则正常写入
+    write_user_struct(token, 0x1000_0ff0 as *mut [u8; 16], &value).unwrap();
+    assert_eq!(read_user_struct(token, 0x1000_0ff0 as *const [u8; 16]), Ok(value));
+    info!("write_user_struct_test passed!");
+}
@@ -6,6 +6,11 @@ const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
 const SBI_SHUTDOWN: usize = 8;
+/// System Reset extension, its function 0 is `sbi_system_reset`
+const SBI_EXT_SRST: usize = 0x5352_5354;
+const SRST_TYPE_SHUTDOWN: usize = 0;
+const SRST_REASON_NONE: usize = 0;
+const SRST_REASON_FAILURE: usize = 1;
 
 #[inline(always)]
 /// general sbi call
@@ -39,8 +44,16 @@ pub fn console_getchar() -> usize {
     sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
 }
 
-/// use sbi call to shutdown the kernel
-pub fn shutdown() -> ! {
+/// use sbi call to shutdown the kernel, a nonzero `exit_code` is reported
+/// to the SBI as a system failure
+pub fn shutdown(exit_code: i32) -> ! {
+    let reason = if exit_code == 0 {
+        SRST_REASON_NONE
+    } else {
+        SRST_REASON_FAILURE
+    };
+    sbi_call(SBI_EXT_SRST, SRST_TYPE_SHUTDOWN, reason, 0);
+    // 不支持 SRST 扩展的 SBI 会返回错误，退回到 legacy 的关机调用
     sbi_call(SBI_SHUTDOWN, 0, 0, 0);
     panic!("It should shutdown!");
 }
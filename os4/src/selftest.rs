@@ -0,0 +1,149 @@
+//! Boot-time smoke test running the kernel self tests
+//!
+//! Only built with the `self_test` feature. Runs after memory management and
+//! traps are set up but before the first task, so task tests see every task
+//! still `Ready`. A failing assertion panics the kernel; the last
+//! `[selftest] running` line names the test that failed.
+//!
+//! `trap::kernel_stack_guard_test` is left out on purpose, it overflows the
+//! kernel stack and never returns.
+
+use crate::{mm, sync, syscall, task};
+
+/// Run `$test` after logging its name, counting it in `$passed`.
+macro_rules! run {
+    ($passed:ident, $test:path) => {
+        info!("[selftest] running {}", stringify!($test));
+        $test();
+        $passed += 1;
+    };
+}
+
+/// Run every self test, panicking on the first failure.
+pub fn run_self_tests() {
+    let mut passed = 0;
+    // mm
+    run!(passed, mm::address_arith_test);
+    run!(passed, mm::vpn_range_index_test);
+    run!(passed, mm::address_conversion_test);
+    run!(passed, mm::unaligned_vpn_test);
+    run!(passed, mm::paging_scheme_test);
+    run!(passed, mm::get_mut_test);
+    run!(passed, mm::phys_addr_get_mut_test);
+    run!(passed, mm::heap_test);
+    run!(passed, mm::frame_allocator_test);
+    run!(passed, mm::memory_probe_test);
+    run!(passed, mm::frame_peak_test);
+    run!(passed, mm::frame_ref_count_test);
+    run!(passed, mm::frames_available_test);
+    run!(passed, mm::frame_limit_test);
+    run!(passed, mm::ordered_frame_allocator_test);
+    run!(passed, mm::bitmap_frame_allocator_test);
+    run!(passed, mm::page_table::page_table_walk_test);
+    run!(passed, mm::page_table::translated_byte_buffer_test);
+    run!(passed, mm::page_table::unmap_reclaim_test);
+    run!(passed, mm::remap_test);
+    run!(passed, mm::memory_set::fault_classify_test);
+    run!(passed, mm::memory_set::map_permission_test);
+    run!(passed, mm::memory_set::map_permission_from_port_test);
+    run!(passed, mm::memory_set::memory_set_dump_test);
+    run!(passed, mm::memory_set::regions_test);
+    run!(passed, mm::memory_set::virtual_size_test);
+    run!(passed, mm::memory_set::from_elf_error_test);
+    run!(passed, mm::memory_set::elf_bss_test);
+    run!(passed, mm::memory_set::elf_align_test);
+    run!(passed, mm::memory_set::elf_args_test);
+    run!(passed, mm::memory_set::iter_mappings_test);
+    run!(passed, mm::memory_set::skip_zeroing_test);
+    run!(passed, mm::memory_set::skip_zeroing_cost_test);
+    run!(passed, mm::memory_set::protect_range_test);
+    run!(passed, mm::memory_set::flush_tlb_test);
+    run!(passed, mm::memory_set::is_accessible_test);
+    run!(passed, mm::memory_set::remap_contiguous_test);
+    run!(passed, mm::memory_set::contiguous_area_test);
+    run!(passed, mm::memory_set::map_alias_test);
+    run!(passed, mm::memory_set::map_area_contains_test);
+    run!(passed, mm::memory_set::map_area_debug_test);
+    run!(passed, mm::memory_set::range_present_test);
+    run!(passed, mm::memory_set::insert_overlap_test);
+    run!(passed, mm::memory_set::first_conflict_test);
+    run!(passed, mm::memory_set::munmap_partial_test);
+    run!(passed, mm::memory_set::unmap_area_by_range_test);
+    run!(passed, mm::memory_set::new_test_space_test);
+    run!(passed, mm::memory_set::unmap_area_reclaim_test);
+    run!(passed, mm::memory_set::translate_va_test);
+    run!(passed, mm::memory_set::clone_full_shared_test);
+    run!(passed, mm::memory_set::cow_fault_test);
+    run!(passed, mm::memory_set::ad_bits_test);
+    run!(passed, mm::memory_set::map_out_of_frames_test);
+    run!(passed, mm::memory_set::clone_full_out_of_frames_test);
+    run!(passed, mm::memory_set::grow_area_test);
+    run!(passed, mm::memory_set::shared_text_test);
+    run!(passed, mm::memory_set::elf_lazy_load_test);
+    run!(passed, mm::memory_set::clone_kernel_test);
+    run!(passed, mm::memory_set::make_readonly_test);
+    run!(passed, mm::memory_set::equal_contents_test);
+    run!(passed, mm::memory_set::trap_context_ppn_test);
+    run!(passed, mm::memory_set::find_free_range_test);
+    run!(passed, mm::memory_set::drop_pages_test);
+    run!(passed, mm::memory_set::check_invariants_test);
+    run!(passed, mm::read_user_struct_test);
+    run!(passed, mm::write_user_struct_test);
+    // sync
+    run!(passed, sync::switch_watchdog_test);
+    // task ，此时还没有任务开始运行
+    run!(passed, task::stride_less_test);
+    run!(passed, task::task_status_test);
+    run!(passed, task::kernel_stack_limit_test);
+    run!(passed, task::find_next_task_test);
+    run!(passed, task::yield_to_test);
+    run!(passed, task::count_by_status_test);
+    run!(passed, task::final_exit_code_test);
+    run!(passed, task::fault_exit_test);
+    run!(passed, task::store_page_fault_test);
+    run!(passed, task::mark_started_test);
+    run!(passed, task::current_task_name_test);
+    run!(passed, task::task_context_snapshot_test);
+    run!(passed, task::backtrace_test);
+    run!(passed, task::lock_order_test);
+    run!(passed, task::load_apps_test);
+    run!(passed, task::restart_test);
+    run!(passed, task::page_fault_counter_test);
+    run!(passed, task::kernel_time_test);
+    run!(passed, task::yield_count_test);
+    run!(passed, task::switch_count_test);
+    run!(passed, task::mmap_wrap_test);
+    run!(passed, task::task_info_of_test);
+    run!(passed, task::inc_syscall_bounds_test);
+    run!(passed, task::mmap_anon_test);
+    run!(passed, task::syscall_times_saturate_test);
+    #[cfg(feature = "syscall_trace")]
+    {
+        run!(passed, task::syscall_trace_test);
+    }
+    // syscall ，作用于 0 号任务的地址空间，结束后恢复原状
+    run!(passed, syscall::unknown_syscall_test);
+    #[cfg(feature = "syscall_trace")]
+    {
+        run!(passed, syscall::syscall_trace_roundtrip_test);
+    }
+    run!(passed, syscall::process::get_time_us_test);
+    run!(passed, syscall::process::set_priority_test);
+    run!(passed, syscall::process::yield_to_test);
+    run!(passed, syscall::process::nanosleep_test);
+    run!(passed, syscall::process::trace_test);
+    run!(passed, syscall::process::task_meminfo_test);
+    run!(passed, syscall::process::mprotect_test);
+    run!(passed, syscall::process::madvise_test);
+    run!(passed, syscall::process::mmap_permission_test);
+    run!(passed, syscall::process::zero_length_mmap_test);
+    run!(passed, syscall::process::mmap_overlap_test);
+    run!(passed, syscall::process::munmap_round_len_test);
+    run!(passed, syscall::process::mmap_oom_test);
+    run!(passed, syscall::process::munmap_trap_context_test);
+    run!(passed, syscall::process::mmap_hugepage_test);
+    run!(passed, syscall::getcwd_test);
+    run!(passed, syscall::write_cross_page_test);
+    run!(passed, syscall::read_stdin_test);
+    info!("[selftest] all {} self tests passed", passed);
+}
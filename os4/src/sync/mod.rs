@@ -2,4 +2,8 @@
 
 mod up;
 
-pub use up::UPSafeCell;
+pub use up::{bump_switch_generation, UPRefMut, UPSafeCell};
+#[allow(unused)]
+pub use up::{switch_generation, HeldAcrossSwitch};
+#[cfg(feature = "self_test")]
+pub use up::switch_watchdog_test;
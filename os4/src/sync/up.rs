@@ -1,6 +1,24 @@
 //! Uniprocessor interior mutability primitives
 
-use core::cell::{RefCell, RefMut};
+use core::cell::{Cell, RefCell, RefMut};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bumped right before every `__switch`. On a single hart a borrow that is
+/// still alive in a newer generation was held across a task switch, which is
+/// always a bug: the next task will find the cell borrowed forever.
+static SWITCH_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// current switch generation
+pub fn switch_generation() -> usize {
+    SWITCH_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Start a new switch generation, call it right before `__switch`.
+pub fn bump_switch_generation() {
+    SWITCH_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
 
 /// Wrap a static data structure inside it so that we are
 /// able to access it without any `unsafe`.
@@ -12,6 +30,8 @@ use core::cell::{RefCell, RefMut};
 pub struct UPSafeCell<T> {
     /// inner data
     inner: RefCell<T>,
+    /// switch generation of the latest borrow
+    borrowed_at: Cell<usize>,
 }
 
 unsafe impl<T> Sync for UPSafeCell<T> {}
@@ -22,10 +42,118 @@ impl<T> UPSafeCell<T> {
     pub unsafe fn new(value: T) -> Self {
         Self {
             inner: RefCell::new(value),
+            borrowed_at: Cell::new(0),
+        }
+    }
+    /// Panic if the data has been borrowed. In debug builds the panic says
+    /// so if the earlier borrow was held across a `__switch`, and so does
+    /// dropping a borrow that was.
+    pub fn exclusive_access(&self) -> UPRefMut<'_, T> {
+        let inner = match self.inner.try_borrow_mut() {
+            Ok(inner) => inner,
+            Err(_) => self.borrow_conflict(),
+        };
+        let borrowed_at = switch_generation();
+        self.borrowed_at.set(borrowed_at);
+        UPRefMut { inner, borrowed_at }
+    }
+    fn borrow_conflict(&self) -> ! {
+        if cfg!(debug_assertions) {
+            let held = HeldAcrossSwitch {
+                borrowed_at: self.borrowed_at.get(),
+                now: switch_generation(),
+            };
+            if held.borrowed_at != held.now {
+                panic!("{}", held);
+            }
+        }
+        panic!("UPSafeCell already borrowed");
+    }
+}
+
+/// A borrow of a [`UPSafeCell`], remembering the switch generation it was
+/// taken in.
+pub struct UPRefMut<'a, T> {
+    inner: RefMut<'a, T>,
+    borrowed_at: usize,
+}
+
+impl<'a, T> UPRefMut<'a, T> {
+    /// Err if a `__switch` happened since the borrow was taken.
+    pub fn check_switch(&self) -> Result<(), HeldAcrossSwitch> {
+        let now = switch_generation();
+        if now == self.borrowed_at {
+            Ok(())
+        } else {
+            Err(HeldAcrossSwitch {
+                borrowed_at: self.borrowed_at,
+                now,
+            })
+        }
+    }
+}
+
+impl<'a, T> Deref for UPRefMut<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T> DerefMut for UPRefMut<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<'a, T> Drop for UPRefMut<'a, T> {
+    fn drop(&mut self) {
+        if cfg!(debug_assertions) {
+            if let Err(held) = self.check_switch() {
+                panic!("{}", held);
+            }
         }
     }
-    /// Panic if the data has been borrowed.
-    pub fn exclusive_access(&self) -> RefMut<'_, T> {
-        self.inner.borrow_mut()
+}
+
+/// a [`UPSafeCell`] borrow outlived a task switch
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct HeldAcrossSwitch {
+    /// switch generation the borrow was taken in
+    pub borrowed_at: usize,
+    /// switch generation when it was noticed
+    pub now: usize,
+}
+
+impl fmt::Display for HeldAcrossSwitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "UPSafeCell borrowed in switch generation {} is still held in {}, drop it before __switch",
+            self.borrowed_at, self.now
+        )
     }
 }
+
+#[allow(unused)]
+/// Hold a borrow across a simulated switch and check it is reported, while a
+/// borrow dropped before the switch is not.
+pub fn switch_watchdog_test() {
+    use alloc::format;
+    let cell = unsafe { UPSafeCell::new(0usize) };
+    let mut guard = cell.exclusive_access();
+    *guard += 1;
+    assert!(guard.check_switch().is_ok());
+    drop(guard);
+    bump_switch_generation();
+    let guard = cell.exclusive_access();
+    assert!(guard.check_switch().is_ok());
+    // 模拟 __switch 时忘记 drop 借用
+    bump_switch_generation();
+    let held = guard.check_switch().unwrap_err();
+    assert_eq!(held.now, held.borrowed_at + 1);
+    assert!(format!("{}", held).contains("before __switch"));
+    // 这个单元格只在测试里用，直接忘掉这次借用，免得 drop 时触发诊断
+    core::mem::forget(guard);
+    info!("switch_watchdog_test passed!");
+}
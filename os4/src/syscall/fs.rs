@@ -1,21 +1,201 @@
 //! File and filesystem-related syscalls
 
-use crate::mm::translated_byte_buffer;
-use crate::task::current_user_token;
+use crate::mm::{user_byte_buffer, user_byte_buffer_mut, write_user_struct};
+use crate::sbi::{console_getchar, console_putchar};
+use crate::task::{current_user_token, suspend_current_and_run_next};
 
+const FD_STDIN: usize = 0;
 const FD_STDOUT: usize = 1;
 
-pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+/// where `sys_read` takes its input from
+trait InputSource {
+    /// next byte if one is available right now
+    fn getchar(&mut self) -> Option<u8>;
+}
+
+/// the UART behind the SBI console
+struct SbiConsole;
+
+impl InputSource for SbiConsole {
+    fn getchar(&mut self) -> Option<u8> {
+        // 没有输入时 SBI 返回 -1
+        match console_getchar() as isize {
+            c if c < 0 => None,
+            c => Some(c as u8),
+        }
+    }
+}
+
+/// Read up to `len` bytes from stdin into `buf`. Waits, letting other tasks
+/// run, until at least one byte arrives, then returns the bytes available
+/// so far. Returns -1 for any other fd or if `buf` is not writable.
+pub fn sys_read(fd: usize, buf: *mut u8, len: usize) -> isize {
     match fd {
-        FD_STDOUT => {
-            let buffers = translated_byte_buffer(current_user_token(), buf, len);
-            for buffer in buffers {
-                print!("{}", core::str::from_utf8(buffer).unwrap());
+        FD_STDIN => read_user_bytes(buf, len, &mut SbiConsole, suspend_current_and_run_next),
+        _ => -1,
+    }
+}
+
+/// Fill the user buffer from `input`, calling `wait` while nothing has
+/// arrived yet. The buffer is checked before any input is taken.
+fn read_user_bytes(
+    buf: *mut u8,
+    len: usize,
+    input: &mut impl InputSource,
+    mut wait: impl FnMut(),
+) -> isize {
+    if len == 0 {
+        return 0;
+    }
+    let buffers = match user_byte_buffer_mut(current_user_token(), buf, len) {
+        Ok(buffers) => buffers,
+        Err(_) => return -1,
+    };
+    let first = loop {
+        if let Some(byte) = input.getchar() {
+            break byte;
+        }
+        wait();
+    };
+    let mut read = 0;
+    for buffer in buffers {
+        for slot in buffer.iter_mut() {
+            // 第一个字节之后不再等待，已有的输入读完就返回
+            let byte = if read == 0 { Some(first) } else { input.getchar() };
+            match byte {
+                Some(byte) => *slot = byte,
+                None => return read as isize,
             }
-            len as isize
+            read += 1;
         }
-        _ => {
-            panic!("Unsupported fd in sys_write!");
+    }
+    read as isize
+}
+
+/// Write `len` bytes at `buf` to stdout, the buffer may cross pages.
+/// Returns -1 for any other fd or if part of the buffer is not readable.
+pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
+    match fd {
+        FD_STDOUT => write_user_bytes(buf, len, |byte| console_putchar(byte as usize)),
+        _ => -1,
+    }
+}
+
+/// Hand every byte of the user buffer to `put`, nothing if a page is not
+/// readable. 逐字节输出，跨页拆开的 UTF-8 字符也能完整地到达终端
+fn write_user_bytes(buf: *const u8, len: usize, mut put: impl FnMut(u8)) -> isize {
+    let buffers = match user_byte_buffer(current_user_token(), buf, len) {
+        Ok(buffers) => buffers,
+        Err(_) => return -1,
+    };
+    for buffer in buffers {
+        buffer.iter().for_each(|byte| put(*byte));
+    }
+    len as isize
+}
+
+/// working directory reported by `sys_getcwd`, there is no file system yet
+const CWD: [u8; 2] = *b"/\0";
+
+/// Write the NUL-terminated working directory to `buf` and return its length
+/// including the NUL, -1 if `len` is too small or `buf` is not writable.
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
+    if len < CWD.len() {
+        return -1;
+    }
+    match write_user_struct(current_user_token(), buf as *mut [u8; 2], &CWD) {
+        Ok(()) => CWD.len() as isize,
+        Err(_) => -1,
+    }
+}
+
+#[allow(unused)]
+/// check `sys_getcwd` writes "/" into a large enough buffer only
+pub fn getcwd_test() {
+    use crate::config::PAGE_SIZE;
+    use crate::mm::read_user_struct;
+    use super::process::{sys_mmap, sys_munmap};
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    let buf = start as *mut u8;
+    assert_eq!(sys_getcwd(buf, 0), -1);
+    assert_eq!(sys_getcwd(buf, 1), -1);
+    assert_eq!(sys_getcwd(buf, 2), 2);
+    assert_eq!(read_user_struct(current_user_token(), buf as *const [u8; 2]), Ok(*b"/\0"));
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    // 没有映射的缓冲区返回 -1 而不是 panic
+    assert_eq!(sys_getcwd(buf, 2), -1);
+    info!("getcwd_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_write` copies every byte of a buffer crossing a page boundary
+/// and rejects unmapped buffers and unknown fds
+pub fn write_cross_page_test() {
+    use crate::config::PAGE_SIZE;
+    use alloc::vec::Vec;
+    use super::process::{sys_mmap, sys_munmap};
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, 2 * PAGE_SIZE, 0x3), 0);
+    let msg = b"[kernel] write_cross_page_test\n";
+    let buf = (start + PAGE_SIZE - 8) as *mut [u8; 31];
+    write_user_struct(current_user_token(), buf, msg).unwrap();
+    let mut written = Vec::new();
+    assert_eq!(write_user_bytes(buf as *const u8, msg.len(), |byte| written.push(byte)), 31);
+    assert_eq!(written.as_slice(), &msg[..]);
+    assert_eq!(sys_write(FD_STDOUT, buf as *const u8, msg.len()), 31);
+    assert_eq!(sys_write(2, buf as *const u8, msg.len()), -1);
+    // 第二页之后没有映射
+    let tail = (start + 2 * PAGE_SIZE - 8) as *const u8;
+    assert_eq!(sys_write(FD_STDOUT, tail, 16), -1);
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), 0);
+    info!("write_cross_page_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_read` waits for the first byte, returns partial reads and
+/// rejects a buffer it cannot write, using a fixed input instead of the UART
+pub fn read_stdin_test() {
+    use crate::config::PAGE_SIZE;
+    use crate::mm::read_user_struct;
+    use super::process::{sys_mmap, sys_munmap};
+    /// `idle` empty polls, then `data` one byte at a time
+    struct MockInput {
+        idle: usize,
+        data: &'static [u8],
+    }
+    impl InputSource for MockInput {
+        fn getchar(&mut self) -> Option<u8> {
+            if self.idle > 0 {
+                self.idle -= 1;
+                return None;
+            }
+            let (first, rest) = self.data.split_first()?;
+            self.data = rest;
+            Some(*first)
         }
     }
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, 2 * PAGE_SIZE, 0x3), 0);
+    let token = current_user_token();
+    // 缓冲区跨页
+    let buf = (start + PAGE_SIZE - 2) as *mut u8;
+    let mut input = MockInput { idle: 2, data: b"hello" };
+    let mut waits = 0;
+    assert_eq!(read_user_bytes(buf, 3, &mut input, || waits += 1), 3);
+    assert_eq!(waits, 2);
+    assert_eq!(read_user_struct(token, buf as *const [u8; 3]), Ok(*b"hel"));
+    // 输入不够 len 个字节时只返回已有的部分
+    assert_eq!(read_user_bytes(buf, 8, &mut input, || waits += 1), 2);
+    assert_eq!(waits, 2);
+    assert_eq!(read_user_struct(token, buf as *const [u8; 2]), Ok(*b"lo"));
+    assert_eq!(read_user_bytes(buf, 0, &mut input, || panic!("len 0 waited")), 0);
+    // 缓冲区不可写时不消耗输入
+    let mut input = MockInput { idle: 0, data: b"x" };
+    let tail = (start + 2 * PAGE_SIZE - 1) as *mut u8;
+    assert_eq!(read_user_bytes(tail, 2, &mut input, || ()), -1);
+    assert_eq!(input.data, b"x");
+    assert_eq!(sys_read(FD_STDOUT, buf, 1), -1);
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), 0);
+    info!("read_stdin_test passed!");
 }
@@ -10,36 +10,156 @@
 //! `sys_` then the name of the syscall. You can find functions like this in
 //! submodules, and you should also implement syscalls this way.
 
+const SYSCALL_GETCWD: usize = 17;
+const SYSCALL_READ: usize = 63;
 const SYSCALL_WRITE: usize = 64;
 const SYSCALL_EXIT: usize = 93;
 const SYSCALL_YIELD: usize = 124;
 const SYSCALL_GET_TIME: usize = 169;
 const SYSCALL_MUNMAP: usize = 215;
 const SYSCALL_MMAP: usize = 222;
+const SYSCALL_MPROTECT: usize = 226;
+const SYSCALL_MADVISE: usize = 233;
 const SYSCALL_SET_PRIORITY: usize = 140;
 const SYSCALL_TASK_INFO: usize = 410;
+const SYSCALL_YIELD_TO: usize = 412;
+const SYSCALL_GET_TIME_US: usize = 413;
+const SYSCALL_MEMINFO: usize = 415;
+const SYSCALL_SHUTDOWN: usize = 416;
+const SYSCALL_MMAP_HUGEPAGE: usize = 417;
+const SYSCALL_TASK_INFO_OF: usize = 418;
+const SYSCALL_NANOSLEEP: usize = 419;
+const SYSCALL_SWITCH_COUNT: usize = 420;
+const SYSCALL_TRACE: usize = 421;
+const SYSCALL_TASK_MEMINFO: usize = 422;
+#[cfg(feature = "syscall_trace")]
+const SYSCALL_SYSCALL_TRACE: usize = 411;
+#[cfg(feature = "task_context_debug")]
+const SYSCALL_DUMP_TASK_CONTEXT: usize = 414;
 
 mod fs;
 pub mod process;
 
 use fs::*;
 use process::*;
+#[cfg(feature = "self_test")]
+pub use fs::{getcwd_test, read_stdin_test, write_cross_page_test};
 
-use crate::task::inc_current_task_syscall;
+use crate::task::{inc_current_task_syscall, inc_current_unknown_syscall};
+#[cfg(feature = "syscall_trace")]
+use crate::task::SyscallRecord;
+
+/// a syscall implementation taking the raw arguments from the trap context
+type SyscallHandler = fn([usize; 3]) -> isize;
+
+/// Every supported syscall id and its handler. A new syscall only needs an
+/// entry here, counting is done by [`syscall()`] for all of them.
+static SYSCALL_TABLE: &[(usize, SyscallHandler)] = &[
+    (SYSCALL_GETCWD, |args| sys_getcwd(args[0] as *mut u8, args[1])),
+    (SYSCALL_READ, |args| sys_read(args[0], args[1] as *mut u8, args[2])),
+    (SYSCALL_WRITE, |args| sys_write(args[0], args[1] as *const u8, args[2])),
+    (SYSCALL_EXIT, |args| sys_exit(args[0] as i32)),
+    (SYSCALL_YIELD, |_| sys_yield()),
+    (SYSCALL_YIELD_TO, |args| sys_yield_to(args[0] as isize)),
+    (SYSCALL_GET_TIME, |args| sys_get_time(args[0] as *mut TimeVal, args[1])),
+    (SYSCALL_GET_TIME_US, |_| sys_get_time_us()),
+    (SYSCALL_NANOSLEEP, |args| sys_nanosleep(args[0] as *mut TimeVal)),
+    (SYSCALL_MMAP, |args| sys_mmap(args[0], args[1], args[2])),
+    (SYSCALL_MMAP_HUGEPAGE, |args| sys_mmap_hugepage(args[0], args[1])),
+    (SYSCALL_MUNMAP, |args| sys_munmap(args[0], args[1])),
+    (SYSCALL_MPROTECT, |args| sys_mprotect(args[0], args[1], args[2])),
+    (SYSCALL_MADVISE, |args| sys_madvise(args[0], args[1], args[2])),
+    (SYSCALL_SET_PRIORITY, |args| sys_set_priority(args[0] as isize)),
+    (SYSCALL_TASK_INFO, |args| sys_task_info(args[0] as *mut TaskInfo)),
+    (SYSCALL_TASK_INFO_OF, |args| sys_task_info_of(args[0], args[1] as *mut TaskInfo)),
+    (SYSCALL_MEMINFO, |args| sys_meminfo(args[0] as *mut MemInfo)),
+    (SYSCALL_TASK_MEMINFO, |args| sys_task_meminfo(args[0], args[1] as *mut TaskMemInfo)),
+    (SYSCALL_SHUTDOWN, |_| sys_shutdown()),
+    (SYSCALL_SWITCH_COUNT, |args| sys_switch_count(args[0] as isize)),
+    (SYSCALL_TRACE, |args| sys_trace(args[0], args[1], args[2])),
+    #[cfg(feature = "syscall_trace")]
+    (SYSCALL_SYSCALL_TRACE, |args| {
+        sys_syscall_trace(args[0] as *mut SyscallRecord, args[1])
+    }),
+    #[cfg(feature = "task_context_debug")]
+    (SYSCALL_DUMP_TASK_CONTEXT, |_| sys_dump_task_context()),
+];
+
+/// handler registered for `syscall_id` in [`SYSCALL_TABLE`]
+fn find_handler(syscall_id: usize) -> Option<SyscallHandler> {
+    SYSCALL_TABLE
+        .iter()
+        .find(|(id, _)| *id == syscall_id)
+        .map(|(_, handler)| *handler)
+}
 
 /// handle syscall exception with `syscall_id` and other arguments
+///
+/// An id without a handler returns -1 and is counted in the task's unknown
+/// syscall bucket instead of `syscall_times`.
 pub fn syscall(syscall_id: usize, args: [usize; 3]) -> isize {
     // LAB1: You may need to update syscall info here.
-    inc_current_task_syscall(syscall_id);
-    match syscall_id {
-        SYSCALL_WRITE => sys_write(args[0], args[1] as *const u8, args[2]),
-        SYSCALL_EXIT => sys_exit(args[0] as i32),
-        SYSCALL_YIELD => sys_yield(),
-        SYSCALL_GET_TIME => sys_get_time(args[0] as *mut TimeVal, args[1]),
-        SYSCALL_MMAP => sys_mmap(args[0], args[1], args[2]),
-        SYSCALL_MUNMAP => sys_munmap(args[0], args[1]),
-        SYSCALL_SET_PRIORITY => sys_set_priority(args[0] as isize),
-        SYSCALL_TASK_INFO => sys_task_info(args[0] as *mut TaskInfo),
-        _ => panic!("Unsupported syscall_id: {}", syscall_id),
+    match find_handler(syscall_id) {
+        Some(handler) => {
+            inc_current_task_syscall(syscall_id);
+            handler(args)
+        }
+        None => {
+            inc_current_unknown_syscall();
+            -1
+        }
+    }
+}
+
+#[allow(unused)]
+/// check an unknown syscall id returns -1 and lands in the unknown bucket
+pub fn unknown_syscall_test() {
+    use crate::task::{current_unknown_syscalls, get_current_task_info};
+    let unknown = current_unknown_syscalls();
+    let syscall_times = get_current_task_info().syscall_times;
+    // 1 号在 syscall_times 范围内但没有实现，499 是最后一个计数位置
+    for syscall_id in [1, 499, 9999] {
+        assert!(find_handler(syscall_id).is_none());
+        assert_eq!(syscall(syscall_id, [0; 3]), -1);
+    }
+    assert_eq!(current_unknown_syscalls(), unknown + 3);
+    assert!(get_current_task_info().syscall_times == syscall_times);
+    assert!(find_handler(SYSCALL_GET_TIME_US).is_some());
+    info!("unknown_syscall_test passed!");
+}
+
+#[cfg(feature = "syscall_trace")]
+#[allow(unused)]
+/// make a known sequence of syscalls through `syscall()` and read it back
+/// with `sys_syscall_trace`
+pub fn syscall_trace_roundtrip_test() {
+    use crate::config::PAGE_SIZE;
+    use crate::mm::read_user_struct;
+    use crate::task::current_user_token;
+    let start = 0x1000_0000;
+    assert_eq!(syscall(SYSCALL_MMAP, [start, PAGE_SIZE, 0x3]), 0);
+    syscall(SYSCALL_GET_TIME_US, [0; 3]);
+    // 参数非法的调用同样会被记录
+    assert_eq!(syscall(SYSCALL_SET_PRIORITY, [0; 3]), -1);
+    syscall(SYSCALL_GET_TIME_US, [0; 3]);
+    // sys_syscall_trace 自己在执行前就已经被记录
+    assert_eq!(syscall(SYSCALL_SYSCALL_TRACE, [start, 5, 0]), 5);
+    let records = read_user_struct(current_user_token(), start as *const [SyscallRecord; 5]).unwrap();
+    let ids = [
+        SYSCALL_MMAP,
+        SYSCALL_GET_TIME_US,
+        SYSCALL_SET_PRIORITY,
+        SYSCALL_GET_TIME_US,
+        SYSCALL_SYSCALL_TRACE,
+    ];
+    for (record, id) in records.iter().zip(ids.iter()) {
+        assert_eq!(record.id, *id);
     }
+    assert!(records.windows(2).all(|w| w[0].time_us <= w[1].time_us));
+    // 没有映射或者只读的缓冲区返回 -1，而不是让内核 panic
+    assert_eq!(sys_syscall_trace(core::ptr::null_mut(), 2), -1);
+    assert_eq!(sys_mprotect(start, PAGE_SIZE, 0x1), 0);
+    assert_eq!(sys_syscall_trace(start as *mut SyscallRecord, 2), -1);
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    info!("syscall_trace_roundtrip_test passed!");
 }
@@ -1,70 +1,163 @@
 //! Process management syscalls
 
-use crate::config::{MAX_SYSCALL_NUM, PAGE_SIZE, KERNEL_STACK_SIZE, MEMORY_END};
+use crate::config::{MAX_SYSCALL_NUM, HUGE_PAGE_SIZE, MMAP_TOP, PAGE_SIZE, KERNEL_STACK_SIZE, MEMORY_END, TRAP_CONTEXT};
 use crate::mm::memory_set::{MapArea, MapType, self, MemorySet};
-use crate::mm::{VirtAddr, PhysAddr, MapPermission};
-use crate::task::{exit_current_and_run_next, suspend_current_and_run_next, TaskStatus, current_user_token, get_current_task_info, kernel_sys_mmap, kernel_sys_munmap};
+use crate::mm::{frame_usage, frames_available, memory_end, read_user_struct, write_user_struct, VirtAddr, MapPermission};
+use crate::task::{current_priority, current_task_name, set_current_priority, exit_current_and_run_next, suspend_current_and_run_next, suspend_current_and_yield_to, TaskStatus, current_user_token, get_current_task_info, get_task_info, switch_count, task_memory_usage, kernel_sys_madvise_dontneed, kernel_sys_make_readonly, kernel_sys_mmap, kernel_sys_mmap_hugepage, kernel_sys_munmap};
+use crate::sbi::shutdown;
 use crate::timer::get_time_us;
-use crate::mm::page_table::PageTable;
+#[cfg(feature = "syscall_trace")]
+use crate::mm::user_byte_buffer_mut;
+#[cfg(feature = "syscall_trace")]
+use crate::task::{get_current_syscall_trace, SyscallRecord};
+#[cfg(feature = "task_context_debug")]
+use crate::task::current_task_context_snapshot;
 
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct TimeVal {
     pub sec: usize,
     pub usec: usize,
 }
 
+/// physical memory usage reported by `sys_meminfo`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    /// end of physical memory detected at boot
+    pub memory_end: usize,
+    /// frames currently allocated
+    pub allocated_frames: usize,
+    /// most frames ever allocated at the same time
+    pub peak_frames: usize,
+}
+
+/// memory usage of one task reported by `sys_task_meminfo`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TaskMemInfo {
+    /// pages backed by a frame of the task
+    pub resident_pages: usize,
+    /// pages reserved by the areas of the task, mapped or not
+    pub virtual_pages: usize,
+}
+
 #[derive(Debug,Clone, Copy)]
 pub struct TaskInfo {
     pub status: TaskStatus,
     pub syscall_times: [u32; MAX_SYSCALL_NUM],
     pub time: usize,
+    /// page faults the trap handler has serviced for the task, appended last
+    /// so the layout of the older fields does not change
+    pub page_faults: usize,
+    /// microseconds the task spent in user mode and in the kernel
+    pub user_time: usize,
+    pub kernel_time: usize,
+    /// times the task was suspended, by `sys_yield` or preemption. Appended
+    /// after `kernel_time`, so the struct grows by 8 bytes and every earlier
+    /// field keeps its offset
+    pub yields: usize,
 }
 
 pub fn sys_exit(exit_code: i32) -> ! {
-    info!("[kernel] Application exited with code {}", exit_code);
-    exit_current_and_run_next();
+    info!("[kernel] Application {} exited with code {}", current_task_name(), exit_code);
+    exit_current_and_run_next(exit_code);
     panic!("Unreachable in sys_exit!");
 }
 
+/// Power off the machine right away, reporting success, for test harnesses
+/// that decide on their own when everything is done.
+pub fn sys_shutdown() -> ! {
+    info!("[kernel] Application {} shut down the kernel", current_task_name());
+    shutdown(0)
+}
+
 /// current task gives up resources for other tasks
 pub fn sys_yield() -> isize {
     suspend_current_and_run_next();
     0
 }
 
+/// current task gives up resources and asks for task `pid` to run next,
+/// returns -1 if `pid` does not name a task
+pub fn sys_yield_to(pid: isize) -> isize {
+    if pid < 0 || !suspend_current_and_yield_to(pid as usize) {
+        return -1;
+    }
+    0
+}
+
 // YOUR JOB: 引入虚地址后重写 sys_get_time
 pub fn sys_get_time(ts: *mut TimeVal, _tz: usize) -> isize {
-    // ts to ppa
-    let user_token = current_user_token();
-    let page_table = PageTable::from_token(user_token);
-    let ptr = ts  as usize;
-    let va = VirtAddr::from(ptr);
-    // 第一次的时候漏掉了
-    let page_offset = va.page_offset();
-    let vpn = va.floor();
-    let ppn = page_table.translate(vpn).unwrap().ppn();
-    let pa = PhysAddr::from(PhysAddr::from(ppn).0 | page_offset);
     let us = get_time_us();
-    let sec = us / 1_000_000;
-    let usec = us % 1_000_000;
-    // 向物理地址写数据
-    let time_val = pa.0 as *mut TimeVal;
-    unsafe{
-        *time_val = TimeVal {
-            sec,
-            usec,
-        };
+    let time_val = TimeVal {
+        sec: us / 1_000_000,
+        usec: us % 1_000_000,
+    };
+    // TimeVal 可能跨页，由 write_user_struct 负责检查和拆分
+    match write_user_struct(current_user_token(), ts, &time_val) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Sleep for the `sec`/`usec` duration at `req`, yielding the CPU until it
+/// has passed, then write the time left to `req`. Nothing wakes a sleeping
+/// task early yet, so that is always zero. -1 if `req` is not accessible or
+/// `usec` is not below one second.
+pub fn sys_nanosleep(req: *mut TimeVal) -> isize {
+    sleep_for(req, suspend_current_and_run_next)
+}
+
+/// `sys_nanosleep`, calling `wait` while the deadline has not passed
+fn sleep_for(req: *mut TimeVal, mut wait: impl FnMut()) -> isize {
+    let token = current_user_token();
+    let duration = match read_user_struct(token, req as *const TimeVal) {
+        Ok(duration) => duration,
+        Err(_) => return -1,
+    };
+    if duration.usec >= 1_000_000 {
+        return -1;
+    }
+    let us = duration
+        .sec
+        .saturating_mul(1_000_000)
+        .saturating_add(duration.usec);
+    let deadline = get_time_us().saturating_add(us);
+    while get_time_us() < deadline {
+        wait();
+    }
+    let left = deadline.saturating_sub(get_time_us());
+    let remaining = TimeVal {
+        sec: left / 1_000_000,
+        usec: left % 1_000_000,
+    };
+    match write_user_struct(token, req, &remaining) {
+        Ok(()) => 0,
+        Err(_) => -1,
     }
-    0
+}
+
+/// microseconds since boot as the return value, saturating at `isize::MAX`
+pub fn sys_get_time_us() -> isize {
+    get_time_us().min(isize::MAX as usize) as isize
 }
 
 // CLUE: 从 ch4 开始不再对调度算法进行测试~
+/// Set the priority of the current task, returns it or -1 if `_prio < 2`.
 pub fn sys_set_priority(_prio: isize) -> isize {
-    -1
+    if _prio < 2 {
+        return -1;
+    }
+    set_current_priority(_prio);
+    _prio
 }
 
 // YOUR JOB: 扩展内核以实现 sys_mmap 和 sys_munmap
+/// Map `_len` bytes at `_start`. A fixed `_start` returns 0 on success as
+/// before, `_start == 0` lets the kernel pick a free range and returns its
+/// address. `_len == 0` maps nothing and always succeeds with 0, like
+/// `sys_munmap`.
 pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
     if _len == 0{
         return 0;
@@ -72,28 +165,57 @@ pub fn sys_mmap(_start: usize, _len: usize, _port: usize) -> isize {
     if _start > 268439552 || _start % PAGE_SIZE != 0{
         return  -1;
     }
-    if _port &!0x7 != 0 || _port &0x7 == 0{
-        return -1;
-    }
-    let mut permission = MapPermission::U;
-    if _port & 1 == 1{
-        permission  |= MapPermission::R;
+    let permission = match MapPermission::from_port(_port) {
+        Some(permission) => permission,
+        None => return -1,
+    };
+    match kernel_sys_mmap(_start,_len,permission) {
+        // println!("mmap _start:{}, _len:{},result:{}",_start, _len, -1);
+        None => -1,
+        // 由内核挑选地址时把它返回给用户
+        Some(start) if _start == 0 => start as isize,
+        // println!("mmap _start:{}, _len:{},result:{}",_start, _len, 0);
+        Some(_) => 0,
     }
-    if _port & 2 == 2{
-        permission  |= MapPermission::W;
+}
+
+/// Map `HUGE_PAGE_SIZE` bytes at `start` like `sys_mmap`, on physically
+/// contiguous frames if the allocator still has a free run that long.
+/// `start == 0` lets the kernel pick the address and returns it.
+pub fn sys_mmap_hugepage(start: usize, port: usize) -> isize {
+    if start % PAGE_SIZE != 0 {
+        return -1;
     }
-    if _port & 4 == 4{
-        permission  |= MapPermission::X;
+    match start.checked_add(HUGE_PAGE_SIZE) {
+        Some(end) if end <= MMAP_TOP => {}
+        _ => return -1,
     }
-    if !kernel_sys_mmap(_start,_len,permission){
-        // println!("mmap _start:{}, _len:{},result:{}",_start, _len, -1);
-        return -1;
+    let permission = match MapPermission::from_port(port) {
+        Some(permission) => permission,
+        None => return -1,
+    };
+    match kernel_sys_mmap_hugepage(start, permission) {
+        None => -1,
+        Some(start) => start as isize,
     }
-    // println!("mmap _start:{}, _len:{},result:{}",_start, _len, 0);
-    0
 }
 
+/// Unmap `_len` bytes at `_start`. `_len == 0` unmaps nothing and always
+/// succeeds with 0, like `sys_mmap`. Like `sys_mmap`, `_start` must be page
+/// aligned and `_len` is rounded up to whole pages. Ranges reaching the trap
+/// context or the trampoline are refused.
 pub fn sys_munmap(_start: usize, _len: usize) -> isize {
+    if _len == 0 {
+        return 0;
+    }
+    if _start % PAGE_SIZE != 0 {
+        return -1;
+    }
+    // Trap 上下文和跳板页是陷入内核时切换地址空间必需的，不允许用户解除映射
+    match _start.checked_add(_len) {
+        Some(end) if end <= TRAP_CONTEXT => {}
+        _ => return -1,
+    }
     // if _len % PAGE_SIZE != 0{
     //     println!("munmap _start:{}, _len:{} % PAGE_SIZE != 0, result:{} ",VirtAddr::from(_start).floor().0, _len,-1);
     //     return  -1;
@@ -107,25 +229,457 @@ pub fn sys_munmap(_start: usize, _len: usize) -> isize {
     kernel_sys_munmap(_start,_len)
 }
 
+/// `advice` of `sys_madvise`: the range will not be needed for a while
+const MADV_DONTNEED: usize = 4;
+
+/// Give the kernel a hint about `[start, start + len)`. Only `MADV_DONTNEED`
+/// is supported: the backing frames are freed and the next access reads zeroes.
+pub fn sys_madvise(start: usize, len: usize, advice: usize) -> isize {
+    if advice != MADV_DONTNEED || start % PAGE_SIZE != 0 {
+        return -1;
+    }
+    if len == 0 {
+        return 0;
+    }
+    match start.checked_add(len) {
+        Some(end) if end <= TRAP_CONTEXT => {}
+        _ => return -1,
+    }
+    if !kernel_sys_madvise_dontneed(start, len) {
+        return -1;
+    }
+    0
+}
+
+/// Change the protection of `[start, start + len)` to `port`. Only making a
+/// range read-only is supported, `port` must be 1; execute permission of the
+/// pages is kept. -1 if part of the range is not mapped.
+pub fn sys_mprotect(start: usize, len: usize, port: usize) -> isize {
+    if port != 0x1 || start % PAGE_SIZE != 0 {
+        return -1;
+    }
+    if len == 0 {
+        return 0;
+    }
+    match start.checked_add(len) {
+        Some(end) if end <= TRAP_CONTEXT => {}
+        _ => return -1,
+    }
+    if !kernel_sys_make_readonly(start, len) {
+        return -1;
+    }
+    0
+}
+
 // YOUR JOB: 引入虚地址后重写 sys_task_info
 pub fn sys_task_info(ti: *mut TaskInfo) -> isize {
-    let user_token = current_user_token();
-    let page_table = PageTable::from_token(user_token);
-    let ptr = ti  as usize;
-    let va = VirtAddr::from(ptr);
-    let page_offset = va.page_offset();
-    let vpn = va.floor();
-    let ppn = page_table.translate(vpn).unwrap().ppn();
-    let pa = PhysAddr::from(PhysAddr::from(ppn).0 | page_offset);
-    let current_task = get_current_task_info();
-    // 向物理地址写数据
-    let task_info = pa.0 as *mut TaskInfo;
-    unsafe{
-        *task_info = TaskInfo {
-            status: current_task.status,
-            syscall_times: current_task.syscall_times,
-            time: (get_time_us() - current_task.time)/1_000,
-        };
+    write_task_info(get_current_task_info(), ti)
+}
+
+/// write the info of task `pid` to `ti`, -1 if there is no such task
+pub fn sys_task_info_of(pid: usize, ti: *mut TaskInfo) -> isize {
+    match get_task_info(pid) {
+        Some(task_info) => write_task_info(task_info, ti),
+        None => -1,
+    }
+}
+
+/// copy `task` out to `ti` in the current address space, with `time` turned
+/// from the start timestamp into milliseconds since the task started
+fn write_task_info(task: TaskInfo, ti: *mut TaskInfo) -> isize {
+    let task_info = TaskInfo {
+        time: (get_time_us() - task.time) / 1_000,
+        ..task
+    };
+    match write_user_struct(current_user_token(), ti, &task_info) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// write the current physical memory usage to `info`
+pub fn sys_meminfo(info: *mut MemInfo) -> isize {
+    let (allocated_frames, peak_frames) = frame_usage();
+    let mem_info = MemInfo {
+        memory_end: memory_end(),
+        allocated_frames,
+        peak_frames,
+    };
+    match write_user_struct(current_user_token(), info, &mem_info) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Write the memory usage of task `pid` to `info`, -1 if there is no such
+/// task or `info` is not writable.
+pub fn sys_task_meminfo(pid: usize, info: *mut TaskMemInfo) -> isize {
+    let (resident_pages, virtual_pages) = match task_memory_usage(pid) {
+        Some(usage) => usage,
+        None => return -1,
+    };
+    let task_mem_info = TaskMemInfo {
+        resident_pages,
+        virtual_pages,
+    };
+    match write_user_struct(current_user_token(), info, &task_mem_info) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Number of task switches so far, in total for a negative `pid`, otherwise
+/// into task `pid`. -1 if there is no such task.
+pub fn sys_switch_count(pid: isize) -> isize {
+    let (total, task) = match switch_count(pid.max(0) as usize) {
+        Some(counts) => counts,
+        None => return -1,
+    };
+    if pid < 0 {
+        total as isize
+    } else {
+        task as isize
     }
+}
+
+/// `sys_trace` request: read the byte at `id`
+const TRACE_READ: usize = 0;
+/// `sys_trace` request: write the low byte of `data` to `id`
+const TRACE_WRITE: usize = 1;
+/// `sys_trace` request: times the current task called syscall `id`
+const TRACE_SYSCALL_COUNT: usize = 2;
+
+/// Debugger-style access to the current task. Reading returns the byte,
+/// writing returns 0, both need the page to be user readable or writable.
+/// -1 for an unknown request, a bad address or syscall id.
+pub fn sys_trace(request: usize, id: usize, data: usize) -> isize {
+    // 页表只看低 39 位，超出用户地址空间的地址会被当成别的页面
+    if request != TRACE_SYSCALL_COUNT && id >= MMAP_TOP {
+        return -1;
+    }
+    let token = current_user_token();
+    match request {
+        TRACE_READ => read_user_struct(token, id as *const u8).map_or(-1, |byte| byte as isize),
+        TRACE_WRITE => write_user_struct(token, id as *mut u8, &(data as u8)).map_or(-1, |_| 0),
+        TRACE_SYSCALL_COUNT => get_current_task_info()
+            .syscall_times
+            .get(id)
+            .map_or(-1, |times| *times as isize),
+        _ => -1,
+    }
+}
+
+#[cfg(feature = "task_context_debug")]
+/// print the saved `TaskContext` of the current task
+pub fn sys_dump_task_context() -> isize {
+    println!("[kernel] {:?}", current_task_context_snapshot());
     0
 }
+
+#[cfg(feature = "syscall_trace")]
+/// copy the last `n` syscalls of the current task to `buf`, oldest first,
+/// returns how many records were copied, -1 if `buf` is not writable
+pub fn sys_syscall_trace(buf: *mut SyscallRecord, n: usize) -> isize {
+    let records = get_current_syscall_trace(n);
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            records.as_ptr() as *const u8,
+            records.len() * core::mem::size_of::<SyscallRecord>(),
+        )
+    };
+    let buffers = match user_byte_buffer_mut(current_user_token(), buf as *mut u8, bytes.len()) {
+        Ok(buffers) => buffers,
+        Err(_) => return -1,
+    };
+    let mut start = 0;
+    for buffer in buffers {
+        buffer.copy_from_slice(&bytes[start..start + buffer.len()]);
+        start += buffer.len();
+    }
+    records.len() as isize
+}
+
+#[allow(unused)]
+/// a simple test for `sys_get_time_us`
+pub fn get_time_us_test() {
+    let first = sys_get_time_us();
+    let second = sys_get_time_us();
+    assert!(first >= 0);
+    assert!(second >= first);
+    info!("get_time_us_test passed!");
+}
+
+#[allow(unused)]
+/// check every page mapped for `sys_mmap` is accessible from U mode
+pub fn mmap_permission_test() {
+    use crate::mm::page_table::PTEFlags;
+    for port in 1..8 {
+        let permission = MapPermission::from_port(port).unwrap();
+        let mut memory_set = MemorySet::new_bare();
+        memory_set.insert_framed_area(
+            VirtAddr::from(0x1000_0000),
+            VirtAddr::from(0x1000_2000),
+            permission,
+        ).unwrap();
+        for vpn in [VirtAddr::from(0x1000_0000).floor(), VirtAddr::from(0x1000_1000).floor()] {
+            let flags = memory_set.translate(vpn).unwrap().flags();
+            assert!(flags.contains(PTEFlags::V | PTEFlags::U));
+            assert_eq!(flags.contains(PTEFlags::R), port & 1 == 1);
+        }
+    }
+    info!("mmap_permission_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_mmap` and `sys_munmap` both treat `len == 0` as a successful no-op
+pub fn zero_length_mmap_test() {
+    for start in [0, 0x1000_0000, 0x1000_0123] {
+        assert_eq!(sys_mmap(start, 0, 0x3), 0);
+        assert_eq!(sys_munmap(start, 0), 0);
+    }
+    // 长度为 0 时其它参数不再检查
+    assert_eq!(sys_mmap(0x1000_0000, 0, 0), 0);
+    info!("zero_length_mmap_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_mmap` on the current task rejects a range overlapping an
+/// earlier mapping, then unmaps it again
+pub fn mmap_overlap_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, 2 * PAGE_SIZE, 0x3), 0);
+    assert_eq!(sys_mmap(start + PAGE_SIZE, 2 * PAGE_SIZE, 0x3), -1);
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x1), -1);
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), 0);
+    // 解除映射后同一段地址可以再次映射
+    assert_eq!(sys_mmap(start + PAGE_SIZE, 2 * PAGE_SIZE, 0x3), 0);
+    assert_eq!(sys_munmap(start + PAGE_SIZE, 2 * PAGE_SIZE), 0);
+    info!("mmap_overlap_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_munmap` rounds `len` up to a page and rejects an unaligned start
+pub fn munmap_round_len_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, 2 * PAGE_SIZE, 0x3), 0);
+    assert_eq!(sys_munmap(start + 0x10, 2 * PAGE_SIZE), -1);
+    assert_eq!(sys_munmap(start + PAGE_SIZE - 1, PAGE_SIZE + 1), -1);
+    // PAGE_SIZE + 1 个字节向上取整为两页，正好是映射的范围
+    assert_eq!(sys_munmap(start, PAGE_SIZE + 1), 0);
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), -1);
+    info!("munmap_round_len_test passed!");
+}
+
+#[allow(unused)]
+/// check an mmap needing more frames than are free fails up front without
+/// allocating anything
+pub fn mmap_oom_test() {
+    let start = 0x1000_0000;
+    let available = frames_available();
+    let (allocated, _) = frame_usage();
+    assert_eq!(sys_mmap(start, (available + 1) * PAGE_SIZE, 0x3), -1);
+    assert_eq!(frames_available(), available);
+    assert_eq!(frame_usage().0, allocated);
+    // 没有留下逻辑段，同一地址仍然可以正常映射
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    info!("mmap_oom_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_munmap` refuses to unmap the trap context and the trampoline
+pub fn munmap_trap_context_test() {
+    use crate::config::TRAMPOLINE;
+    use crate::mm::page_table::PageTable;
+    assert_eq!(sys_munmap(TRAP_CONTEXT, PAGE_SIZE), -1);
+    assert_eq!(sys_munmap(TRAMPOLINE, PAGE_SIZE), -1);
+    assert_eq!(sys_munmap(TRAP_CONTEXT - PAGE_SIZE, 2 * PAGE_SIZE), -1);
+    assert_eq!(sys_munmap(TRAP_CONTEXT, usize::MAX), -1);
+    // 两个页面仍然映射着，当前任务可以正常陷入和返回
+    let page_table = PageTable::from_token(current_user_token());
+    for va in [TRAP_CONTEXT, TRAMPOLINE] {
+        let pte = page_table.translate(VirtAddr::from(va).floor());
+        assert!(pte.map_or(false, |pte| pte.is_valid()));
+    }
+    info!("munmap_trap_context_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_mmap_hugepage` maps 512 pages on consecutive frames
+pub fn mmap_hugepage_test() {
+    use crate::mm::page_table::PageTable;
+    use crate::mm::VirtPageNum;
+    use alloc::vec::Vec;
+    let start = sys_mmap_hugepage(0, 0x3);
+    assert!(start > 0);
+    let page_table = PageTable::from_token(current_user_token());
+    let first = VirtAddr::from(start as usize).floor();
+    let ppns: Vec<usize> = (0..512)
+        .map(|i| page_table.translate(VirtPageNum(first.0 + i)).unwrap().ppn().0)
+        .collect();
+    assert!(ppns.windows(2).all(|w| w[1] == w[0] + 1));
+    // 与已有映射重叠时失败
+    assert_eq!(sys_mmap_hugepage(start as usize, 0x3), -1);
+    assert_eq!(sys_mmap_hugepage(start as usize + 1, 0x3), -1);
+    // MMAP_TOP 以上的地址会与 Trap 上下文和跳板页共用页表项
+    assert_eq!(sys_mmap_hugepage(MMAP_TOP, 0x3), -1);
+    assert_eq!(sys_mmap_hugepage(0xff_ffe0_0000, 0x3), -1);
+    assert_eq!(sys_mmap_hugepage(MMAP_TOP - HUGE_PAGE_SIZE + PAGE_SIZE, 0x3), -1);
+    assert_eq!(sys_munmap(start as usize, HUGE_PAGE_SIZE), 0);
+    info!("mmap_hugepage_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_set_priority` accepts `[2, isize::MAX]` and stores it
+pub fn set_priority_test() {
+    let saved = current_priority();
+    assert_eq!(sys_set_priority(2), 2);
+    assert_eq!(current_priority(), 2);
+    assert_eq!(sys_set_priority(1), -1);
+    assert_eq!(sys_set_priority(0), -1);
+    assert_eq!(sys_set_priority(-5), -1);
+    // 非法的值不会改掉已经设置的优先级
+    assert_eq!(current_priority(), 2);
+    assert_eq!(sys_set_priority(isize::MAX), isize::MAX);
+    assert_eq!(current_priority(), isize::MAX);
+    set_current_priority(saved);
+    info!("set_priority_test passed!");
+}
+
+#[allow(unused)]
+/// sleep 2ms through a `TimeVal` in user memory and check the time really
+/// passed and the remaining time written back is zero
+pub fn nanosleep_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    let token = current_user_token();
+    let req = start as *mut TimeVal;
+    write_user_struct(token, req, &TimeVal { sec: 0, usec: 2_000 }).unwrap();
+    let mut waits = 0;
+    let before = get_time_us();
+    assert_eq!(sleep_for(req, || waits += 1), 0);
+    let elapsed = get_time_us() - before;
+    assert!(elapsed >= 2_000, "slept only {}us", elapsed);
+    assert!(waits > 0);
+    let remaining = read_user_struct(token, req as *const TimeVal).unwrap();
+    assert_eq!((remaining.sec, remaining.usec), (0, 0));
+    // usec 必须小于一秒
+    write_user_struct(token, req, &TimeVal { sec: 0, usec: 1_000_000 }).unwrap();
+    assert_eq!(sleep_for(req, || panic!("invalid duration waited")), -1);
+    // 0 时长立即返回
+    write_user_struct(token, req, &TimeVal { sec: 0, usec: 0 }).unwrap();
+    assert_eq!(sleep_for(req, || panic!("zero duration waited")), 0);
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    assert_eq!(sys_nanosleep(req), -1);
+    info!("nanosleep_test passed!");
+}
+
+#[allow(unused)]
+/// check every `sys_trace` request, and that bad addresses and ids give -1
+pub fn trace_test() {
+    use super::{syscall, SYSCALL_GET_TIME_US};
+    let start = 0x1000_0000;
+    let read_only = start + PAGE_SIZE;
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    assert_eq!(sys_mmap(read_only, PAGE_SIZE, 0x1), 0);
+    assert_eq!(sys_trace(TRACE_WRITE, start + 5, 0x1a5), 0);
+    assert_eq!(sys_trace(TRACE_READ, start + 5, 0), 0xa5);
+    // 只读页面可以读不能写
+    assert_eq!(sys_trace(TRACE_READ, read_only, 0), 0);
+    assert_eq!(sys_trace(TRACE_WRITE, read_only, 1), -1);
+    assert_eq!(sys_trace(TRACE_READ, read_only, 0), 0);
+    // 没有映射的页面、超出用户地址空间的地址
+    for va in [start + 2 * PAGE_SIZE, MMAP_TOP, MMAP_TOP + start, usize::MAX] {
+        assert_eq!(sys_trace(TRACE_READ, va, 0), -1);
+        assert_eq!(sys_trace(TRACE_WRITE, va, 0), -1);
+    }
+    let times = sys_trace(TRACE_SYSCALL_COUNT, SYSCALL_GET_TIME_US, 0);
+    assert!(times >= 0);
+    syscall(SYSCALL_GET_TIME_US, [0; 3]);
+    assert_eq!(sys_trace(TRACE_SYSCALL_COUNT, SYSCALL_GET_TIME_US, 0), times + 1);
+    assert_eq!(sys_trace(TRACE_SYSCALL_COUNT, MAX_SYSCALL_NUM, 0), -1);
+    assert_eq!(sys_trace(3, start, 0), -1);
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), 0);
+    assert_eq!(sys_trace(TRACE_READ, start + 5, 0), -1);
+    info!("trace_test passed!");
+}
+
+#[allow(unused)]
+/// check the resident and virtual page counts of the current task follow
+/// its mmaps, and a bad pid or buffer gives -1
+pub fn task_meminfo_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    let token = current_user_token();
+    let info = start as *mut TaskMemInfo;
+    let read = || {
+        assert_eq!(sys_task_meminfo(0, info), 0);
+        read_user_struct(token, info as *const TaskMemInfo).unwrap()
+    };
+    let before = read();
+    assert!(before.resident_pages > 0);
+    assert!(before.virtual_pages >= before.resident_pages);
+    assert_eq!(sys_mmap(start + PAGE_SIZE, PAGE_SIZE, 0x3), 0);
+    let after = read();
+    assert_eq!(after.resident_pages, before.resident_pages + 1);
+    assert_eq!(after.virtual_pages, before.virtual_pages + 1);
+    assert_eq!(sys_munmap(start + PAGE_SIZE, PAGE_SIZE), 0);
+    assert_eq!(read().resident_pages, before.resident_pages);
+    assert_eq!(sys_task_meminfo(usize::MAX, info), -1);
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    // 缓冲区已经解除映射
+    assert_eq!(sys_task_meminfo(0, info), -1);
+    info!("task_meminfo_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_mprotect` makes an mmapped page read-only for user writes and
+/// refuses other protections and unmapped ranges
+pub fn mprotect_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, 2 * PAGE_SIZE, 0x3), 0);
+    let token = current_user_token();
+    write_user_struct(token, start as *mut usize, &0x5a5a).unwrap();
+    assert_eq!(sys_mprotect(start, PAGE_SIZE, 0x3), -1);
+    assert_eq!(sys_mprotect(start + 1, PAGE_SIZE, 0x1), -1);
+    assert_eq!(sys_mprotect(start, 3 * PAGE_SIZE, 0x1), -1);
+    assert_eq!(sys_mprotect(start, PAGE_SIZE, 0x1), 0);
+    // 内核替用户写也要遵守只读
+    assert!(write_user_struct(token, start as *mut usize, &0).is_err());
+    assert_eq!(read_user_struct(token, start as *const usize), Ok(0x5a5a));
+    write_user_struct(token, (start + PAGE_SIZE) as *mut usize, &1).unwrap();
+    assert_eq!(sys_munmap(start, 2 * PAGE_SIZE), 0);
+    info!("mprotect_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_yield_to` refuses a negative or unknown pid with -1
+pub fn yield_to_test() {
+    use crate::loader::get_num_app;
+    let yields = get_current_task_info().yields;
+    assert_eq!(sys_yield_to(-1), -1);
+    assert_eq!(sys_yield_to(isize::MAX), -1);
+    assert_eq!(sys_yield_to(get_num_app() as isize), -1);
+    assert_eq!(get_current_task_info().yields, yields);
+    info!("yield_to_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_madvise` drops an mmapped page and refuses other advice,
+/// unaligned starts and ranges running past the trap context
+pub fn madvise_test() {
+    let start = 0x1000_0000;
+    assert_eq!(sys_mmap(start, PAGE_SIZE, 0x3), 0);
+    let token = current_user_token();
+    assert_eq!(sys_madvise(start, usize::MAX, 4), -1);
+    assert_eq!(sys_madvise(start, TRAP_CONTEXT, 4), -1);
+    assert_eq!(sys_madvise(start + 1, PAGE_SIZE, 4), -1);
+    assert_eq!(sys_madvise(start, PAGE_SIZE, 0), -1);
+    assert_eq!(sys_madvise(start, 0, 4), 0);
+    write_user_struct(token, start as *mut usize, &1).unwrap();
+    assert_eq!(sys_madvise(start, PAGE_SIZE, 4), 0);
+    // 页帧已经释放，下一次用户访问才会缺页分配
+    assert!(read_user_struct(token, start as *const usize).is_err());
+    assert_eq!(sys_munmap(start, PAGE_SIZE), 0);
+    info!("madvise_test passed!");
+}
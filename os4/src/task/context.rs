@@ -1,5 +1,11 @@
 //! Implementation of [`TaskContext`]
+use crate::config::KERNEL_STACK_SIZE;
 use crate::trap::trap_return;
+use core::arch::asm;
+use core::fmt::{self, Debug, Formatter};
+
+/// most return addresses [`backtrace`] prints
+const MAX_BACKTRACE_DEPTH: usize = 32;
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -26,4 +32,136 @@ impl TaskContext {
             s: [0; 12],
         }
     }
+    /// saved return address, where `__switch` will jump back to
+    pub fn ra(&self) -> usize {
+        self.ra
+    }
+    /// saved kernel stack pointer
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+    /// saved frame pointer `s0` of the function that called `__switch`
+    pub fn fp(&self) -> usize {
+        self.s[0]
+    }
+    /// Context of the calling function at this point, with `ra` set to the
+    /// current pc, so a backtrace can start from anywhere in the kernel.
+    #[inline(always)]
+    pub fn here() -> Self {
+        let (pc, sp, fp): (usize, usize, usize);
+        unsafe {
+            asm!("auipc {}, 0", "mv {}, sp", "mv {}, s0", out(reg) pc, out(reg) sp, out(reg) fp);
+        }
+        let mut cx = Self::zero_init();
+        cx.ra = pc;
+        cx.sp = sp;
+        cx.s[0] = fp;
+        cx
+    }
+    /// Return addresses the context resumes into, innermost first: `ra`,
+    /// then the ones found by following the saved frame pointers.
+    pub fn return_addresses(&self) -> ReturnAddresses {
+        ReturnAddresses {
+            next_ra: self.ra,
+            fp: self.fp(),
+            sp: self.sp,
+        }
+    }
+}
+
+/// Iterator of [`TaskContext::return_addresses`]. The kernel is built with
+/// `-Cforce-frame-pointers=yes`, so every frame keeps its return address at
+/// `fp - 8` and the frame pointer of its caller at `fp - 16`. The walk ends at
+/// an address outside the kernel text or a frame pointer that does not move
+/// towards the bottom of the stack `sp` is on, e.g. a user `s0` saved by
+/// `__alltraps`.
+pub struct ReturnAddresses {
+    next_ra: usize,
+    fp: usize,
+    sp: usize,
+}
+
+impl Iterator for ReturnAddresses {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        extern "C" {
+            fn stext();
+            fn etext();
+        }
+        let ra = self.next_ra;
+        if !(stext as usize..etext as usize).contains(&ra) {
+            return None;
+        }
+        // 帧指针之间不能越过栈的大小，不会读到栈以外太远的地方
+        let limit = self.sp.saturating_add(KERNEL_STACK_SIZE);
+        if self.fp % 8 != 0 || self.fp < self.sp.saturating_add(16) || self.fp > limit {
+            self.next_ra = 0;
+            return Some(ra);
+        }
+        let (next_ra, next_fp) =
+            unsafe { (*((self.fp - 8) as *const usize), *((self.fp - 16) as *const usize)) };
+        self.next_ra = next_ra;
+        // 调用者的栈帧在更高的地址上，否则说明帧指针链已经断了
+        if next_fp <= self.fp {
+            self.fp = 0;
+        } else {
+            self.sp = self.fp;
+            self.fp = next_fp;
+        }
+        Some(ra)
+    }
+}
+
+/// Print the return addresses `cx` resumes into, see
+/// [`TaskContext::return_addresses`]. Allocates nothing, so it works from the
+/// panic handler.
+pub fn backtrace(cx: &TaskContext) {
+    println!("[kernel] backtrace:");
+    for (i, ra) in cx.return_addresses().take(MAX_BACKTRACE_DEPTH).enumerate() {
+        println!("[kernel]   #{} {:#x}", i, ra);
+    }
+}
+
+impl Debug for TaskContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "TaskContext {{ ra: {:#x}, sp: {:#x}, s: [", self.ra, self.sp)?;
+        for (i, s) in self.s.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{:#x}", s)?;
+        }
+        write!(f, "] }}")
+    }
+}
+
+#[allow(unused)]
+/// check a context captured here unwinds through this function into its
+/// caller, and a fresh task context stops right after `trap_return`
+pub fn backtrace_test() {
+    extern "C" {
+        fn stext();
+        fn etext();
+    }
+    let text = stext as usize..etext as usize;
+    let cx = TaskContext::here();
+    let mut addrs = [0usize; MAX_BACKTRACE_DEPTH];
+    let mut depth = 0;
+    for ra in cx.return_addresses().take(MAX_BACKTRACE_DEPTH) {
+        addrs[depth] = ra;
+        depth += 1;
+    }
+    // 当前 pc 在本函数里，下一个返回地址在调用它的 selftest 里，再往上还有 rust_main
+    assert!(depth >= 3, "only {} frames", depth);
+    assert!(addrs[..depth].iter().all(|ra| text.contains(ra)));
+    assert!(addrs[0] >= backtrace_test as usize);
+    assert_ne!(addrs[1], addrs[0]);
+    assert!(cx.fp() > cx.sp());
+    // 新任务还没有运行过，s0 为 0 ，只有 trap_return 一项
+    let cx = TaskContext::goto_trap_return(cx.sp());
+    let mut frames = cx.return_addresses();
+    assert_eq!(frames.next(), Some(trap_return as usize));
+    assert_eq!(frames.next(), None);
+    backtrace(&TaskContext::here());
+    info!("backtrace_test passed!");
 }
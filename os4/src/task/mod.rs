@@ -11,26 +11,46 @@
 
 mod context;
 mod switch;
+mod stride;
 #[allow(clippy::module_inception)]
 mod task;
+#[cfg(feature = "syscall_trace")]
+mod trace;
 
-use core::borrow::{Borrow, BorrowMut};
+use core::borrow::BorrowMut;
 
-use crate::config::{MAX_SYSCALL_NUM, PAGE_SIZE};
-use crate::loader::{get_app_data, get_num_app};
-use crate::mm::memory_set::{MapType, MapArea};
-use crate::mm::{MapPermission, VirtAddr, VirtPageNum};
-use crate::sync::UPSafeCell;
+use crate::config::{
+    kernel_stack_position, HUGE_PAGE_SIZE, MAX_SYSCALL_NUM, MMAP_PRESET_AD, MMAP_TOP, PAGE_SIZE,
+    USER_STACK_SIZE,
+};
+use crate::loader::{get_app_data, get_app_name, get_num_app};
+use crate::mm::memory_set::MapType;
+use crate::mm::{frames_available, FaultKind, MapPermission, VirtAddr, VirtPageNum, KERNEL_SPACE};
+use crate::sync::{bump_switch_generation, UPRefMut, UPSafeCell};
 use crate::syscall;
 use crate::syscall::process::TaskInfo;
+use crate::sbi::shutdown;
 use crate::timer::get_time_us;
 use crate::trap::TrapContext;
+use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use lazy_static::*;
 pub use switch::__switch;
+#[allow(unused)]
+pub use stride::{stride_less, BIG_STRIDE};
+#[cfg(feature = "self_test")]
+pub use stride::stride_less_test;
 pub use task::{TaskControlBlock, TaskStatus};
+#[cfg(feature = "self_test")]
+pub use task::{kernel_stack_limit_test, task_status_test};
+#[cfg(feature = "syscall_trace")]
+pub use trace::{SyscallRecord, SyscallTrace};
+#[cfg(all(feature = "syscall_trace", feature = "self_test"))]
+pub use trace::syscall_trace_test;
 
-pub use context::TaskContext;
+pub use context::{backtrace, TaskContext};
+#[cfg(feature = "self_test")]
+pub use context::backtrace_test;
 
 /// The task manager, where all the tasks are managed.
 ///
@@ -41,6 +61,11 @@ pub use context::TaskContext;
 /// Most of `TaskManager` are hidden behind the field `inner`, to defer
 /// borrowing checks to runtime. You can see examples on how to use `inner` in
 /// existing functions on `TaskManager`.
+///
+/// Lock ordering: `inner` is always taken before `KERNEL_SPACE`. Holding the
+/// inner borrow while creating a task (which locks `KERNEL_SPACE`) is fine,
+/// borrowing `inner` while `KERNEL_SPACE` is locked is not. Go through
+/// `inner_access`, which checks this in debug builds.
 pub struct TaskManager {
     /// total number of tasks
     num_app: usize,
@@ -54,24 +79,44 @@ struct TaskManagerInner {
     tasks: Vec<TaskControlBlock>,
     /// id of current `Running` task
     current_task: usize,
+    /// number of `__switch` calls so far
+    switches: usize,
+}
+
+impl TaskManagerInner {
+    /// Mark task `task_id` as `Running`. The first time a task is scheduled
+    /// its `start_time` is recorded, later switches leave it untouched.
+    fn mark_started(&mut self, task_id: usize) {
+        let task = &mut self.tasks[task_id];
+        task.task_status = TaskStatus::Running;
+        if task.start_time == 0 {
+            task.start_time = get_time_us();
+        }
+    }
+    /// Count a `__switch` into task `next`, once right before each call.
+    fn count_switch(&mut self, next: usize) {
+        self.switches += 1;
+        self.tasks[next].switches += 1;
+    }
 }
 
 lazy_static! {
     /// a `TaskManager` instance through lazy_static!
     pub static ref TASK_MANAGER: TaskManager = {
         info!("init TASK_MANAGER");
-        let num_app = get_num_app();
-        info!("num_app = {}", num_app);
-        let mut tasks: Vec<TaskControlBlock> = Vec::new();
-        for i in 0..num_app {
-            tasks.push(TaskControlBlock::new(get_app_data(i), i));
+        let (tasks, errors) = load_all_apps();
+        for (i, err) in errors.iter() {
+            error!("[kernel] skipping app {}: {}", i, err);
         }
+        let num_app = tasks.len();
+        info!("num_app = {}", num_app);
         TaskManager {
             num_app,
             inner: unsafe {
                 UPSafeCell::new(TaskManagerInner {
                     tasks,
                     current_task: 0,
+                    switches: 0,
                 })
             },
         }
@@ -79,81 +124,175 @@ lazy_static! {
 }
 
 impl TaskManager {
+    /// Borrow `inner`, see the lock ordering on [`TaskManager`].
+    fn inner_access(&self) -> UPRefMut<'_, TaskManagerInner> {
+        debug_assert!(
+            !KERNEL_SPACE.is_locked(),
+            "task inner borrowed while KERNEL_SPACE is locked"
+        );
+        self.inner.exclusive_access()
+    }
+
     /// Run the first task in task list.
     ///
     /// Generally, the first task in task list is an idle task (we call it zero process later).
     /// But in ch4, we load apps statically, so the first task is a real app.
     fn run_first_task(&self) -> ! {
-        let mut inner = self.inner.exclusive_access();
-        let next_task = &mut inner.tasks[0];
-        next_task.task_status = TaskStatus::Running;
-        next_task.time = get_time_us();
-        let next_task_cx_ptr = &next_task.task_cx as *const TaskContext;
+        let mut inner = self.inner_access();
+        inner.mark_started(0);
+        inner.count_switch(0);
+        inner.tasks[0].mode_switch_time = get_time_us();
+        let next_task_cx_ptr = &inner.tasks[0].task_cx as *const TaskContext;
         drop(inner);
         let mut _unused = TaskContext::zero_init();
         // before this, we should drop local variables that must be dropped manually
+        bump_switch_generation();
         unsafe {
             __switch(&mut _unused as *mut _, next_task_cx_ptr);
         }
         panic!("unreachable in run_first_task!");
     }
 
-    /// Change the status of current `Running` task into `Ready`.
+    /// Change the status of current `Running` task into `Ready`, counting
+    /// the yield against it.
     fn mark_current_suspended(&self) {
-        let mut inner = self.inner.exclusive_access();
+        let mut inner = self.inner_access();
         let current = inner.current_task;
         inner.tasks[current].task_status = TaskStatus::Ready;
+        inner.tasks[current].yields += 1;
     }
 
     /// Change the status of current `Running` task into `Exited`.
-    fn mark_current_exited(&self) {
-        let mut inner = self.inner.exclusive_access();
+    fn mark_current_exited(&self, exit_code: i32) {
+        let mut inner = self.inner_access();
         let current = inner.current_task;
         inner.tasks[current].task_status = TaskStatus::Exited;
+        inner.tasks[current].exit_code = exit_code;
     }
 
     /// Find next task to run and return task id.
     ///
     /// In this case, we only return the first `Ready` task in task list.
     fn find_next_task(&self) -> Option<usize> {
-        let inner = self.inner.exclusive_access();
+        let inner = self.inner_access();
         let current = inner.current_task;
-        (current + 1..current + self.num_app + 1)
-            .map(|id| id % self.num_app)
-            .find(|id| inner.tasks[*id].task_status == TaskStatus::Ready)
+        pick_next_ready(current, self.num_app, |id| inner.tasks[id].task_status)
+    }
+
+    /// Number of tasks currently in `status`.
+    fn count_by_status(&self, status: TaskStatus) -> usize {
+        let inner = self.inner_access();
+        inner
+            .tasks
+            .iter()
+            .filter(|task| task.task_status == status)
+            .count()
+    }
+
+    /// Exit code for the whole kernel once every task has exited: the first
+    /// nonzero exit code of a task, 0 if all of them succeeded.
+    fn final_exit_code(&self) -> Option<i32> {
+        if self.count_by_status(TaskStatus::Exited) != self.num_app {
+            return None;
+        }
+        let inner = self.inner_access();
+        let failed = inner.tasks.iter().find(|task| task.exit_code != 0);
+        Some(failed.map_or(0, |task| task.exit_code))
+    }
+
+    /// Number of tasks that are `Ready` or `Running`, i.e. not finished yet.
+    fn num_runnable(&self) -> usize {
+        self.count_by_status(TaskStatus::Ready) + self.count_by_status(TaskStatus::Running)
+    }
+
+    /// Set the priority of the current 'Running' task.
+    fn set_current_priority(&self, priority: isize) {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        inner.tasks[current_task].priority = priority;
+    }
+
+    /// Name of the current 'Running' task.
+    fn current_task_name(&self) -> &'static str {
+        let inner = self.inner_access();
+        inner.tasks[inner.current_task].name
     }
 
     /// Get the current 'Running' task's token.
     fn get_current_token(&self) -> usize {
-        let inner = self.inner.exclusive_access();
+        let inner = self.inner_access();
         inner.tasks[inner.current_task].get_user_token()
     }
 
     #[allow(clippy::mut_from_ref)]
     /// Get the current 'Running' task's trap contexts.
     fn get_current_trap_cx(&self) -> &mut TrapContext {
-        let inner = self.inner.exclusive_access();
+        let inner = self.inner_access();
         inner.tasks[inner.current_task].get_trap_cx()
     }
 
     #[allow(clippy::mut_from_ref)]
-    /// Get the current 'Running' task's trap contexts.
-    fn sys_mmap(&self,start: usize, len: usize, permission: MapPermission) -> bool{
-        let mut inner = self.inner.exclusive_access();
+    /// Map `[start, start + len)` into the current task, `start == 0` lets the
+    /// kernel pick a free range. Returns the start address actually mapped.
+    fn sys_mmap(&self,start: usize, len: usize, permission: MapPermission) -> Option<usize>{
+        self.mmap_at(start, len, permission, false)
+    }
+
+    /// Map `HUGE_PAGE_SIZE` bytes like `sys_mmap`, backed by physically
+    /// contiguous frames when there are enough of them.
+    fn sys_mmap_hugepage(&self, start: usize, permission: MapPermission) -> Option<usize> {
+        self.mmap_at(start, HUGE_PAGE_SIZE, permission, true)
+    }
+
+    /// `sys_mmap` with the choice of contiguous frames.
+    fn mmap_at(
+        &self,
+        start: usize,
+        len: usize,
+        permission: MapPermission,
+        contiguous: bool,
+    ) -> Option<usize> {
+        // mmap 出来的页面必须是用户态可访问的
+        if !permission.contains(MapPermission::U) {
+            return None;
+        }
+        // start + len 回绕后 end_vpn 会比 start_vpn 小，VPNRange::new 直接 panic。
+        // MMAP_TOP 以上的地址在页表中与 Trap 上下文和跳板页的页表项互为别名
+        match start.checked_add(len) {
+            Some(end) if end <= MMAP_TOP => {}
+            _ => return None,
+        }
+        let mut inner = self.inner_access();
         let current_task = inner.current_task;
-        let start_vpn = VirtAddr::from(start).floor();
-        let end_vpn = VirtAddr::from(start+len).ceil();
-        let areas: &Vec<MapArea> =  inner.tasks[current_task].memory_set.areas.borrow();
-        for ele in  areas{
-            // 判断是否在范围内
-        //    if start_vpn <= ele.vpn_range.get_start()  && ele.vpn_range.get_end() <= end_vpn {
-        //         return false;
-        //    }
-           let start = ele.vpn_range.get_start();
-            let end = ele.vpn_range.get_end();
-            if start_vpn < end && end_vpn > start {
-                return false;
+        let start = if start == 0 {
+            let pages = VirtAddr::from(len).ceil().0;
+            VirtAddr::from(inner.tasks[current_task].memory_set.find_free_range(pages)?).0
+        } else {
+            start
+        };
+        // 用户给出的地址没有对齐时系统调用失败，而不是在转换成页号时 panic
+        let start_vpn = match VirtAddr::from(start).try_into_vpn() {
+            Ok(vpn) => vpn,
+            Err(err) => {
+                debug!("[kernel] mmap {}", err);
+                return None;
             }
+        };
+        let end_vpn = VirtAddr::from(start+len).ceil();
+        // 物理页帧不够时直接失败，不要映射到一半再回滚。
+        // 页表节点也要占用页帧，所以这只是必要条件，但足以挡住绝大多数过大的请求
+        if end_vpn.0 - start_vpn.0 > frames_available() {
+            return None;
+        }
+        if let Some(range) = inner.tasks[current_task].memory_set.first_conflict(start_vpn, end_vpn) {
+            debug!(
+                "[kernel] mmap [{:?}, {:?}) overlaps the area [{:?}, {:?})",
+                start_vpn,
+                end_vpn,
+                range.get_start(),
+                range.get_end()
+            );
+            return None;
         }
         // {
         //     let mut start = start_vpn.0;
@@ -171,24 +310,51 @@ impl TaskManager {
         //     start_va += PAGE_SIZE;
         // }
         // println!("insert_framed_area start:{} end:{}",VirtAddr::from(start).floor().0 ,VirtAddr::from(start+len).ceil().0);
-        inner.tasks[current_task].memory_set.insert_framed_area(start_vpn.into() ,end_vpn.into(),permission);
+        let memory_set = &mut inner.tasks[current_task].memory_set;
+        let inserted = if contiguous {
+            memory_set
+                .insert_contiguous_area(start_vpn.into(), end_vpn.into(), permission)
+                .map(|_| ())
+        } else {
+            memory_set.insert_framed_area_ad(
+                start_vpn.into(),
+                end_vpn.into(),
+                permission,
+                MMAP_PRESET_AD,
+            )
+        };
+        if inserted.is_err() {
+            return None;
+        }
+        inner.tasks[current_task].memory_set.check_invariants();
         // 拆分每页
         // let mut start = start_vpn.0;
         // while start < end_vpn.0{
         //     inner.tasks[current_task].memory_set.insert_framed_area(VirtPageNum::from(start).into() ,VirtPageNum::from(start+1).into() ,permission);
         //     start+=1usize;
         // }
-        true
+        Some(start)
+    }
+
+    /// Map `len_bytes` rounded up to whole pages at a free address picked by
+    /// the kernel, returns the base address. Fails for `len_bytes == 0`.
+    fn sys_mmap_anon(&self, len_bytes: usize, permission: MapPermission) -> Option<usize> {
+        if len_bytes == 0 {
+            return None;
+        }
+        self.sys_mmap(0, len_bytes, permission)
     }
 
     #[allow(clippy::mut_from_ref)]
     fn sys_munmap(&self,start: usize, len: usize) -> isize{
 
-        let mut inner = self.inner.exclusive_access();
+        let mut inner = self.inner_access();
         let current_task = inner.current_task;
 
         let memory_set = &mut inner.tasks[current_task].memory_set;
-        memory_set.remove(start, len)
+        let result = memory_set.remove(start, len);
+        memory_set.check_invariants();
+        result
 
 
         // let start_vpn = VirtAddr(start).floor();
@@ -240,49 +406,264 @@ impl TaskManager {
     #[allow(clippy::mut_from_ref)]
     /// Get the current 'Running' task's trap contexts.
     fn get_current_task_info(&self) -> syscall::process::TaskInfo {
-        let inner = self.inner.exclusive_access();
-         syscall::process::TaskInfo{
-            status: inner.tasks[inner.current_task].task_status.clone(),
-            syscall_times:inner.tasks[inner.current_task].syscall_times.clone(),
-            time: inner.tasks[inner.current_task].time,
-         }
+        let current_task = self.inner_access().current_task;
+        self.get_task_info(current_task).unwrap()
+    }
+
+    /// Get the info of task `pid`, or `None` if there is no such task.
+    fn get_task_info(&self, pid: usize) -> Option<syscall::process::TaskInfo> {
+        let inner = self.inner_access();
+        let task = inner.tasks.get(pid)?;
+        Some(syscall::process::TaskInfo {
+            status: task.task_status,
+            syscall_times: task.syscall_times,
+            time: task.start_time,
+            page_faults: task.page_faults,
+            user_time: task.user_time,
+            kernel_time: task.kernel_time,
+            yields: task.yields,
+        })
     }
 
+    /// The current task trapped into the kernel.
+    fn account_trap_enter(&self) {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        inner.tasks[current_task].charge_user_time(get_time_us());
+    }
+
+    /// The current task returns to user mode.
+    fn account_trap_return(&self) {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        inner.tasks[current_task].charge_kernel_time(get_time_us());
+    }
+
+    /// Classify a page fault of the current 'Running' task at `va`.
+    fn classify_current_fault(&self, va: VirtAddr) -> FaultKind {
+        let inner = self.inner_access();
+        let task = &inner.tasks[inner.current_task];
+        // base_size 是用户栈栈顶，栈底下面紧挨着的一页就是 guard page
+        let guard_page = VirtAddr::from(task.base_size - USER_STACK_SIZE - PAGE_SIZE).floor();
+        if va.floor() == guard_page {
+            FaultKind::GuardPage
+        } else {
+            task.memory_set.classify_fault(va)
+        }
+    }
+
+    /// Count a syscall of the current task. Ids beyond `MAX_SYSCALL_NUM` come
+    /// straight from the application and are not counted, counters stop at
+    /// `u32::MAX` instead of wrapping around.
     fn inc_current_task_syscall(&self,syscall_id: usize){
-        let mut inner = self.inner.exclusive_access();
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        if let Some(times) = inner.tasks[current_task].syscall_times.get_mut(syscall_id) {
+            *times = times.saturating_add(1);
+        }
+        #[cfg(feature = "syscall_trace")]
+        inner.tasks[current_task]
+            .syscall_trace
+            .push(syscall_id, get_time_us());
+    }
+
+    /// Count a syscall of the current task that has no handler.
+    fn inc_current_unknown_syscall(&self) {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        let unknown = &mut inner.tasks[current_task].unknown_syscalls;
+        *unknown = unknown.saturating_add(1);
+    }
+
+    /// Free the frames of `[start, start + len)` in the current task.
+    fn madvise_dontneed(&self, start: usize, len: usize) -> bool {
+        // start + len 回绕时 VPNRange::new 会 panic
+        let end = match start.checked_add(len) {
+            Some(end) => end,
+            None => return false,
+        };
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        inner.tasks[current_task]
+            .memory_set
+            .drop_pages(VirtAddr::from(start).floor(), VirtAddr::from(end).ceil())
+    }
+
+    /// Make `[start, start + len)` of the current task read-only.
+    fn make_current_readonly(&self, start: usize, len: usize) -> bool {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        let memory_set = &mut inner.tasks[current_task].memory_set;
+        let done = memory_set
+            .make_readonly(VirtAddr::from(start).floor(), VirtAddr::from(start + len).ceil());
+        memory_set.check_invariants();
+        done
+    }
+
+    /// Try to map a page for a fault of the current task at `va`.
+    fn handle_current_page_fault(&self, va: VirtAddr, access: MapPermission) -> bool {
+        let mut inner = self.inner_access();
+        let current_task = inner.current_task;
+        inner.tasks[current_task].memory_set.handle_page_fault(va, access)
+    }
+
+    /// Count a page fault of the current 'Running' task.
+    fn inc_current_page_faults(&self) {
+        let mut inner = self.inner_access();
         let current_task = inner.current_task;
-        inner.tasks[current_task].syscall_times[syscall_id]+=1;
+        inner.tasks[current_task].page_faults += 1;
     }
+
+    #[cfg(feature = "syscall_trace")]
+    /// Get the last `n` syscalls of the current 'Running' task, oldest first.
+    fn get_current_syscall_trace(&self, n: usize) -> Vec<SyscallRecord> {
+        let inner = self.inner_access();
+        let trace = &inner.tasks[inner.current_task].syscall_trace;
+        let skip = trace.len().saturating_sub(n);
+        (skip..trace.len()).filter_map(|i| trace.get(i)).collect()
+    }
+    /// Copy of the current task's saved `TaskContext`, read under the inner
+    /// borrow. 正在运行的任务的 task_cx 是它上一次被切换出去时保存的
+    fn current_task_context_snapshot(&self) -> TaskContext {
+        let inner = self.inner_access();
+        inner.tasks[inner.current_task].task_cx
+    }
+
     /// Switch current `Running` task to the task we have found,
     /// or there is no `Ready` task and we can exit with all applications completed
     fn run_next_task(&self) {
         if let Some(next) = self.find_next_task() {
-            let mut inner = self.inner.exclusive_access();
-            let current = inner.current_task;
-            inner.tasks[next].task_status = TaskStatus::Running;
-            inner.current_task = next;
-            if inner.tasks[next].time == 0 {
-                inner.tasks[next].time = get_time_us();
-            }
-            let current_task_cx_ptr = &mut inner.tasks[current].task_cx as *mut TaskContext;
-            let next_task_cx_ptr = &inner.tasks[next].task_cx as *const TaskContext;
-            drop(inner);
-            // before this, we should drop local variables that must be dropped manually
-            unsafe {
-                __switch(current_task_cx_ptr, next_task_cx_ptr);
-            }
-            // go back to user mode
-        } else {
-            panic!("All applications completed!");
+            self.switch_to(next);
+        } else if let Some(exit_code) = self.final_exit_code() {
+            info!("[kernel] All applications completed with exit code {}", exit_code);
+            shutdown(exit_code);
+        } else if self.num_runnable() == 0 {
+            panic!("No task can run but not every task has exited!");
         }
+        // 否则只剩当前任务还在运行，直接回到它
+    }
+
+    /// Suspend the current `Running` task and run task `pid` next if it is
+    /// `Ready`, otherwise fall back to the normal round-robin choice.
+    ///
+    /// Returns false without suspending anything if `pid` does not exist.
+    fn yield_to(&self, pid: usize) -> bool {
+        if pid >= self.num_app {
+            return false;
+        }
+        self.mark_current_suspended();
+        let inner = self.inner_access();
+        let next = pick_yield_target(inner.current_task, pid, self.num_app, |id| {
+            inner.tasks[id].task_status
+        });
+        drop(inner);
+        match next {
+            Some(next) => self.switch_to(next),
+            // 没有可运行的任务，交给 run_next_task 决定关机还是回到当前任务
+            None => self.run_next_task(),
+        }
+        true
+    }
+
+    /// Reload the exited task `pid` from its app and make it `Ready`, so it
+    /// runs again from the entry. Returns false if there is no such task, it
+    /// has not exited, it is still the current task or its app fails to load.
+    fn restart(&self, pid: usize) -> bool {
+        let mut inner = self.inner_access();
+        match inner.tasks.get(pid) {
+            Some(task) if task.task_status == TaskStatus::Exited => {}
+            _ => return false,
+        }
+        // 刚退出的任务还在自己的内核栈上运行，直到切换出去
+        if pid == inner.current_task {
+            return false;
+        }
+        // 任务 pid 的内核栈就在第 pid 个位置
+        let (_, kernel_stack_top) = kernel_stack_position(pid);
+        let app_index = inner.tasks[pid].app_index;
+        inner.tasks[pid]
+            .restart(get_app_data(app_index), kernel_stack_top)
+            .is_ok()
+    }
+
+    /// Switch current `Running` task to task `next`.
+    fn switch_to(&self, next: usize) {
+        let mut inner = self.inner_access();
+        let current = inner.current_task;
+        // 换出的任务停止计时，换入的任务从现在开始计入内核态时间
+        let now = get_time_us();
+        inner.tasks[current].charge_kernel_time(now);
+        inner.tasks[next].mode_switch_time = now;
+        inner.mark_started(next);
+        inner.count_switch(next);
+        inner.current_task = next;
+        let current_task_cx_ptr = &mut inner.tasks[current].task_cx as *mut TaskContext;
+        let next_task_cx_ptr = &inner.tasks[next].task_cx as *const TaskContext;
+        drop(inner);
+        // before this, we should drop local variables that must be dropped manually
+        bump_switch_generation();
+        unsafe {
+            __switch(current_task_cx_ptr, next_task_cx_ptr);
+        }
+        // go back to user mode
     }
 }
 
+/// Round-robin search for the next `Ready` task after `current`.
+///
+/// `current + 1..=current + num_app` visits every id exactly once with
+/// `current` itself tried last, so with a single task it is only picked
+/// again if it is still `Ready`. An `Exited` task is never returned.
+fn pick_next_ready(
+    current: usize,
+    num_app: usize,
+    status: impl Fn(usize) -> TaskStatus,
+) -> Option<usize> {
+    (current + 1..current + num_app + 1)
+        .map(|id| id % num_app)
+        .find(|id| status(*id) == TaskStatus::Ready)
+}
+
 /// Run the first task in task list.
 pub fn run_first_task() {
     TASK_MANAGER.run_first_task();
 }
 
+/// Build a task for every app linked into the kernel. Broken apps are left
+/// out and reported as `(app index, error)`.
+fn load_all_apps() -> (Vec<TaskControlBlock>, Vec<(usize, String)>) {
+    load_apps((0..get_num_app()).map(|i| (get_app_name(i), get_app_data(i))), 0, true)
+}
+
+/// Build a task for every `(name, image)` in `apps`, the kernel stacks are
+/// allocated from slot `first_id` on, one per task actually built. With
+/// `shared_text` the images are the linked apps in order and tasks of the
+/// same app share their text.
+fn load_apps<'a>(
+    apps: impl Iterator<Item = (&'static str, &'a [u8])>,
+    first_id: usize,
+    shared_text: bool,
+) -> (Vec<TaskControlBlock>, Vec<(usize, String)>) {
+    let mut tasks = Vec::new();
+    let mut errors = Vec::new();
+    for (i, (name, elf_data)) in apps.enumerate() {
+        let app_id = first_id + tasks.len();
+        let task = if shared_text {
+            TaskControlBlock::new_shared(name, elf_data, app_id, i)
+        } else {
+            TaskControlBlock::new(name, elf_data, app_id)
+        };
+        match task {
+            Ok(mut task) => {
+                task.app_index = i;
+                tasks.push(task);
+            }
+            Err(err) => errors.push((i, err.to_string())),
+        }
+    }
+    (tasks, errors)
+}
+
 /// Switch current `Running` task to the task we have found,
 /// or there is no `Ready` task and we can exit with all applications completed
 fn run_next_task() {
@@ -295,8 +676,8 @@ fn mark_current_suspended() {
 }
 
 /// Change the status of current `Running` task into `Exited`.
-fn mark_current_exited() {
-    TASK_MANAGER.mark_current_exited();
+fn mark_current_exited(exit_code: i32) {
+    TASK_MANAGER.mark_current_exited(exit_code);
 }
 
 /// Suspend the current 'Running' task and run the next task in task list.
@@ -305,9 +686,45 @@ pub fn suspend_current_and_run_next() {
     run_next_task();
 }
 
-/// Exit the current 'Running' task and run the next task in task list.
-pub fn exit_current_and_run_next() {
-    mark_current_exited();
+/// Suspend the current 'Running' task and run task `pid` next if it is `Ready`.
+///
+/// Returns false if `pid` does not name a task.
+pub fn suspend_current_and_yield_to(pid: usize) -> bool {
+    TASK_MANAGER.yield_to(pid)
+}
+
+/// Number of `__switch` calls so far, in total and into task `pid`, `None`
+/// if there is no such task.
+pub fn switch_count(pid: usize) -> Option<(usize, usize)> {
+    let inner = TASK_MANAGER.inner_access();
+    let task = inner.tasks.get(pid)?;
+    Some((inner.switches, task.switches))
+}
+
+/// Resident and virtual pages of task `pid`, see
+/// [`MemorySet::resident_pages`] and [`MemorySet::virtual_size`]. `None` if
+/// there is no such task.
+pub fn task_memory_usage(pid: usize) -> Option<(usize, usize)> {
+    let inner = TASK_MANAGER.inner_access();
+    let memory_set = &inner.tasks.get(pid)?.memory_set;
+    Some((memory_set.resident_pages(), memory_set.virtual_size(false) / PAGE_SIZE))
+}
+
+/// Run the exited task `pid` again from its entry, false if it cannot be
+/// restarted.
+#[allow(unused)]
+pub fn restart_task(pid: usize) -> bool {
+    TASK_MANAGER.restart(pid)
+}
+
+/// Name of the current 'Running' task, for logging.
+pub fn current_task_name() -> &'static str {
+    TASK_MANAGER.current_task_name()
+}
+
+/// Exit the current 'Running' task with `exit_code` and run the next task in task list.
+pub fn exit_current_and_run_next(exit_code: i32) {
+    mark_current_exited(exit_code);
     run_next_task();
 }
 
@@ -327,18 +744,695 @@ pub fn get_current_task_info() -> TaskInfo {
     TASK_MANAGER.get_current_task_info()
 }
 
+/// Get the info of task `pid`, `None` if it does not exist.
+pub fn get_task_info(pid: usize) -> Option<TaskInfo> {
+    TASK_MANAGER.get_task_info(pid)
+}
+
+/// Start charging the current task's kernel time, called on trap entry.
+pub fn account_trap_enter() {
+    TASK_MANAGER.account_trap_enter()
+}
+
+/// Start charging the current task's user time, called right before
+/// returning to user mode.
+pub fn account_trap_return() {
+    TASK_MANAGER.account_trap_return()
+}
+
+/// Classify a page fault of the current 'Running' task at `va`.
+pub fn classify_current_fault(va: VirtAddr) -> FaultKind {
+    TASK_MANAGER.classify_current_fault(va)
+}
+
 /// Get the current 'Running' task's trap contexts.
 pub fn inc_current_task_syscall(syscall_id: usize) {
     TASK_MANAGER.inc_current_task_syscall(syscall_id)
 }
 
+/// Count a syscall of the current task that has no handler.
+pub fn inc_current_unknown_syscall() {
+    TASK_MANAGER.inc_current_unknown_syscall()
+}
+
+/// Set the priority of the current 'Running' task, the caller validates it.
+pub fn set_current_priority(priority: isize) {
+    TASK_MANAGER.set_current_priority(priority)
+}
+
+/// Priority of the current 'Running' task.
+pub fn current_priority() -> isize {
+    let inner = TASK_MANAGER.inner_access();
+    inner.tasks[inner.current_task].priority
+}
+
+/// Number of syscalls without a handler the current task has made.
+pub fn current_unknown_syscalls() -> u32 {
+    let inner = TASK_MANAGER.inner_access();
+    inner.tasks[inner.current_task].unknown_syscalls
+}
+
+/// Free the frames of `[start, start + len)` in the current task, keeping the
+/// areas mapped. Returns false if part of the range is not mapped.
+pub fn kernel_sys_madvise_dontneed(start: usize, len: usize) -> bool {
+    TASK_MANAGER.madvise_dontneed(start, len)
+}
+
+/// Take write access to `[start, start + len)` away from the current task,
+/// see [`MemorySet::make_readonly`]. Returns false if part of the range is
+/// not mapped.
+pub fn kernel_sys_make_readonly(start: usize, len: usize) -> bool {
+    TASK_MANAGER.make_current_readonly(start, len)
+}
+
+/// Try to service a page fault of the current task at `va` with a fresh
+/// zeroed page, returns false if the fault is a real error.
+pub fn handle_current_page_fault(va: VirtAddr, access: MapPermission) -> bool {
+    TASK_MANAGER.handle_current_page_fault(va, access)
+}
+
+/// Count a page fault of the current 'Running' task.
+pub fn inc_current_page_faults() {
+    TASK_MANAGER.inc_current_page_faults()
+}
+
+#[cfg(feature = "syscall_trace")]
+/// Get the last `n` syscalls of the current 'Running' task, oldest first.
+pub fn get_current_syscall_trace(n: usize) -> Vec<SyscallRecord> {
+    TASK_MANAGER.get_current_syscall_trace(n)
+}
+
+/// Copy of the current task's saved `TaskContext`, for debugging.
+pub fn current_task_context_snapshot() -> TaskContext {
+    TASK_MANAGER.current_task_context_snapshot()
+}
+
 /// Get the current 'Running' task's trap contexts.
-pub fn kernel_sys_mmap(start: usize, len: usize, port: MapPermission) -> bool {
+/// Map memory into the current task, see [`TaskManager::sys_mmap`].
+pub fn kernel_sys_mmap(start: usize, len: usize, port: MapPermission) -> Option<usize> {
     TASK_MANAGER.sys_mmap(start,len,port)
 }
 
 
+/// Map a huge page for the current task, see [`TaskManager::sys_mmap_hugepage`].
+pub fn kernel_sys_mmap_hugepage(start: usize, permission: MapPermission) -> Option<usize> {
+    TASK_MANAGER.sys_mmap_hugepage(start, permission)
+}
+
+/// Map anonymous memory at an address the kernel picks, see
+/// [`TaskManager::sys_mmap_anon`].
+pub fn kernel_sys_mmap_anon(len_bytes: usize, permission: MapPermission) -> Option<usize> {
+    TASK_MANAGER.sys_mmap_anon(len_bytes, permission)
+}
+
 pub fn kernel_sys_munmap(_start: usize, _len: usize) -> isize{
     // 不小心把 _len 写错 _start 排查 3 小时
     TASK_MANAGER.sys_munmap(_start,_len)
-}
\ No newline at end of file
+}
+
+/// Task to run after `current` yields to `pid`: `pid` itself if it is another
+/// `Ready` task, otherwise the round-robin choice of [`pick_next_ready`].
+/// `pid` must be below `num_app`.
+fn pick_yield_target(
+    current: usize,
+    pid: usize,
+    num_app: usize,
+    status: impl Fn(usize) -> TaskStatus,
+) -> Option<usize> {
+    if pid != current && status(pid) == TaskStatus::Ready {
+        return Some(pid);
+    }
+    pick_next_ready(current, num_app, status)
+}
+
+#[allow(unused)]
+/// a simple test for the round-robin choice of the next task
+pub fn find_next_task_test() {
+    use TaskStatus::*;
+    // 两个任务，0 号退出后只剩 1 号可以运行
+    let tasks = [Exited, Ready];
+    assert_eq!(pick_next_ready(0, 2, |id| tasks[id]), Some(1));
+    assert_eq!(pick_next_ready(1, 2, |id| tasks[id]), Some(1));
+    // 全部退出后不会再选中任何任务
+    let tasks = [Exited, Exited];
+    assert_eq!(pick_next_ready(1, 2, |id| tasks[id]), None);
+    // 单个任务：退出后不会被重新选中
+    assert_eq!(pick_next_ready(0, 1, |_| Exited), None);
+    assert_eq!(pick_next_ready(0, 1, |_| Ready), Some(0));
+    info!("find_next_task_test passed!");
+}
+
+#[allow(unused)]
+/// Check the task chosen by `yield_to`: A yielding to C skips B, a target
+/// that is not `Ready` falls back to round-robin, and an unknown pid fails
+/// without suspending the current task.
+pub fn yield_to_test() {
+    use TaskStatus::*;
+    // A=0 刚被挂起，B=1 和 C=2 都就绪
+    let tasks = [Ready, Ready, Ready];
+    assert_eq!(pick_yield_target(0, 2, 3, |id| tasks[id]), Some(2));
+    assert_eq!(pick_next_ready(0, 3, |id| tasks[id]), Some(1));
+    // 目标已退出或就是自己时按轮转选择
+    let tasks = [Ready, Ready, Exited];
+    assert_eq!(pick_yield_target(0, 2, 3, |id| tasks[id]), Some(1));
+    assert_eq!(pick_yield_target(0, 0, 3, |id| tasks[id]), Some(1));
+    let tasks = [Ready, Exited, Running];
+    assert_eq!(pick_yield_target(0, 2, 3, |id| tasks[id]), Some(0));
+    let tasks = [Exited, Exited, Exited];
+    assert_eq!(pick_yield_target(0, 1, 3, |id| tasks[id]), None);
+    // 不存在的 pid：返回 false，当前任务既没有被挂起也没有被计入 yields
+    let before = get_current_task_info();
+    let status = TASK_MANAGER.inner_access().tasks[0].task_status;
+    for pid in [TASK_MANAGER.num_app, usize::MAX] {
+        assert!(!suspend_current_and_yield_to(pid));
+    }
+    assert_eq!(get_current_task_info().yields, before.yields);
+    assert_eq!(TASK_MANAGER.inner_access().tasks[0].task_status, status);
+    info!("yield_to_test passed!");
+}
+
+#[allow(unused)]
+/// Check the saved context of the current task. Before the first task runs
+/// it is still the one built by `goto_trap_return`; once the task has been
+/// switched out and back, `ra` points back into `switch_to` right after the
+/// call to `__switch`, which this boot-time test cannot observe.
+pub fn task_context_snapshot_test() {
+    use crate::trap::trap_return;
+    let cx = current_task_context_snapshot();
+    assert_eq!(cx.ra(), trap_return as usize);
+    assert_eq!(cx.sp(), kernel_stack_position(0).1);
+    info!("task_context_snapshot_test passed!");
+}
+
+#[allow(unused)]
+/// Create a task while the task inner is borrowed, the order allowed by the
+/// lock ordering on [`TaskManager`]. Uses the kernel stack slot right after
+/// the last app and unmaps it again afterwards.
+pub fn lock_order_test() {
+    use crate::config::{kernel_stack_position, KERNEL_STACK_SIZE};
+    let inner = TASK_MANAGER.inner_access();
+    let app_id = TASK_MANAGER.num_app;
+    let task = TaskControlBlock::new(get_app_name(0), get_app_data(0), app_id).unwrap();
+    assert_eq!(task.task_status, TaskStatus::Ready);
+    drop(task);
+    drop(inner);
+    let (kernel_stack_bottom, _) = kernel_stack_position(app_id);
+    assert_eq!(KERNEL_SPACE.lock().remove(kernel_stack_bottom, KERNEL_STACK_SIZE), 0);
+    info!("lock_order_test passed!");
+}
+
+#[allow(unused)]
+/// check page faults counted for the current task show up in its `TaskInfo`
+pub fn page_fault_counter_test() {
+    let before = get_current_task_info().page_faults;
+    for _ in 0..3 {
+        inc_current_page_faults();
+    }
+    assert_eq!(get_current_task_info().page_faults, before + 3);
+    // 恢复计数，不影响任务真正运行时的统计
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    inner.tasks[current_task].page_faults = before;
+    drop(inner);
+    info!("page_fault_counter_test passed!");
+}
+
+#[allow(unused)]
+/// check `count_by_status` and `num_runnable` after some tasks exit
+pub fn count_by_status_test() {
+    let num_app = TASK_MANAGER.num_app;
+    let mut inner = TASK_MANAGER.inner_access();
+    let saved: Vec<TaskStatus> = inner.tasks.iter().map(|task| task.task_status).collect();
+    // 偶数号任务退出，奇数号任务保持就绪
+    for (id, task) in inner.tasks.iter_mut().enumerate() {
+        task.task_status = if id % 2 == 0 {
+            TaskStatus::Exited
+        } else {
+            TaskStatus::Ready
+        };
+    }
+    drop(inner);
+    let exited = (num_app + 1) / 2;
+    assert_eq!(TASK_MANAGER.count_by_status(TaskStatus::Exited), exited);
+    assert_eq!(TASK_MANAGER.count_by_status(TaskStatus::Ready), num_app - exited);
+    assert_eq!(TASK_MANAGER.count_by_status(TaskStatus::Running), 0);
+    assert_eq!(TASK_MANAGER.num_runnable(), num_app - exited);
+    let mut inner = TASK_MANAGER.inner_access();
+    for (task, status) in inner.tasks.iter_mut().zip(saved) {
+        task.task_status = status;
+    }
+    drop(inner);
+    assert_eq!(TASK_MANAGER.num_runnable(), num_app);
+    info!("count_by_status_test passed!");
+}
+
+#[allow(unused)]
+/// check `load_apps` builds the valid apps and reports the broken ones by
+/// index. Uses the kernel stack slots after the last app and unmaps them again.
+pub fn load_apps_test() {
+    use crate::config::{kernel_stack_position, KERNEL_STACK_SIZE};
+    let garbage = [0xa5u8; 64];
+    let apps: [(&'static str, &[u8]); 4] = [
+        ("first", get_app_data(0)),
+        ("garbage", &garbage),
+        ("empty", &[]),
+        ("second", get_app_data(0)),
+    ];
+    let first_id = TASK_MANAGER.num_app;
+    let (tasks, errors) = load_apps(apps.iter().copied(), first_id, false);
+    assert_eq!(tasks.len(), 2);
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].0, 1);
+    assert_eq!(errors[1].0, 2);
+    assert!(errors.iter().all(|(_, err)| err.starts_with("invalid elf")));
+    // 两个正常的应用占用连续的两个内核栈
+    for (i, task) in tasks.iter().enumerate() {
+        assert_eq!(task.task_cx.sp(), kernel_stack_position(first_id + i).1);
+    }
+    assert_eq!(tasks[0].name, "first");
+    assert_eq!(tasks[1].name, "second");
+    drop(tasks);
+    for id in first_id..first_id + 2 {
+        let (kernel_stack_bottom, _) = kernel_stack_position(id);
+        assert_eq!(KERNEL_SPACE.lock().remove(kernel_stack_bottom, KERNEL_STACK_SIZE), 0);
+    }
+    info!("load_apps_test passed!");
+}
+
+#[allow(unused)]
+/// check every task carries the name of the app it was loaded from
+pub fn current_task_name_test() {
+    // 所有应用都是正常的 ELF 时任务编号与应用编号一致
+    assert_eq!(current_task_name(), get_app_name(0));
+    assert!(!current_task_name().is_empty());
+    let inner = TASK_MANAGER.inner_access();
+    for (i, task) in inner.tasks.iter().enumerate() {
+        assert_eq!(task.name, get_app_name(i));
+    }
+    drop(inner);
+    info!("current_task_name_test passed!");
+}
+
+#[allow(unused)]
+/// check out-of-range syscall ids are ignored instead of panicking
+pub fn inc_syscall_bounds_test() {
+    let before = get_current_task_info().syscall_times;
+    inc_current_task_syscall(MAX_SYSCALL_NUM);
+    inc_current_task_syscall(usize::MAX);
+    assert!(get_current_task_info().syscall_times == before);
+    inc_current_task_syscall(MAX_SYSCALL_NUM - 1);
+    assert_eq!(
+        get_current_task_info().syscall_times[MAX_SYSCALL_NUM - 1],
+        before[MAX_SYSCALL_NUM - 1] + 1
+    );
+    // 恢复计数，不影响任务真正运行时的统计
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    inner.tasks[current_task].syscall_times = before;
+    drop(inner);
+    info!("inc_syscall_bounds_test passed!");
+}
+
+#[allow(unused)]
+/// check a syscall counter saturates at `u32::MAX` instead of wrapping
+pub fn syscall_times_saturate_test() {
+    const SYSCALL_YIELD: usize = 124;
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    let before = inner.tasks[current_task].syscall_times[SYSCALL_YIELD];
+    inner.tasks[current_task].syscall_times[SYSCALL_YIELD] = u32::MAX - 1;
+    drop(inner);
+    inc_current_task_syscall(SYSCALL_YIELD);
+    assert_eq!(get_current_task_info().syscall_times[SYSCALL_YIELD], u32::MAX);
+    inc_current_task_syscall(SYSCALL_YIELD);
+    assert_eq!(get_current_task_info().syscall_times[SYSCALL_YIELD], u32::MAX);
+    let mut inner = TASK_MANAGER.inner_access();
+    inner.tasks[current_task].syscall_times[SYSCALL_YIELD] = before;
+    drop(inner);
+    info!("syscall_times_saturate_test passed!");
+}
+
+#[allow(unused)]
+/// check `sys_mmap_anon` rounds 5000 bytes up to two writable pages
+pub fn mmap_anon_test() {
+    let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+    assert!(kernel_sys_mmap_anon(0, permission).is_none());
+    let base = kernel_sys_mmap_anon(5000, permission).unwrap();
+    assert_eq!(base % PAGE_SIZE, 0);
+    let inner = TASK_MANAGER.inner_access();
+    let memory_set = &inner.tasks[inner.current_task].memory_set;
+    let area = memory_set.find_area_containing(VirtAddr::from(base).floor()).unwrap();
+    assert_eq!(area.vpn_range.get_start(), VirtAddr::from(base).floor());
+    assert_eq!(area.vpn_range.get_end(), VirtAddr::from(base + 2 * PAGE_SIZE).floor());
+    for page in 0..2 {
+        let va = VirtAddr::from(base + page * PAGE_SIZE);
+        assert!(memory_set.is_accessible(va, MapPermission::W));
+        let ppn = memory_set.translate(va.floor()).unwrap().ppn();
+        ppn.get_bytes_array()[0] = 0x5a;
+        assert_eq!(ppn.get_bytes_array()[0], 0x5a);
+    }
+    drop(inner);
+    assert_eq!(kernel_sys_munmap(base, 5000), 0);
+    info!("mmap_anon_test passed!");
+}
+
+#[allow(unused)]
+/// check time spent in syscalls is charged to kernel time and the two
+/// counters together never exceed the time that actually passed
+pub fn kernel_time_test() {
+    use crate::syscall::process::sys_get_time_us;
+    let inner = TASK_MANAGER.inner_access();
+    let task = &inner.tasks[inner.current_task];
+    let saved = (task.user_time, task.kernel_time, task.mode_switch_time);
+    drop(inner);
+    let start = get_time_us();
+    // 假装当前任务刚刚回到用户态
+    account_trap_return();
+    let before = get_current_task_info();
+    for _ in 0..20 {
+        account_trap_enter();
+        // 每次系统调用都在内核里停留至少 5us
+        let entered = sys_get_time_us();
+        while sys_get_time_us() - entered < 5 {}
+        account_trap_return();
+    }
+    let after = get_current_task_info();
+    let elapsed = get_time_us() - start;
+    let kernel_time = after.kernel_time - before.kernel_time;
+    let user_time = after.user_time - before.user_time;
+    assert!(kernel_time >= 20 * 5);
+    assert!(kernel_time + user_time <= elapsed);
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    let task = &mut inner.tasks[current_task];
+    task.user_time = saved.0;
+    task.kernel_time = saved.1;
+    task.mode_switch_time = saved.2;
+    drop(inner);
+    info!("kernel_time_test passed!");
+}
+
+#[allow(unused)]
+/// Run the exit path the trap handler takes for a faulting task, with the
+/// exit codes of an access fault and an illegal instruction: the task is
+/// `Exited` with that code and every other task keeps its status and can
+/// still be scheduled.
+pub fn fault_exit_test() {
+    use crate::trap::{EXIT_BAD_ACCESS, EXIT_ILLEGAL_INSTRUCTION};
+    let inner = TASK_MANAGER.inner_access();
+    let current = inner.current_task;
+    let saved: Vec<(TaskStatus, i32)> = inner
+        .tasks
+        .iter()
+        .map(|task| (task.task_status, task.exit_code))
+        .collect();
+    drop(inner);
+    for exit_code in [EXIT_BAD_ACCESS, EXIT_ILLEGAL_INSTRUCTION] {
+        mark_current_exited(exit_code);
+        let inner = TASK_MANAGER.inner_access();
+        assert_eq!(inner.tasks[current].task_status, TaskStatus::Exited);
+        assert_eq!(inner.tasks[current].exit_code, exit_code);
+        for (id, task) in inner.tasks.iter().enumerate().filter(|(id, _)| *id != current) {
+            assert_eq!(task.task_status, saved[id].0);
+        }
+        drop(inner);
+        // 其余任务照常被调度，出错的任务不会再被选中
+        let others_ready = saved
+            .iter()
+            .enumerate()
+            .any(|(id, (status, _))| id != current && *status == TaskStatus::Ready);
+        match TASK_MANAGER.find_next_task() {
+            Some(next) => assert!(next != current && others_ready),
+            None => assert!(!others_ready),
+        }
+        let mut inner = TASK_MANAGER.inner_access();
+        inner.tasks[current].task_status = saved[current].0;
+        inner.tasks[current].exit_code = saved[current].1;
+    }
+    info!("fault_exit_test passed!");
+}
+
+#[allow(unused)]
+/// Store to a page made read-only by `mprotect` through the page fault path
+/// of the trap handler: it is reported as a permission violation and kills
+/// only the current task with `EXIT_BAD_ACCESS`.
+pub fn store_page_fault_test() {
+    use crate::trap::{user_page_fault, EXIT_BAD_ACCESS};
+    use alloc::format;
+    let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+    let start = 0x1000_0000;
+    assert_eq!(kernel_sys_mmap(start, PAGE_SIZE, permission), Some(start));
+    assert!(kernel_sys_make_readonly(start, PAGE_SIZE));
+    let inner = TASK_MANAGER.inner_access();
+    let current = inner.current_task;
+    let saved: Vec<(TaskStatus, i32)> = inner
+        .tasks
+        .iter()
+        .map(|task| (task.task_status, task.exit_code))
+        .collect();
+    let page_faults = inner.tasks[current].page_faults;
+    drop(inner);
+    // trap_handler 对 StorePageFault 的处理：先尝试缺页处理，失败后分类并杀掉任务
+    let kind = user_page_fault(VirtAddr::from(start + 8), MapPermission::W).unwrap_err();
+    assert_eq!(kind, FaultKind::PermissionViolation);
+    assert_eq!(format!("{}", kind), "permission violation");
+    mark_current_exited(EXIT_BAD_ACCESS);
+    let mut inner = TASK_MANAGER.inner_access();
+    assert_eq!(inner.tasks[current].page_faults, page_faults + 1);
+    assert_eq!(inner.tasks[current].task_status, TaskStatus::Exited);
+    assert_eq!(inner.tasks[current].exit_code, EXIT_BAD_ACCESS);
+    for (id, task) in inner.tasks.iter().enumerate().filter(|(id, _)| *id != current) {
+        assert_eq!((task.task_status, task.exit_code), saved[id]);
+    }
+    let task = &mut inner.tasks[current];
+    task.task_status = saved[current].0;
+    task.exit_code = saved[current].1;
+    task.page_faults = page_faults;
+    drop(inner);
+    assert_eq!(kernel_sys_munmap(start, PAGE_SIZE), 0);
+    info!("store_page_fault_test passed!");
+}
+
+#[allow(unused)]
+/// check `mark_started` stamps `start_time` the first time the first app and
+/// a later app are scheduled, and leaves it alone on later switches
+pub fn mark_started_test() {
+    let mut inner = TASK_MANAGER.inner_access();
+    let last = TASK_MANAGER.num_app - 1;
+    let saved: Vec<(TaskStatus, usize)> = [0, last]
+        .iter()
+        .map(|id| (inner.tasks[*id].task_status, inner.tasks[*id].start_time))
+        .collect();
+    for id in [0, last] {
+        inner.tasks[id].start_time = 0;
+    }
+    let before = get_time_us();
+    inner.mark_started(0);
+    let first = inner.tasks[0].start_time;
+    assert!(first >= before && first > 0);
+    assert_eq!(inner.tasks[0].task_status, TaskStatus::Running);
+    // 只有一个应用时 last 就是 0 号，已经有了开始时间
+    inner.mark_started(last);
+    let later = inner.tasks[last].start_time;
+    assert!(later >= first);
+    // 再次被调度不会改变开始时间
+    inner.mark_started(0);
+    inner.mark_started(last);
+    assert_eq!(inner.tasks[0].start_time, first);
+    assert_eq!(inner.tasks[last].start_time, later);
+    for (id, (status, start_time)) in [0, last].iter().zip(saved) {
+        inner.tasks[*id].task_status = status;
+        inner.tasks[*id].start_time = start_time;
+    }
+    drop(inner);
+    info!("mark_started_test passed!");
+}
+
+#[allow(unused)]
+/// check the kernel shuts down only after the last task exits, reporting the
+/// first failed exit code
+pub fn final_exit_code_test() {
+    let mut inner = TASK_MANAGER.inner_access();
+    let saved: Vec<(TaskStatus, i32)> = inner
+        .tasks
+        .iter()
+        .map(|task| (task.task_status, task.exit_code))
+        .collect();
+    for task in inner.tasks.iter_mut() {
+        task.task_status = TaskStatus::Exited;
+        task.exit_code = 0;
+    }
+    // 最后一个任务还在运行时不能关机
+    let last = TASK_MANAGER.num_app - 1;
+    inner.tasks[last].task_status = TaskStatus::Running;
+    drop(inner);
+    assert_eq!(TASK_MANAGER.final_exit_code(), None);
+    assert_eq!(TASK_MANAGER.find_next_task(), None);
+    // 它退出后 run_next_task 会正常关机而不是 panic
+    TASK_MANAGER.inner_access().tasks[last].task_status = TaskStatus::Exited;
+    assert_eq!(TASK_MANAGER.final_exit_code(), Some(0));
+    TASK_MANAGER.inner_access().tasks[last].exit_code = -3;
+    assert_eq!(TASK_MANAGER.final_exit_code(), Some(-3));
+    let mut inner = TASK_MANAGER.inner_access();
+    for (task, (status, exit_code)) in inner.tasks.iter_mut().zip(saved) {
+        task.task_status = status;
+        task.exit_code = exit_code;
+    }
+    drop(inner);
+    info!("final_exit_code_test passed!");
+}
+
+#[allow(unused)]
+/// check every suspension of the current task shows up in `TaskInfo::yields`
+pub fn yield_count_test() {
+    let inner = TASK_MANAGER.inner_access();
+    let task = &inner.tasks[inner.current_task];
+    let saved = (task.task_status, task.yields);
+    drop(inner);
+    let before = get_current_task_info().yields;
+    for _ in 0..3 {
+        mark_current_suspended();
+    }
+    assert_eq!(get_current_task_info().yields, before + 3);
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    inner.tasks[current_task].task_status = saved.0;
+    inner.tasks[current_task].yields = saved.1;
+    drop(inner);
+    info!("yield_count_test passed!");
+}
+
+#[allow(unused)]
+/// check the info of every task can be read by pid, and that each task sees
+/// the other's counters rather than its own
+pub fn task_info_of_test() {
+    let num_app = TASK_MANAGER.num_app;
+    assert!(get_task_info(num_app).is_none());
+    assert!(get_task_info(usize::MAX).is_none());
+    let current_task = TASK_MANAGER.inner_access().current_task;
+    let current = get_current_task_info();
+    let by_pid = get_task_info(current_task).unwrap();
+    assert_eq!(by_pid.status, current.status);
+    assert_eq!(by_pid.yields, current.yields);
+    if num_app < 2 {
+        info!("task_info_of_test skipped, only one app");
+        return;
+    }
+    // 给两个任务设置不同的 yields，互相读取对方的信息
+    let mut inner = TASK_MANAGER.inner_access();
+    let saved = (inner.tasks[0].yields, inner.tasks[1].yields);
+    inner.tasks[0].yields = 100;
+    inner.tasks[1].yields = 200;
+    drop(inner);
+    assert_eq!(get_task_info(1).unwrap().yields, 200);
+    assert_eq!(get_task_info(0).unwrap().yields, 100);
+    assert_eq!(get_task_info(1).unwrap().status, TaskStatus::Ready);
+    let mut inner = TASK_MANAGER.inner_access();
+    inner.tasks[0].yields = saved.0;
+    inner.tasks[1].yields = saved.1;
+    drop(inner);
+    info!("task_info_of_test passed!");
+}
+
+#[allow(unused)]
+/// Exit the last task and restart it: it is `Ready` again with fresh
+/// counters and will enter its app at the ELF entry on its own kernel stack.
+pub fn restart_test() {
+    use crate::mm::MemorySet;
+    let num_app = TASK_MANAGER.num_app;
+    assert!(!restart_task(num_app));
+    if num_app < 2 {
+        info!("restart_test skipped, only one app");
+        return;
+    }
+    let pid = num_app - 1;
+    // 还没有退出的任务不能重启
+    assert!(!restart_task(pid));
+    let mut inner = TASK_MANAGER.inner_access();
+    let current_task = inner.current_task;
+    let saved = inner.tasks[current_task].task_status;
+    inner.tasks[current_task].task_status = TaskStatus::Exited;
+    let task = &mut inner.tasks[pid];
+    let app_index = task.app_index;
+    task.task_status = TaskStatus::Exited;
+    task.exit_code = -1;
+    task.yields = 5;
+    task.syscall_times[0] = 3;
+    drop(inner);
+    assert!(!restart_task(current_task));
+    TASK_MANAGER.inner_access().tasks[current_task].task_status = saved;
+    assert!(restart_task(pid));
+    let (_, _, entry) = MemorySet::from_elf(get_app_data(app_index)).unwrap();
+    let inner = TASK_MANAGER.inner_access();
+    let task = &inner.tasks[pid];
+    assert_eq!(task.task_status, TaskStatus::Ready);
+    assert_eq!(task.app_index, app_index);
+    assert_eq!((task.exit_code, task.yields, task.syscall_times[0]), (0, 0, 0));
+    assert_eq!(task.get_trap_cx().sepc, entry);
+    assert_eq!(task.get_trap_cx().x[2], task.base_size);
+    assert_eq!(task.task_cx.sp(), kernel_stack_position(pid).1);
+    drop(inner);
+    // 已经是 Ready 的任务不能再次重启
+    assert!(!restart_task(pid));
+    info!("restart_test passed!");
+}
+
+#[allow(unused)]
+/// Switch the current task to itself through `switch_to` three times: the
+/// real `__switch` path counts exactly one switch per call, for the total
+/// and for that task only.
+pub fn switch_count_test() {
+    assert_eq!(switch_count(TASK_MANAGER.num_app), None);
+    let inner = TASK_MANAGER.inner_access();
+    let current = inner.current_task;
+    let task = &inner.tasks[current];
+    // 切换会改写任务上下文、状态和计时，测试结束后恢复原样
+    let saved = (
+        task.task_cx,
+        task.task_status,
+        task.start_time,
+        task.kernel_time,
+        task.mode_switch_time,
+    );
+    let counts: Vec<usize> = inner.tasks.iter().map(|task| task.switches).collect();
+    let total = inner.switches;
+    drop(inner);
+    for i in 1..=3 {
+        // 保存和恢复的是同一个任务上下文，__switch 直接返回到这里
+        TASK_MANAGER.switch_to(current);
+        assert_eq!(switch_count(current).unwrap(), (total + i, counts[current] + i));
+    }
+    for (id, count) in counts.iter().enumerate().filter(|(id, _)| *id != current) {
+        assert_eq!(switch_count(id).unwrap(), (total + 3, *count));
+    }
+    let mut inner = TASK_MANAGER.inner_access();
+    assert_eq!(inner.current_task, current);
+    inner.switches = total;
+    let task = &mut inner.tasks[current];
+    task.switches = counts[current];
+    task.task_cx = saved.0;
+    task.task_status = saved.1;
+    task.start_time = saved.2;
+    task.kernel_time = saved.3;
+    task.mode_switch_time = saved.4;
+    drop(inner);
+    info!("switch_count_test passed!");
+}
+
+#[allow(unused)]
+/// check an mmap whose end wraps around the address space fails cleanly
+pub fn mmap_wrap_test() {
+    let permission = MapPermission::R | MapPermission::W | MapPermission::U;
+    let top = usize::MAX & !(PAGE_SIZE - 1);
+    assert!(kernel_sys_mmap(top, 2 * PAGE_SIZE, permission).is_none());
+    assert!(kernel_sys_mmap(top - PAGE_SIZE, usize::MAX, permission).is_none());
+    assert!(kernel_sys_mmap(0x1000_0000, usize::MAX, permission).is_none());
+    // 恰好回绕到 0
+    assert!(kernel_sys_mmap(0x1000_0000, 0usize.wrapping_sub(0x1000_0000), permission).is_none());
+    assert!(kernel_sys_mmap(0, usize::MAX, permission).is_none());
+    assert_eq!(crate::syscall::process::sys_mmap(0x1000_0000, usize::MAX, 0x3), -1);
+    // 什么都没有映射，同一位置仍然可以正常 mmap
+    assert_eq!(kernel_sys_mmap(0x1000_0000, PAGE_SIZE, permission), Some(0x1000_0000));
+    assert_eq!(kernel_sys_munmap(0x1000_0000, PAGE_SIZE), 0);
+    info!("mmap_wrap_test passed!");
+}
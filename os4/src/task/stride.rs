@@ -0,0 +1,49 @@
+//! Pass comparison for stride scheduling
+//!
+//! Every task owns a `pass` that grows by `BIG_STRIDE / priority` each time
+//! it runs, and the task with the smallest pass runs next. Passes are `u64`
+//! and are allowed to wrap around, so they must never be compared with `<`.
+
+/// stride of a task with priority 1, the stride of priority `p` is
+/// `BIG_STRIDE / p`. Priorities are at least 2, so two passes never drift
+/// more than `BIG_STRIDE / 2` apart, far less than half of the `u64` range.
+pub const BIG_STRIDE: u64 = u32::MAX as u64;
+
+/// Whether pass `a` is before pass `b`, correct across wraparound as long as
+/// the two are less than half the `u64` range apart.
+pub fn stride_less(a: u64, b: u64) -> bool {
+    stride_less_bits(a, b, 64)
+}
+
+/// `stride_less` for passes of `bits` bits, so the wraparound can be tested
+/// exhaustively on a small range.
+/// a 到 b 的距离超过半个范围时说明 b 已经回绕，a 反而在后面
+fn stride_less_bits(a: u64, b: u64, bits: u32) -> bool {
+    let mask = u64::MAX >> (64 - bits);
+    let diff = b.wrapping_sub(a) & mask;
+    diff != 0 && diff < (1 << (bits - 1))
+}
+
+#[allow(unused)]
+/// check `stride_less` for every pair of 8-bit passes and at the `u64` wrap
+pub fn stride_less_test() {
+    // 8 位的 pass 穷举：b 在 a 之后 1..128 步时 a 更小，反过来不成立
+    for a in 0..256u64 {
+        assert!(!stride_less_bits(a, a, 8));
+        for d in 1..128u64 {
+            let b = (a + d) & 0xff;
+            assert!(stride_less_bits(a, b, 8), "{} < {}", a, b);
+            assert!(!stride_less_bits(b, a, 8), "{} >= {}", b, a);
+        }
+    }
+    assert!(stride_less(0, 1));
+    assert!(!stride_less(1, 0));
+    assert!(!stride_less(7, 7));
+    // 回绕的边界
+    assert!(stride_less(u64::MAX, 0));
+    assert!(!stride_less(0, u64::MAX));
+    assert!(stride_less(u64::MAX - BIG_STRIDE / 2, BIG_STRIDE / 2));
+    assert!(stride_less(0, (1 << 63) - 1));
+    assert!(!stride_less((1 << 63) - 1, 0));
+    info!("stride_less_test passed!");
+}
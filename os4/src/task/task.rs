@@ -1,12 +1,47 @@
 //! Types related to task management
 use super::TaskContext;
-use crate::config::{kernel_stack_position, TRAP_CONTEXT, MAX_SYSCALL_NUM};
+#[cfg(feature = "syscall_trace")]
+use super::SyscallTrace;
+use crate::config::{checked_kernel_stack_position, kernel_stack_guard_position, MAX_SYSCALL_NUM};
+use crate::mm::memory_set::ElfError;
 use crate::mm::{MapPermission, MemorySet, PhysPageNum, VirtAddr, KERNEL_SPACE};
 use crate::timer::{get_time, get_time_us};
 use crate::trap::{trap_handler, TrapContext};
+use core::fmt;
+
+/// why [`TaskControlBlock::new`] could not create a task
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TaskError {
+    /// the app image is not a usable ELF
+    Elf(ElfError),
+    /// `app_id` is past the last kernel stack slot
+    NoKernelStack(usize),
+}
+
+impl From<ElfError> for TaskError {
+    fn from(err: ElfError) -> Self {
+        TaskError::Elf(err)
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskError::Elf(err) => write!(f, "{}", err),
+            TaskError::NoKernelStack(app_id) => write!(f, "no kernel stack slot for app {}", app_id),
+        }
+    }
+}
+
+/// priority of a task that never called `sys_set_priority`
+pub const DEFAULT_PRIORITY: isize = 16;
 
 /// task control block structure
 pub struct TaskControlBlock {
+    // 应用的名字，来自 loader 的名字表
+    pub name: &'static str,
+    // 应用在 loader 中的编号，重启时据此重新加载 ELF
+    pub app_index: usize,
     pub task_status: TaskStatus,
     pub task_cx: TaskContext,
     pub memory_set: MemorySet,
@@ -14,8 +49,27 @@ pub struct TaskControlBlock {
     pub trap_cx_ppn: PhysPageNum,
     pub base_size: usize,
     pub syscall_times: [u32; MAX_SYSCALL_NUM],
-    pub time: usize,
-
+    // 任务第一次被调度运行的时间(us)，0 表示还没有运行过
+    pub start_time: usize,
+    // 任务退出时的返回值，异常退出时由 trap handler 设置为负数
+    pub exit_code: i32,
+    // trap handler 为该任务处理过的缺页异常次数
+    pub page_faults: usize,
+    // 没有对应实现的系统调用次数，这些调用不计入 syscall_times
+    pub unknown_syscalls: u32,
+    // 在用户态和内核态累计运行的时间(us)
+    pub user_time: usize,
+    pub kernel_time: usize,
+    // 上一次进出内核或被调度的时间(us)，0 表示还没有开始计时
+    pub mode_switch_time: usize,
+    // 任务被挂起让出 CPU 的次数，包括时钟中断导致的抢占
+    pub yields: usize,
+    // 通过 __switch 切换到该任务的次数
+    pub switches: usize,
+    // 由 sys_set_priority 设置的优先级，不小于 2
+    pub priority: isize,
+    #[cfg(feature = "syscall_trace")]
+    pub syscall_trace: SyscallTrace,
 }
 
 impl TaskControlBlock {
@@ -25,29 +79,108 @@ impl TaskControlBlock {
     pub fn get_user_token(&self) -> usize {
         self.memory_set.token()
     }
-    pub fn new(elf_data: &[u8], app_id: usize) -> Self {
+    /// The task trapped into the kernel at `now`, charge the time since the
+    /// last switch to user time.
+    pub fn charge_user_time(&mut self, now: usize) {
+        if self.mode_switch_time != 0 {
+            self.user_time += now.saturating_sub(self.mode_switch_time);
+        }
+        self.mode_switch_time = now;
+    }
+    /// The task leaves the kernel at `now`, either back to user mode or
+    /// switched out, charge the time since the last switch to kernel time.
+    pub fn charge_kernel_time(&mut self, now: usize) {
+        if self.mode_switch_time != 0 {
+            self.kernel_time += now.saturating_sub(self.mode_switch_time);
+        }
+        self.mode_switch_time = now;
+    }
+    /// Fails without touching the kernel space if `elf_data` is not a valid ELF
+    /// or `app_id` has no kernel stack slot.
+    pub fn new(name: &'static str, elf_data: &[u8], app_id: usize) -> Result<Self, TaskError> {
+        Self::build(name, elf_data, app_id, None)
+    }
+    /// [`TaskControlBlock::new`] for app `app_index`, whose read-only text is
+    /// shared with the other tasks of the same app, see
+    /// [`MemorySet::from_elf_shared`].
+    pub fn new_shared(
+        name: &'static str,
+        elf_data: &[u8],
+        app_id: usize,
+        app_index: usize,
+    ) -> Result<Self, TaskError> {
+        let mut task = Self::build(name, elf_data, app_id, Some(app_index))?;
+        task.app_index = app_index;
+        Ok(task)
+    }
+    fn build(
+        name: &'static str,
+        elf_data: &[u8],
+        app_id: usize,
+        shared_text: Option<usize>,
+    ) -> Result<Self, TaskError> {
+        // 先确认内核栈的位置，避免为用不上的地址空间分配页帧
+        let (kernel_stack_bottom, kernel_stack_top) =
+            checked_kernel_stack_position(app_id).ok_or(TaskError::NoKernelStack(app_id))?;
         // memory_set with elf program headers/trampoline/trap context/user stack
         // 解析传入的 ELF 格式数据构造应用的地址空间 memory_set 并获得其他信息
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
-        // 从地址空间 memory_set 中查多级页表找到应用地址空间中的 Trap 上下文实际被放在哪个物理页帧
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        let task_status = TaskStatus::Ready;
+        let (memory_set, user_sp, entry_point) = match shared_text {
+            Some(app_index) => MemorySet::from_elf_shared(elf_data, app_index)?,
+            None => MemorySet::from_elf(elf_data)?,
+        };
         // map a kernel-stack in kernel space
         // 根据传入的应用 ID app_id 调用在 config 子模块中定义的 
         // kernel_stack_position 找到 应用的内核栈预计放在内核地址空间 
         // KERNEL_SPACE 中的哪个位置，并通过 insert_framed_area 实际
         // 将这个逻辑段 加入到内核地址空间中；
-        let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(app_id);
-        KERNEL_SPACE.lock().insert_framed_area(
+        let mut kernel_space = KERNEL_SPACE.lock();
+        if let Err(err) = kernel_space.insert_framed_area(
             kernel_stack_bottom.into(),
             kernel_stack_top.into(),
             MapPermission::R | MapPermission::W,
+        ) {
+            panic!("kernel stack of app {} {}", app_id, err);
+        }
+        // 内核栈下方的保护页必须保持未映射，溢出时才会触发缺页而不是写坏相邻的内核栈
+        let (guard_bottom, _) = kernel_stack_guard_position(app_id);
+        assert!(
+            kernel_space
+                .translate(VirtAddr::from(guard_bottom).floor())
+                .map_or(true, |pte| !pte.is_valid()),
+            "guard page of kernel stack {} is mapped",
+            app_id
         );
+        drop(kernel_space);
+        Ok(Self::from_parts(name, memory_set, user_sp, entry_point, kernel_stack_top))
+    }
+    /// Start over from the entry of `elf_data` with a new address space and
+    /// every counter cleared, reusing the kernel stack at `kernel_stack_top`.
+    /// The task must not be the one running on that stack. `elf_data` must be
+    /// the image of its app, the text is shared with the other tasks of it.
+    pub fn restart(&mut self, elf_data: &[u8], kernel_stack_top: usize) -> Result<(), ElfError> {
+        let (memory_set, user_sp, entry_point) =
+            MemorySet::from_elf_shared(elf_data, self.app_index)?;
+        let app_index = self.app_index;
+        // 旧的地址空间随旧的任务控制块一起释放
+        *self = Self::from_parts(self.name, memory_set, user_sp, entry_point, kernel_stack_top);
+        self.app_index = app_index;
+        Ok(())
+    }
+    /// A `Ready` task about to enter `entry_point` in `memory_set`, whose
+    /// kernel stack is already mapped.
+    fn from_parts(
+        name: &'static str,
+        memory_set: MemorySet,
+        user_sp: usize,
+        entry_point: usize,
+        kernel_stack_top: usize,
+    ) -> Self {
+        // 从地址空间 memory_set 中查多级页表找到应用地址空间中的 Trap 上下文实际被放在哪个物理页帧
+        let trap_cx_ppn = memory_set.trap_context_ppn().unwrap();
         let task_control_block = Self {
-            task_status,
+            name,
+            app_index: 0,
+            task_status: TaskStatus::Ready,
             // 为了能够支持对该应用的启动并顺利切换到用户地址空间执行
             // 我们在应用的内核栈顶压入一个跳转到 trap_return 而不是 __restore 的任务上下文
             // 在构造方式上，只是将 ra 寄存器的值设置为 trap_return 的地址。
@@ -56,8 +189,19 @@ impl TaskControlBlock {
             trap_cx_ppn,
             base_size: user_sp,
             syscall_times: [0; MAX_SYSCALL_NUM],
-            // 首次设置的时候是 get_time_us mark。
-            time: 0,
+            // 首次被调度的时候由 mark_started 设置为 get_time_us
+            start_time: 0,
+            exit_code: 0,
+            page_faults: 0,
+            unknown_syscalls: 0,
+            user_time: 0,
+            kernel_time: 0,
+            mode_switch_time: 0,
+            yields: 0,
+            switches: 0,
+            priority: DEFAULT_PRIORITY,
+            #[cfg(feature = "syscall_trace")]
+            syscall_trace: SyscallTrace::new(),
         };
         // prepare TrapContext in user space
         // println!("prepare TrapContext in user space:{}",task_control_block.trap_cx_ppn.0);
@@ -73,7 +217,7 @@ impl TaskControlBlock {
     }
 }
 
-#[derive(Debug,Copy, Clone, PartialEq)]
+#[derive(Debug,Copy, Clone, PartialEq, Eq)]
 /// task status: UnInit, Ready, Running, Exited
 pub enum TaskStatus {
     UnInit,
@@ -81,3 +225,42 @@ pub enum TaskStatus {
     Running,
     Exited,
 }
+
+#[allow(unused)]
+/// check every `TaskStatus` can be logged and compared with `assert_eq!`
+pub fn task_status_test() {
+    use alloc::format;
+    use TaskStatus::*;
+    let statuses = [UnInit, Ready, Running, Exited];
+    let names = ["UnInit", "Ready", "Running", "Exited"];
+    for (i, status) in statuses.iter().enumerate() {
+        assert_eq!(format!("{:?}", status), names[i]);
+        for (j, other) in statuses.iter().enumerate() {
+            assert_eq!(status == other, i == j);
+        }
+    }
+    assert_eq!(Ready, Ready);
+    assert_ne!(Ready, Exited);
+    info!("task_status_test passed!");
+}
+
+#[allow(unused)]
+/// check an `app_id` past the last kernel stack slot is rejected
+pub fn kernel_stack_limit_test() {
+    use crate::config::{kernel_stack_position, MAX_KERNEL_STACKS};
+    use crate::loader::{get_app_data, get_app_name};
+    use crate::mm::frames_available;
+    assert_eq!(checked_kernel_stack_position(MAX_KERNEL_STACKS), None);
+    assert_eq!(checked_kernel_stack_position(usize::MAX), None);
+    // 最后一个内核栈仍然在内核区域内，且不会与第一个内核栈重叠
+    let (last_bottom, _) = checked_kernel_stack_position(MAX_KERNEL_STACKS - 1).unwrap();
+    let (_, last_guard_bottom) = kernel_stack_guard_position(MAX_KERNEL_STACKS - 1);
+    assert!(last_guard_bottom <= last_bottom);
+    assert!(last_bottom < kernel_stack_position(0).0);
+    assert!(last_bottom > crate::config::MEMORY_END);
+    let available = frames_available();
+    let err = TaskControlBlock::new(get_app_name(0), get_app_data(0), MAX_KERNEL_STACKS).err();
+    assert_eq!(err, Some(TaskError::NoKernelStack(MAX_KERNEL_STACKS)));
+    assert_eq!(frames_available(), available);
+    info!("kernel_stack_limit_test passed!");
+}
@@ -0,0 +1,88 @@
+//! Per-task ring buffer of the most recent syscalls
+//!
+//! Only built with the `syscall_trace` feature, so normal builds pay nothing
+//! for it. The buffer has a fixed size and never grows on the heap.
+
+/// how many syscalls each task remembers
+pub const SYSCALL_TRACE_LEN: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+/// one traced syscall: its id and when it was made (in microseconds)
+pub struct SyscallRecord {
+    pub id: usize,
+    pub time_us: usize,
+}
+
+/// fixed-size ring buffer, overwriting the oldest record once full
+pub struct SyscallTrace {
+    records: [SyscallRecord; SYSCALL_TRACE_LEN],
+    /// index of the next slot to write
+    head: usize,
+    /// number of valid records, at most `SYSCALL_TRACE_LEN`
+    len: usize,
+}
+
+impl SyscallTrace {
+    pub fn new() -> Self {
+        Self {
+            records: [SyscallRecord::default(); SYSCALL_TRACE_LEN],
+            head: 0,
+            len: 0,
+        }
+    }
+    pub fn push(&mut self, id: usize, time_us: usize) {
+        self.records[self.head] = SyscallRecord { id, time_us };
+        self.head = (self.head + 1) % SYSCALL_TRACE_LEN;
+        if self.len < SYSCALL_TRACE_LEN {
+            self.len += 1;
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// The `i`-th record counting from the oldest one.
+    pub fn get(&self, i: usize) -> Option<SyscallRecord> {
+        if i >= self.len {
+            return None;
+        }
+        let oldest = (self.head + SYSCALL_TRACE_LEN - self.len) % SYSCALL_TRACE_LEN;
+        Some(self.records[(oldest + i) % SYSCALL_TRACE_LEN])
+    }
+}
+
+impl Default for SyscallTrace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(unused)]
+/// a simple test for the syscall ring buffer
+pub fn syscall_trace_test() {
+    let mut trace = SyscallTrace::new();
+    assert_eq!(trace.len(), 0);
+    assert!(trace.is_empty());
+    assert_eq!(trace.get(0), None);
+    for i in 0..3 {
+        trace.push(64, i);
+    }
+    assert_eq!(trace.len(), 3);
+    assert!(!trace.is_empty());
+    assert_eq!(trace.get(0), Some(SyscallRecord { id: 64, time_us: 0 }));
+    assert_eq!(trace.get(2), Some(SyscallRecord { id: 64, time_us: 2 }));
+    // wrap around: only the last SYSCALL_TRACE_LEN records survive
+    for i in 3..SYSCALL_TRACE_LEN + 5 {
+        trace.push(124, i);
+    }
+    assert_eq!(trace.len(), SYSCALL_TRACE_LEN);
+    assert_eq!(trace.get(0).unwrap().time_us, 5);
+    assert_eq!(
+        trace.get(SYSCALL_TRACE_LEN - 1).unwrap().time_us,
+        SYSCALL_TRACE_LEN + 4
+    );
+    info!("syscall_trace_test passed!");
+}
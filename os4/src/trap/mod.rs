@@ -13,27 +13,39 @@
 //! to [`syscall()`].
 mod context;
 
-use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::config::{kernel_stack_guard_position, TRAMPOLINE, TRAP_CONTEXT};
+use crate::loader::get_num_app;
+use crate::mm::{FaultKind, MapPermission, VirtAddr};
 use crate::syscall::syscall;
 use crate::task::{
-    current_trap_cx, current_user_token, exit_current_and_run_next, suspend_current_and_run_next,
+    account_trap_enter, account_trap_return, classify_current_fault, current_trap_cx,
+    current_user_token, exit_current_and_run_next,
+    handle_current_page_fault, inc_current_page_faults, suspend_current_and_run_next,
 };
 use crate::timer::set_next_trigger;
 use riscv::register::{
     mtvec::TrapMode,
     scause::{self, Exception, Interrupt, Trap},
-    sie, stval, stvec,
+    sepc, sie, stval, stvec,
 };
 
 core::arch::global_asm!(include_str!("trap.S"));
 
+/// exit code of a task killed for an invalid memory access
+pub const EXIT_BAD_ACCESS: i32 = -2;
+/// exit code of a task killed for an illegal instruction
+pub const EXIT_ILLEGAL_INSTRUCTION: i32 = -3;
+
 pub fn init() {
     set_kernel_trap_entry();
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __kernel_trap();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__kernel_trap as usize, TrapMode::Direct);
     }
 }
 
@@ -52,6 +64,7 @@ pub fn enable_timer_interrupt() {
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
+    account_trap_enter();
     let cx = current_trap_cx();
     let scause = scause::read();
     let stval = stval::read();
@@ -60,15 +73,40 @@ pub fn trap_handler() -> ! {
             cx.sepc += 4;
             cx.x[10] = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]) as usize;
         }
-        Trap::Exception(Exception::StoreFault)
-        | Trap::Exception(Exception::StorePageFault)
-        | Trap::Exception(Exception::LoadPageFault) => {
-            error!("[kernel] PageFault in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.", stval, cx.sepc);
-            exit_current_and_run_next();
+        Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::InstructionPageFault) => {
+            let access = match scause.cause() {
+                Trap::Exception(Exception::StorePageFault) => MapPermission::W,
+                Trap::Exception(Exception::LoadPageFault) => MapPermission::R,
+                _ => MapPermission::X,
+            };
+            if let Err(kind) = user_page_fault(VirtAddr::from(stval), access) {
+                error!(
+                    "[kernel] {:?} in application ({}), bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
+                    scause.cause(),
+                    kind,
+                    stval,
+                    cx.sepc
+                );
+                exit_current_and_run_next(EXIT_BAD_ACCESS);
+            }
+        }
+        Trap::Exception(Exception::StoreFault) | Trap::Exception(Exception::LoadFault) => {
+            error!(
+                "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}, core dumped.",
+                scause.cause(),
+                stval,
+                cx.sepc
+            );
+            exit_current_and_run_next(EXIT_BAD_ACCESS);
         }
         Trap::Exception(Exception::IllegalInstruction) => {
-            error!("[kernel] IllegalInstruction in application, core dumped.");
-            exit_current_and_run_next();
+            error!(
+                "[kernel] IllegalInstruction in application, bad instruction = {:#x}, core dumped.",
+                cx.sepc
+            );
+            exit_current_and_run_next(EXIT_ILLEGAL_INSTRUCTION);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             set_next_trigger();
@@ -85,12 +123,24 @@ pub fn trap_handler() -> ! {
     trap_return();
 }
 
+/// Count and service a page fault of the current task at `va` for
+/// `access`, or tell why the task has to be killed.
+pub fn user_page_fault(va: VirtAddr, access: MapPermission) -> Result<(), FaultKind> {
+    inc_current_page_faults();
+    // 逻辑段内还没有页帧的页面（例如被 madvise 释放过），分配一个全零页面后重新执行
+    if handle_current_page_fault(va, access) {
+        return Ok(());
+    }
+    Err(classify_current_fault(va))
+}
+
 /**
  * 返回用户态
  */
 #[no_mangle]
 pub fn trap_return() -> ! {
     set_user_trap_entry();
+    account_trap_return();
     let trap_cx_ptr = TRAP_CONTEXT;
     let user_satp = current_user_token();
     extern "C" {
@@ -110,9 +160,63 @@ pub fn trap_return() -> ! {
     }
 }
 
+/// Return the app whose kernel stack guard page contains `addr`, if any.
+fn kernel_stack_overflowed(addr: usize) -> Option<usize> {
+    (0..get_num_app()).find(|app_id| {
+        let (bottom, top) = kernel_stack_guard_position(*app_id);
+        (bottom..top).contains(&addr)
+    })
+}
+
 #[no_mangle]
+/// Entered from `__kernel_trap` on a dedicated stack, so an overflowed
+/// kernel stack can still be reported.
 pub fn trap_from_kernel() -> ! {
-    panic!("a trap from kernel!");
+    if let Some(app_id) = kernel_stack_overflowed(stval::read()) {
+        panic!(
+            "kernel stack overflow of app {}, stval = {:#x}, sepc = {:#x}!",
+            app_id,
+            stval::read(),
+            sepc::read()
+        );
+    }
+    panic!(
+        "a trap {:?} from kernel, stval = {:#x}, sepc = {:#x}!",
+        scause::read().cause(),
+        stval::read(),
+        sepc::read()
+    );
 }
 
 pub use context::TrapContext;
+
+#[allow(unused)]
+/// Overflow the kernel stack of app 0 on purpose. Never returns: the kernel
+/// must panic with "kernel stack overflow of app 0" instead of silently
+/// corrupting the neighbouring stack, so run it by hand only, after the
+/// task manager has mapped the kernel stacks.
+pub fn kernel_stack_guard_test() -> ! {
+    fn recurse(depth: usize) -> usize {
+        if depth == usize::MAX {
+            return 0;
+        }
+        let frame = [depth; 64];
+        // 防止编译器把递归优化掉
+        unsafe { core::ptr::read_volatile(&frame[depth % 64]) + recurse(depth + 1) }
+    }
+    extern "C" fn overflow() -> ! {
+        recurse(0);
+        unreachable!("kernel stack overflow was not caught");
+    }
+    let (_, top) = crate::config::kernel_stack_position(0);
+    unsafe {
+        // 切换到 app 0 的内核栈上递归，直到越过栈底碰到保护页
+        core::arch::asm!(
+            "mv sp, {0}",
+            "jalr {1}",
+            in(reg) top,
+            in(reg) overflow as usize,
+            options(noreturn)
+        );
+    }
+}
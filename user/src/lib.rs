@@ -119,6 +119,10 @@ pub struct TaskInfo {
     pub status: TaskStatus,
     pub syscall_times: [u32; MAX_SYSCALL_NUM],
     pub time: usize,
+    pub page_faults: usize,
+    pub user_time: usize,
+    pub kernel_time: usize,
+    pub yields: usize,
 }
 
 impl TaskInfo {
@@ -127,6 +131,10 @@ impl TaskInfo {
             status: TaskStatus::UnInit,
             syscall_times: [0; MAX_SYSCALL_NUM],
             time: 0,
+            page_faults: 0,
+            user_time: 0,
+            kernel_time: 0,
+            yields: 0,
         }
     }
 }